@@ -0,0 +1,2610 @@
+use super::{ACCESS_LOG_FIELDS, Bytes, Error, Response, ResponseBody, build_authority, has_body};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_HEALTHCHECK_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_HEALTHCHECK_POLL_MS: u64 = 25;
+const DEFAULT_HEALTHCHECK_POLL_MAX_MS: u64 = 500;
+const DEFAULT_HEALTHCHECK_POLL_FACTOR: f64 = 2.0;
+const DEFAULT_JITTER: f64 = 0.2;
+const CONFIG_PATH: &str = "./backend/wave.config.json";
+const HEALTH_TIMEOUT_ENV: &str = "WAVE_HEALTH_TIMEOUT_MS";
+const NO_SPAWN_ENV: &str = "WAVE_NO_SPAWN";
+const CONFIG_PATH_ENV: &str = "WAVE_CONFIG_PATH";
+const ENV_OVERLAY_ENV: &str = "WAVE_ENV";
+const STRICT_CONFIG_ENV: &str = "WAVE_STRICT_CONFIG";
+const DEFAULT_CACHE_MAX_ENTRIES: usize = 256;
+const DEFAULT_CACHE_MAX_ENTRY_BYTES: usize = 1024 * 1024;
+const DEFAULT_PORT: u16 = 8080;
+const DEFAULT_BACKEND_HOST: &str = "127.0.0.1";
+const DEFAULT_COMMAND: &str = "main";
+const DEFAULT_CAPTURE_LOG_LINES: usize = 200;
+const DEFAULT_MAX_RESTARTS: u32 = 5;
+const DEFAULT_RESTART_RESET_MS: u64 = 600_000;
+const DEFAULT_INSTANCES: usize = 1;
+const DEFAULT_STARTUP_ATTEMPTS: u32 = 1;
+const DEFAULT_STARTUP_RETRY_DELAY_MS: u64 = 200;
+const DEFAULT_PORT_PATTERN_TIMEOUT_MS: u64 = 5_000;
+const DEFAULT_PRE_START_TIMEOUT_MS: u64 = 60_000;
+const DEFAULT_REUSE_MARKER_TTL_MS: u64 = 300_000;
+const DEFAULT_SHUTDOWN_GRACE_MS: u64 = 5_000;
+const DEFAULT_DRAIN_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_LIVENESS_INTERVAL_MS: u64 = 5_000;
+const DEFAULT_LIVENESS_TIMEOUT_MS: u64 = 2_000;
+const DEFAULT_LIVENESS_FAILURE_THRESHOLD: u32 = 3;
+const DEFAULT_UPSTREAM_RETRIES: u32 = 3;
+const DEFAULT_UPSTREAM_RETRY_DELAY_MS: u64 = 50;
+const DEFAULT_MAX_BODY_BYTES: usize = 10 * 1024 * 1024;
+const DEFAULT_MAX_REQUEST_HEADERS: usize = 200;
+const DEFAULT_MAX_REQUEST_HEADER_BYTES: usize = 64 * 1024;
+const DEFAULT_COMPRESSION_MIN_BYTES: u64 = 1024;
+const DEFAULT_BODY_REWRITE_MAX_BYTES: usize = 4 * 1024 * 1024;
+const DEFAULT_UPSTREAM_QUEUE_TIMEOUT_MS: u64 = 1_000;
+const DEFAULT_SECRET_HEADER: &str = "x-wave-secret";
+pub(crate) const UPSTREAM_HOST_REWRITE: &str = "rewrite";
+pub(crate) const UPSTREAM_HOST_PRESERVE: &str = "preserve";
+const FORWARDED_STYLE_X: &str = "x-forwarded";
+pub(crate) const FORWARDED_STYLE_RFC7239: &str = "rfc7239";
+pub(crate) const FORWARDED_STYLE_BOTH: &str = "both";
+const DEFAULT_NOT_FOUND_STATUS: u16 = 404;
+const DEFAULT_CORS_METHODS: [&str; 6] = ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"];
+const DEFAULT_NOT_FOUND_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+const DEFAULT_POOL_IDLE_TIMEOUT_MS: u64 = 15_000;
+const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 2_000;
+const DEFAULT_POOL_MAX_IDLE_PER_HOST: usize = 64;
+const DEFAULT_CIRCUIT_BREAKER_THRESHOLD: u32 = 5;
+const DEFAULT_CIRCUIT_BREAKER_WINDOW_MS: u64 = 10_000;
+const DEFAULT_CIRCUIT_BREAKER_COOLDOWN_MS: u64 = 5_000;
+pub(crate) const DEFAULT_REQUEST_ID_HEADER: &str = "x-request-id";
+const DEFAULT_HEALTH_ENDPOINT: &str = "/__wave/health";
+const DEFAULT_RESTART_ENDPOINT: &str = "/__wave/restart";
+const DEFAULT_METRICS_ENDPOINT: &str = "/__wave/metrics";
+const DEFAULT_ACCESS_LOG_FORMAT: &str =
+    "{method} {path} {status} ttfb={ttfb_ms}ms total={duration_ms}ms upstream={upstream_ms}ms";
+
+/// `Default` gives the serde defaults, with the required `Core.DistDir` and
+/// `Watch.HealthcheckEndpoint` left empty (which `validate_config` rejects).
+#[derive(Deserialize, Default)]
+pub(crate) struct Config {
+    #[serde(rename = "Core")]
+    pub(crate) core: CoreConfig,
+    #[serde(rename = "Watch")]
+    pub(crate) watch: WatchConfig,
+    #[serde(rename = "Process", default)]
+    pub(crate) process: ProcessConfig,
+    #[serde(rename = "Http", default)]
+    pub(crate) http: HttpConfig,
+    #[serde(rename = "Observability", default)]
+    pub(crate) observability: ObservabilityConfig,
+    #[serde(rename = "LoadBalancing", default)]
+    pub(crate) load_balancing: LoadBalancingConfig,
+    /// Absent turns CORS handling off and preflights go to the backend.
+    #[serde(rename = "Cors", default)]
+    pub(crate) cors: Option<CorsConfig>,
+    /// Absent turns response caching off.
+    #[serde(rename = "Cache", default)]
+    pub(crate) cache: Option<CacheConfig>,
+    /// Where the file was loaded from, and which environment variables
+    /// overrode it; reported in the startup log.
+    #[serde(skip)]
+    pub(crate) source: PathBuf,
+    /// The `WAVE_ENV` overlay merged over `source`, if there was one.
+    #[serde(skip)]
+    pub(crate) overlay: Option<PathBuf>,
+    #[serde(skip)]
+    pub(crate) env_overrides: Vec<&'static str>,
+    /// The merged files as parsed JSON, compared field by field on reload.
+    #[serde(skip)]
+    pub(crate) raw: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CoreConfig {
+    #[serde(rename = "DistDir")]
+    pub(crate) dist_dir: String,
+    /// Port the backend is told to bind via `PORT`. `0` asks the OS for a
+    /// free ephemeral port on every spawn, and picks another when the child
+    /// exits during startup in case the port was taken in between.
+    #[serde(rename = "Port", default = "default_port")]
+    pub(crate) port: u16,
+    /// `[first, last]`, inclusive: instead of `Port`, each spawn hands the
+    /// backend the first port in the range that can be bound right now.
+    #[serde(rename = "PortRange", default)]
+    pub(crate) port_range: Option<[u16; 2]>,
+    /// Unix socket path the backend should listen on instead of TCP. Passed
+    /// to the child as `WAVE_SOCKET_PATH`.
+    #[serde(rename = "BackendSocket", default)]
+    pub(crate) backend_socket: Option<String>,
+    /// Interface the backend binds, passed to the child as `HOST` and
+    /// targeted by both health checks and proxied requests.
+    #[serde(rename = "BackendHost", default = "default_backend_host")]
+    pub(crate) backend_host: String,
+    /// Directory whose files are served by the proxy itself, without waking
+    /// the backend. Requests that match no file fall through to the backend.
+    #[serde(rename = "StaticDir", default)]
+    pub(crate) static_dir: Option<String>,
+    /// Talk to the backend over TLS. Verified against the public web roots
+    /// unless `TlsCaFile` or `TlsPinnedCertFile` is set.
+    #[serde(rename = "Https", default)]
+    pub(crate) https: bool,
+    /// PEM CA certificates to trust instead of the public roots.
+    #[serde(rename = "TlsCaFile", default)]
+    pub(crate) tls_ca_file: Option<String>,
+    /// PEM certificate the backend must present, byte for byte. Chain and
+    /// name checks are skipped, which is what a self-signed loopback
+    /// certificate needs.
+    #[serde(rename = "TlsPinnedCertFile", default)]
+    pub(crate) tls_pinned_cert_file: Option<String>,
+    /// Name the backend certificate is verified against. Defaults to
+    /// `BackendHost`.
+    #[serde(rename = "TlsServerName", default)]
+    pub(crate) tls_server_name: Option<String>,
+    /// PEM certificate chain and private key presented to backends that
+    /// require mutual TLS.
+    #[serde(rename = "TlsClientCert", default)]
+    pub(crate) tls_client_cert: Option<String>,
+    #[serde(rename = "TlsClientKey", default)]
+    pub(crate) tls_client_key: Option<String>,
+}
+
+impl Default for CoreConfig {
+    fn default() -> Self {
+        Self {
+            dist_dir: String::new(),
+            port: DEFAULT_PORT,
+            port_range: None,
+            backend_socket: None,
+            backend_host: default_backend_host(),
+            static_dir: None,
+            https: false,
+            tls_ca_file: None,
+            tls_pinned_cert_file: None,
+            tls_server_name: None,
+            tls_client_cert: None,
+            tls_client_key: None,
+        }
+    }
+}
+
+fn default_backend_host() -> String {
+    DEFAULT_BACKEND_HOST.into()
+}
+
+fn default_port() -> u16 {
+    DEFAULT_PORT
+}
+
+#[derive(Deserialize)]
+pub(crate) struct WatchConfig {
+    #[serde(rename = "HealthcheckEndpoint")]
+    pub(crate) healthcheck_endpoint: String,
+    /// Cheap "process is up" check the supervisor polls to catch a hung
+    /// backend. Without it the supervisor only notices crashes.
+    #[serde(rename = "LivenessEndpoint", default)]
+    pub(crate) liveness_endpoint: Option<String>,
+    #[serde(
+        rename = "LivenessIntervalMs",
+        default = "default_liveness_interval_ms"
+    )]
+    pub(crate) liveness_interval_ms: u64,
+    #[serde(rename = "LivenessTimeoutMs", default = "default_liveness_timeout_ms")]
+    pub(crate) liveness_timeout_ms: u64,
+    /// Consecutive failed liveness checks after which an instance is
+    /// restarted.
+    #[serde(
+        rename = "LivenessFailureThreshold",
+        default = "default_liveness_failure_threshold"
+    )]
+    pub(crate) liveness_failure_threshold: u32,
+    /// Heavier "dependencies connected" check that gates traffic. Falls back
+    /// to `HealthcheckEndpoint`.
+    #[serde(rename = "ReadinessEndpoint", default)]
+    pub(crate) readiness_endpoint: Option<String>,
+    /// Proxy `Upgrade: websocket` handshakes and pipe the upgraded
+    /// connection through to the backend.
+    #[serde(rename = "ProxyWebSockets", default)]
+    pub(crate) proxy_websockets: bool,
+    /// How long `ensure_ready` waits for the backend to pass its health check.
+    /// Precedence: `WAVE_HEALTH_TIMEOUT_MS`, then this field, then 10s. The
+    /// variable is read on every spawn so it can differ between environments
+    /// without touching the file.
+    #[serde(
+        rename = "HealthcheckTimeoutMs",
+        default = "default_healthcheck_timeout_ms"
+    )]
+    pub(crate) healthcheck_timeout_ms: u64,
+    /// First delay between health polls; each subsequent delay is multiplied
+    /// by `HealthcheckPollFactor` up to `HealthcheckPollMaxMs`.
+    #[serde(rename = "HealthcheckPollMs", default = "default_healthcheck_poll_ms")]
+    pub(crate) healthcheck_poll_ms: u64,
+    #[serde(
+        rename = "HealthcheckPollMaxMs",
+        default = "default_healthcheck_poll_max_ms"
+    )]
+    pub(crate) healthcheck_poll_max_ms: u64,
+    #[serde(
+        rename = "HealthcheckPollFactor",
+        default = "default_healthcheck_poll_factor"
+    )]
+    pub(crate) healthcheck_poll_factor: f64,
+    /// Each health-check poll interval, and each delay between startup
+    /// attempts, is moved by a random amount of up to this fraction of it
+    /// either way, so containers that cold-start together don't poll or
+    /// retry in lockstep. 0 turns it off.
+    #[serde(rename = "Jitter", default = "default_jitter")]
+    pub(crate) jitter: f64,
+    /// Exact statuses that count as healthy. When absent, any 2xx does.
+    #[serde(rename = "HealthcheckExpectStatus", default)]
+    pub(crate) healthcheck_expect_status: Option<Vec<u16>>,
+    /// Substring the health response body must contain before the backend
+    /// counts as ready.
+    #[serde(rename = "HealthcheckExpectBody", default)]
+    pub(crate) healthcheck_expect_body: Option<String>,
+    /// Poll this file and apply edits without redeploying. Edits to
+    /// `Process`, `Core.DistDir` or `Core.Port` also restart the backend;
+    /// edits to settings baked into the proxy's clients are refused until
+    /// the proxy itself restarts. Defaults to on only under `vc dev`. The
+    /// file's modification time is checked once a second instead of being
+    /// watched for change events, so edits take up to a second to apply but
+    /// are seen on mounts that deliver no events too.
+    #[serde(rename = "ReloadConfig", default)]
+    pub(crate) reload_config: Option<bool>,
+    /// Readiness without HTTP: startup waits for this file to appear (or,
+    /// for a named pipe, for a line written to it) instead of polling
+    /// `ReadinessEndpoint`, within the same timeout. A stale file is removed
+    /// before each spawn, and the child finds the path in `WAVE_READY_FILE`.
+    /// Liveness checks still use HTTP.
+    #[serde(rename = "ReadySignal", default)]
+    pub(crate) ready_signal: Option<String>,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            healthcheck_endpoint: String::new(),
+            liveness_endpoint: None,
+            liveness_interval_ms: DEFAULT_LIVENESS_INTERVAL_MS,
+            liveness_timeout_ms: DEFAULT_LIVENESS_TIMEOUT_MS,
+            liveness_failure_threshold: DEFAULT_LIVENESS_FAILURE_THRESHOLD,
+            readiness_endpoint: None,
+            proxy_websockets: false,
+            healthcheck_timeout_ms: DEFAULT_HEALTHCHECK_TIMEOUT_MS,
+            healthcheck_poll_ms: DEFAULT_HEALTHCHECK_POLL_MS,
+            healthcheck_poll_max_ms: DEFAULT_HEALTHCHECK_POLL_MAX_MS,
+            healthcheck_poll_factor: DEFAULT_HEALTHCHECK_POLL_FACTOR,
+            jitter: DEFAULT_JITTER,
+            healthcheck_expect_status: None,
+            healthcheck_expect_body: None,
+            reload_config: None,
+            ready_signal: None,
+        }
+    }
+}
+
+impl WatchConfig {
+    /// Trims stray whitespace off the endpoint paths; `validate_config`
+    /// then rejects anything that still is not a plain path.
+    pub(crate) fn normalize(&mut self) {
+        self.healthcheck_endpoint = self.healthcheck_endpoint.trim().to_string();
+        for endpoint in [&mut self.liveness_endpoint, &mut self.readiness_endpoint]
+            .into_iter()
+            .flatten()
+        {
+            *endpoint = endpoint.trim().to_string();
+        }
+    }
+
+    pub(crate) fn reload_config(&self) -> bool {
+        self.reload_config.unwrap_or_else(is_vercel_dev)
+    }
+
+    pub(crate) fn readiness_endpoint(&self) -> &str {
+        self.readiness_endpoint
+            .as_deref()
+            .unwrap_or(&self.healthcheck_endpoint)
+    }
+
+    pub(crate) fn healthcheck_timeout(&self) -> Duration {
+        self.healthcheck_timeout_with(std::env::var(HEALTH_TIMEOUT_ENV).ok().as_deref())
+    }
+
+    /// `healthcheck_timeout` given the value of `WAVE_HEALTH_TIMEOUT_MS`.
+    fn healthcheck_timeout_with(&self, from_env: Option<&str>) -> Duration {
+        let from_env = from_env.and_then(|v| match v.trim().parse::<u64>() {
+            Ok(ms) if ms > 0 => Some(ms),
+            _ => {
+                tracing::warn!(value = %v, "ignoring invalid {HEALTH_TIMEOUT_ENV}");
+                None
+            }
+        });
+        Duration::from_millis(from_env.unwrap_or(self.healthcheck_timeout_ms))
+    }
+}
+
+/// Running under `vc dev`, which the runtime signals with `VERCEL_DEV=1`.
+pub(crate) fn is_vercel_dev() -> bool {
+    std::env::var("VERCEL_DEV").as_deref() == Ok("1")
+}
+
+fn default_liveness_interval_ms() -> u64 {
+    DEFAULT_LIVENESS_INTERVAL_MS
+}
+
+fn default_liveness_timeout_ms() -> u64 {
+    DEFAULT_LIVENESS_TIMEOUT_MS
+}
+
+fn default_liveness_failure_threshold() -> u32 {
+    DEFAULT_LIVENESS_FAILURE_THRESHOLD
+}
+
+fn default_healthcheck_timeout_ms() -> u64 {
+    DEFAULT_HEALTHCHECK_TIMEOUT_MS
+}
+
+fn default_healthcheck_poll_ms() -> u64 {
+    DEFAULT_HEALTHCHECK_POLL_MS
+}
+
+fn default_healthcheck_poll_max_ms() -> u64 {
+    DEFAULT_HEALTHCHECK_POLL_MAX_MS
+}
+
+fn default_jitter() -> f64 {
+    DEFAULT_JITTER
+}
+
+fn default_healthcheck_poll_factor() -> f64 {
+    DEFAULT_HEALTHCHECK_POLL_FACTOR
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ProcessConfig {
+    /// How many times a crashed backend is respawned before `ensure_ready`
+    /// gives up and every request gets a 503. A crash is an instance that
+    /// exited, however it was noticed, or one failing its liveness checks.
+    #[serde(rename = "MaxRestarts", default = "default_max_restarts")]
+    pub(crate) max_restarts: u32,
+    /// A crash arriving this long after the previous one starts the count
+    /// over, so only crashes in quick succession add up to `MaxRestarts`.
+    /// 0 counts over the proxy's whole lifetime. Either way, once the limit
+    /// is hit the backend stays down until the admin restart endpoint is
+    /// used.
+    #[serde(rename = "RestartResetMs", default = "default_restart_reset_ms")]
+    pub(crate) restart_reset_ms: u64,
+    /// How long the child gets to exit after SIGTERM before it is SIGKILLed.
+    /// Ignored on Windows, where the child is always hard-killed.
+    #[serde(rename = "ShutdownGraceMs", default = "default_shutdown_grace_ms")]
+    pub(crate) shutdown_grace_ms: u64,
+    /// Extra environment for the child. `${VAR}` in a value is replaced with
+    /// the proxy's own `VAR` (empty if unset). The variables the proxy sets
+    /// itself (`PORT`, `HOST`, `WAVE_SOCKET_PATH`) always win.
+    #[serde(rename = "Env", default)]
+    pub(crate) env: BTreeMap<String, String>,
+    /// Backend executable, resolved relative to `Core.DistDir`. An absolute
+    /// path is used as is.
+    #[serde(rename = "Command", default = "default_command")]
+    pub(crate) command: String,
+    /// Directory the backend runs in, relative to the site root. Unset, it
+    /// inherits the proxy's own.
+    #[serde(rename = "WorkingDir", default)]
+    pub(crate) working_dir: Option<String>,
+    /// Passed to the backend, with `${PORT}` and `${HOST}` replaced by the
+    /// address it should bind, for backends that take flags instead of
+    /// reading `PORT`. The env vars are set either way.
+    #[serde(rename = "Args", default)]
+    pub(crate) args: Vec<String>,
+    /// Program and arguments run to completion in `WorkingDir`, with `Env`,
+    /// before a cold start spawns the backend: migrations, asset builds and
+    /// the like. A non-zero exit, or running past `PreStartTimeoutMs`, fails
+    /// the startup attempt with the tail of its output. Empty runs nothing.
+    #[serde(rename = "PreStart", default)]
+    pub(crate) pre_start: Vec<String>,
+    #[serde(rename = "PreStartTimeoutMs", default = "default_pre_start_timeout_ms")]
+    pub(crate) pre_start_timeout_ms: u64,
+    /// For backends that pick their own port: a regex whose first capture
+    /// group is the port, matched against the child's stdout. The proxy
+    /// connects to the first port it sees, or to the configured one if none
+    /// shows up within `PortPatternTimeoutMs`.
+    #[serde(
+        rename = "PortPattern",
+        default,
+        deserialize_with = "deserialize_optional_regex"
+    )]
+    pub(crate) port_pattern: Option<regex::Regex>,
+    #[serde(
+        rename = "PortPatternTimeoutMs",
+        default = "default_port_pattern_timeout_ms"
+    )]
+    pub(crate) port_pattern_timeout_ms: u64,
+    /// Pipe the child's stdout/stderr through the proxy, keeping the last
+    /// `CaptureLogLines` lines so startup failures can report them. Lines are
+    /// still echoed to the function logs.
+    #[serde(rename = "CaptureLogs", default)]
+    pub(crate) capture_logs: bool,
+    #[serde(rename = "CaptureLogLines", default = "default_capture_log_lines")]
+    pub(crate) capture_log_lines: usize,
+    /// How long shutdown waits for in-flight requests before stopping the
+    /// backend. New requests get a 503 in the meantime.
+    #[serde(rename = "DrainTimeoutMs", default = "default_drain_timeout_ms")]
+    pub(crate) drain_timeout_ms: u64,
+    /// Start the backend as soon as the proxy boots instead of on the first
+    /// request, hiding spawn and health-check latency from cold starts.
+    #[serde(rename = "PrewarmOnStart", default)]
+    pub(crate) prewarm_on_start: bool,
+    /// Number of backend processes, on consecutive ports starting at
+    /// `Core.Port` (or each on its own ephemeral port). Requests are spread
+    /// across them round-robin.
+    #[serde(rename = "Instances", default = "default_instances")]
+    pub(crate) instances: usize,
+    /// Never start or stop the backend; only health-check the configured
+    /// port or socket until someone else (e.g. a debugger) has it running.
+    /// `WAVE_NO_SPAWN=1` turns it on without editing the config.
+    #[serde(rename = "NoSpawn", default)]
+    pub(crate) no_spawn: bool,
+    /// How many full spawn and health-check cycles a cold start gets before
+    /// failing, with `StartupRetryDelayMs` doubling between them. Guards
+    /// against transient failures such as a binary still being written
+    /// during a deploy.
+    #[serde(rename = "StartupAttempts", default = "default_startup_attempts")]
+    pub(crate) startup_attempts: u32,
+    #[serde(
+        rename = "StartupRetryDelayMs",
+        default = "default_startup_retry_delay_ms"
+    )]
+    pub(crate) startup_retry_delay_ms: u64,
+    /// How long a request that finds the backend down keeps waiting for it
+    /// when a (re)start attempt fails, retrying instead of answering 503
+    /// straight away. Counted from when the request started waiting and
+    /// separate from `HealthcheckTimeoutMs`, which bounds each attempt.
+    /// 0 fails fast.
+    #[serde(rename = "RestartWaitMs", default)]
+    pub(crate) restart_wait_ms: u64,
+    /// File recording the pid and port of the running backend, rewritten
+    /// while it is up. A cold start that finds one younger than
+    /// `ReuseMarkerTtlMs`, for the same `Command`, whose process is alive
+    /// and passes a health check, adopts that backend instead of spawning
+    /// another; it is then stopped, restarted and watched like a spawned
+    /// one. An orderly shutdown stops the backend and removes the marker,
+    /// so this only covers a proxy that dies without one (SIGKILL, OOM).
+    /// Unset always starts fresh.
+    #[serde(rename = "ReuseMarker", default)]
+    pub(crate) reuse_marker: Option<String>,
+    #[serde(rename = "ReuseMarkerTtlMs", default = "default_reuse_marker_ttl_ms")]
+    pub(crate) reuse_marker_ttl_ms: u64,
+    /// Stop the backend after this long without a request, trading a cold
+    /// start on the next one for lower idle memory. Unset keeps it running.
+    #[serde(rename = "IdleTimeoutMs", default)]
+    pub(crate) idle_timeout_ms: Option<u64>,
+}
+
+impl ProcessConfig {
+    pub(crate) fn command_path(&self, dist_dir: &str) -> PathBuf {
+        Path::new(".").join(dist_dir).join(&self.command)
+    }
+
+    pub(crate) fn no_spawn(&self) -> bool {
+        self.no_spawn || std::env::var(NO_SPAWN_ENV).is_ok_and(|v| v == "1")
+    }
+}
+
+impl Default for ProcessConfig {
+    fn default() -> Self {
+        Self {
+            max_restarts: DEFAULT_MAX_RESTARTS,
+            restart_reset_ms: DEFAULT_RESTART_RESET_MS,
+            shutdown_grace_ms: DEFAULT_SHUTDOWN_GRACE_MS,
+            env: BTreeMap::new(),
+            command: default_command(),
+            working_dir: None,
+            args: Vec::new(),
+            pre_start: Vec::new(),
+            pre_start_timeout_ms: DEFAULT_PRE_START_TIMEOUT_MS,
+            port_pattern: None,
+            port_pattern_timeout_ms: DEFAULT_PORT_PATTERN_TIMEOUT_MS,
+            capture_logs: false,
+            capture_log_lines: DEFAULT_CAPTURE_LOG_LINES,
+            drain_timeout_ms: DEFAULT_DRAIN_TIMEOUT_MS,
+            prewarm_on_start: false,
+            instances: DEFAULT_INSTANCES,
+            no_spawn: false,
+            startup_attempts: DEFAULT_STARTUP_ATTEMPTS,
+            startup_retry_delay_ms: DEFAULT_STARTUP_RETRY_DELAY_MS,
+            restart_wait_ms: 0,
+            reuse_marker: None,
+            reuse_marker_ttl_ms: DEFAULT_REUSE_MARKER_TTL_MS,
+            idle_timeout_ms: None,
+        }
+    }
+}
+
+/// Expands `${VAR}` references against the proxy's environment. An
+/// unterminated `${` is kept literally.
+pub(crate) fn interpolate_env(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find('}') else {
+            out.push_str(&rest[start..]);
+            return out;
+        };
+        let name = &after[..end];
+        match std::env::var(name) {
+            Ok(v) => out.push_str(&v),
+            Err(_) => tracing::warn!(var = name, "Process.Env references unset variable"),
+        }
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+pub(crate) fn expand_args(args: &[String], port: u16, host: &str) -> Vec<String> {
+    let port = port.to_string();
+    args.iter()
+        .map(|arg| arg.replace("${PORT}", &port).replace("${HOST}", host))
+        .collect()
+}
+
+fn default_startup_attempts() -> u32 {
+    DEFAULT_STARTUP_ATTEMPTS
+}
+
+fn default_pre_start_timeout_ms() -> u64 {
+    DEFAULT_PRE_START_TIMEOUT_MS
+}
+
+fn default_port_pattern_timeout_ms() -> u64 {
+    DEFAULT_PORT_PATTERN_TIMEOUT_MS
+}
+
+fn default_startup_retry_delay_ms() -> u64 {
+    DEFAULT_STARTUP_RETRY_DELAY_MS
+}
+
+fn default_reuse_marker_ttl_ms() -> u64 {
+    DEFAULT_REUSE_MARKER_TTL_MS
+}
+
+fn default_instances() -> usize {
+    DEFAULT_INSTANCES
+}
+
+fn default_drain_timeout_ms() -> u64 {
+    DEFAULT_DRAIN_TIMEOUT_MS
+}
+
+fn default_capture_log_lines() -> usize {
+    DEFAULT_CAPTURE_LOG_LINES
+}
+
+fn default_command() -> String {
+    DEFAULT_COMMAND.to_string()
+}
+
+fn default_max_restarts() -> u32 {
+    DEFAULT_MAX_RESTARTS
+}
+
+fn default_restart_reset_ms() -> u64 {
+    DEFAULT_RESTART_RESET_MS
+}
+
+fn default_shutdown_grace_ms() -> u64 {
+    DEFAULT_SHUTDOWN_GRACE_MS
+}
+
+#[derive(Deserialize)]
+pub(crate) struct HttpConfig {
+    /// Extra attempts made when the backend refuses the connection. Responses
+    /// the backend actually returned, including 5xx, are never retried.
+    #[serde(rename = "UpstreamRetries", default = "default_upstream_retries")]
+    pub(crate) upstream_retries: u32,
+    /// Delay before the first retry; doubles on every subsequent attempt.
+    #[serde(
+        rename = "UpstreamRetryDelayMs",
+        default = "default_upstream_retry_delay_ms"
+    )]
+    pub(crate) upstream_retry_delay_ms: u64,
+    /// Request bodies are buffered in memory so they can be replayed on
+    /// retry; anything larger is rejected with a 413. With retries disabled
+    /// bodies are streamed through unbuffered and this cap does not apply.
+    ///
+    /// Buffered, each in-flight request can hold up to this many bytes, so
+    /// upload-heavy apps should turn retries off (globally or per route with
+    /// `BufferBody: false`). Streamed, the proxy holds at most a frame or two
+    /// per request: it only reads from the client as fast as the backend
+    /// reads from it.
+    #[serde(rename = "MaxBodyBytes", default = "default_max_body_bytes")]
+    pub(crate) max_body_bytes: usize,
+    /// Cap on a response body from the backend. One whose `Content-Length`
+    /// is over it gets a 502 instead; one that streams past it is cut off
+    /// there, closing the backend connection, and the client sees the body
+    /// end early. Unset allows any size.
+    #[serde(rename = "MaxResponseBytes", default)]
+    pub(crate) max_response_bytes: Option<u64>,
+    /// Decide per request instead: bodies with a `Content-Length` up to this
+    /// many bytes (and requests without a body) are buffered and retried,
+    /// larger or chunked ones are streamed without retries. A route's
+    /// `BufferBody` still wins.
+    #[serde(rename = "BufferThresholdBytes", default)]
+    pub(crate) buffer_threshold_bytes: Option<usize>,
+    /// Requests with more header fields, or more header bytes (names and
+    /// values as they would go on the wire), get a 431 before anything is
+    /// sent to the backend.
+    #[serde(rename = "MaxRequestHeaders", default = "default_max_request_headers")]
+    pub(crate) max_request_headers: usize,
+    #[serde(
+        rename = "MaxRequestHeaderBytes",
+        default = "default_max_request_header_bytes"
+    )]
+    pub(crate) max_request_header_bytes: usize,
+    /// Synthesize `X-Forwarded-For`/`-Proto`/`-Host` on upstream requests.
+    /// Turn off for backends that already trust headers set further out.
+    #[serde(rename = "ForwardedHeaders", default = "default_true")]
+    pub(crate) forwarded_headers: bool,
+    /// Which forwarding headers those are: `x-forwarded`, `rfc7239` for a
+    /// standard `Forwarded: for=...;proto=...;host=...` appended to any the
+    /// client sent, or `both`.
+    #[serde(rename = "ForwardedStyle", default = "default_forwarded_style")]
+    pub(crate) forwarded_style: String,
+    /// Also send `X-Forwarded-Proto-Version` with the HTTP version the client
+    /// spoke (`HTTP/1.1`, `HTTP/2`, ...), which the hop to the backend
+    /// doesn't preserve. Needs `ForwardedHeaders`.
+    #[serde(rename = "ForwardedProtoVersion", default)]
+    pub(crate) forwarded_proto_version: bool,
+    /// Compress responses with brotli or gzip, per `Accept-Encoding`.
+    /// Responses the backend already encoded are passed through.
+    #[serde(rename = "Compression", default)]
+    pub(crate) compression: bool,
+    /// Responses with a known `Content-Length` below this are sent as is.
+    #[serde(
+        rename = "CompressionMinBytes",
+        default = "default_compression_min_bytes"
+    )]
+    pub(crate) compression_min_bytes: u64,
+    /// Bounds the wait for the backend's response headers; the body streams
+    /// unbounded once they arrive. Unset means wait indefinitely.
+    #[serde(rename = "UpstreamRequestTimeoutMs", default)]
+    pub(crate) upstream_request_timeout_ms: Option<u64>,
+    /// Consecutive upstream failures within `CircuitBreakerWindowMs` that open
+    /// the circuit. 0 disables the breaker.
+    #[serde(
+        rename = "CircuitBreakerThreshold",
+        default = "default_circuit_breaker_threshold"
+    )]
+    pub(crate) circuit_breaker_threshold: u32,
+    #[serde(
+        rename = "CircuitBreakerWindowMs",
+        default = "default_circuit_breaker_window_ms"
+    )]
+    pub(crate) circuit_breaker_window_ms: u64,
+    /// How long an open circuit rejects requests before letting one probe
+    /// through.
+    #[serde(
+        rename = "CircuitBreakerCooldownMs",
+        default = "default_circuit_breaker_cooldown_ms"
+    )]
+    pub(crate) circuit_breaker_cooldown_ms: u64,
+    /// Removed from the front of every request path before it is forwarded.
+    /// Requests outside the prefix get a 404 without reaching the backend.
+    #[serde(rename = "StripPathPrefix", default)]
+    pub(crate) strip_path_prefix: Option<String>,
+    /// Prepended to every forwarded path, after `StripPathPrefix`.
+    #[serde(rename = "AddPathPrefix", default)]
+    pub(crate) add_path_prefix: Option<String>,
+    /// Query parameters removed before the request is forwarded, by exact
+    /// name or, with a trailing `*`, by prefix (`utm_*`). The rest keep
+    /// their order and encoding; a query left empty is dropped with its `?`.
+    #[serde(rename = "StripQueryParams", default)]
+    pub(crate) strip_query_params: Vec<String>,
+    /// Speak HTTP/2 with prior knowledge (h2c) to the backend, which must
+    /// accept it on the same port (in Go, wrap the handler with
+    /// `h2c.NewHandler`). Health checks and upgrades stay on HTTP/1.1.
+    #[serde(rename = "Http2", default)]
+    pub(crate) http2: bool,
+    /// `Host` sent to the backend: `rewrite` uses the backend's own address,
+    /// `preserve` passes on the client's, and anything else is sent as a
+    /// literal value.
+    #[serde(rename = "UpstreamHost", default = "default_upstream_host")]
+    pub(crate) upstream_host: String,
+    /// Set on every upstream request, replacing any header of the same name.
+    #[serde(rename = "RequestHeaders", default)]
+    pub(crate) request_headers: BTreeMap<String, String>,
+    /// Set on every response, replacing any header of the same name.
+    #[serde(rename = "ResponseHeaders", default)]
+    pub(crate) response_headers: BTreeMap<String, String>,
+    /// Media types a request body may have, e.g. `application/json` or
+    /// `image/*`; anything else gets a 415. Parameters such as `charset` are
+    /// ignored. A body without a `Content-Type` is refused too, requests
+    /// without a body always pass. Empty allows everything.
+    #[serde(rename = "AllowedContentTypes", default)]
+    pub(crate) allowed_content_types: Vec<String>,
+    /// Paths answered with the `NotFound*` response below without waking or
+    /// reaching the backend, for noisy requests such as `/favicon.ico`. Each
+    /// is an exact path, or a prefix when it ends in `*`; keep the list
+    /// narrow since a match shadows any backend route.
+    #[serde(rename = "NotFoundPaths", default)]
+    pub(crate) not_found_paths: Vec<String>,
+    #[serde(rename = "NotFoundStatus", default = "default_not_found_status")]
+    pub(crate) not_found_status: u16,
+    #[serde(rename = "NotFoundBody", default)]
+    pub(crate) not_found_body: String,
+    #[serde(
+        rename = "NotFoundContentType",
+        default = "default_not_found_content_type"
+    )]
+    pub(crate) not_found_content_type: String,
+    /// Send `Connection: close` on responses finished while draining, so
+    /// clients and load balancers stop reusing the connection.
+    #[serde(rename = "CloseOnDrain", default = "default_true")]
+    pub(crate) close_on_drain: bool,
+    /// Point absolute `Location` headers aimed at the backend's own address
+    /// back at the host and scheme the client used.
+    #[serde(rename = "RewriteLocation", default = "default_true")]
+    pub(crate) rewrite_location: bool,
+    /// Regex replacements applied to backend response headers, in order,
+    /// after `RewriteLocation`.
+    #[serde(rename = "HeaderRewrites", default)]
+    pub(crate) header_rewrites: Vec<HeaderRewrite>,
+    /// Regex replacements applied, in order, to response bodies whose
+    /// content type a rule lists. Rewriting needs the whole body, so a
+    /// matching response is held in memory until it ends (delaying the first
+    /// byte) and, when the backend compressed it with gzip, deflate or br,
+    /// decompressed first and compressed again afterwards, which costs CPU
+    /// on every such response. Everything else streams as usual.
+    #[serde(rename = "BodyRewrites", default)]
+    pub(crate) body_rewrites: Vec<BodyRewrite>,
+    /// Past this many (decoded) bytes a body is streamed through unchanged.
+    #[serde(
+        rename = "BodyRewriteMaxBytes",
+        default = "default_body_rewrite_max_bytes"
+    )]
+    pub(crate) body_rewrite_max_bytes: usize,
+    /// Compress a rewritten body again in the encoding the backend used.
+    /// Off, it is sent decoded unless `Compression` picks an encoding.
+    #[serde(rename = "BodyRewriteRecompress", default = "default_true")]
+    pub(crate) body_rewrite_recompress: bool,
+    /// How long opening a TCP connection to the backend may take before it
+    /// counts as refused, so health polls and retries against a backend
+    /// that is bound but not accepting fail fast instead of waiting on the
+    /// OS connect timeout. 0 leaves it to the OS.
+    #[serde(rename = "ConnectTimeoutMs", default = "default_connect_timeout_ms")]
+    pub(crate) connect_timeout_ms: u64,
+    /// How long an idle backend connection is kept for reuse. Kept short
+    /// because a frozen serverless container can outlive the backend's own
+    /// keep-alive.
+    #[serde(rename = "PoolIdleTimeoutMs", default = "default_pool_idle_timeout_ms")]
+    pub(crate) pool_idle_timeout_ms: u64,
+    #[serde(
+        rename = "PoolMaxIdlePerHost",
+        default = "default_pool_max_idle_per_host"
+    )]
+    pub(crate) pool_max_idle_per_host: usize,
+    /// Sets `TCP_NODELAY` on backend connections. Loopback round trips are
+    /// tiny, so Nagle's algorithm only adds latency; off is mostly useful
+    /// for comparing against it.
+    #[serde(rename = "TcpNodelay", default = "default_true")]
+    pub(crate) tcp_nodelay: bool,
+    /// HTML file served, with the 503 and `no-store`, instead of the JSON
+    /// error when the backend cannot be started. Read once, on the first
+    /// failure, and kept in memory; a missing file falls back to the
+    /// error body.
+    #[serde(rename = "MaintenancePage", default)]
+    pub(crate) maintenance_page: Option<String>,
+    /// Only serve `MaintenancePage` to clients whose `Accept` lists
+    /// `text/html`, so API callers keep getting the plain or JSON error.
+    /// Off serves it to everyone.
+    #[serde(rename = "MaintenancePageHtmlOnly", default = "default_true")]
+    pub(crate) maintenance_page_html_only: bool,
+    /// Caps simultaneous requests to the backend; unset means unlimited.
+    /// Excess requests wait up to `UpstreamQueueTimeoutMs` before a 503.
+    #[serde(rename = "MaxConcurrentUpstream", default)]
+    pub(crate) max_concurrent_upstream: Option<usize>,
+    #[serde(
+        rename = "UpstreamQueueTimeoutMs",
+        default = "default_upstream_queue_timeout_ms"
+    )]
+    pub(crate) upstream_queue_timeout_ms: u64,
+    /// Environment variable holding a shared secret sent to the backend in
+    /// `SecretHeader` on every request, so it can refuse traffic that did not
+    /// come through the proxy. The header is stripped from responses.
+    #[serde(rename = "SecretEnv", default)]
+    pub(crate) secret_env: Option<String>,
+    #[serde(rename = "SecretHeader", default = "default_secret_header")]
+    pub(crate) secret_header: String,
+    /// Methods the proxy forwards; anything else gets a 405 with `Allow`.
+    /// `HEAD` rides along with `GET`. Empty allows every method.
+    #[serde(rename = "AllowedMethods", default)]
+    pub(crate) allowed_methods: Vec<String>,
+    /// Per-path overrides of the upstream settings above.
+    #[serde(rename = "Routes", default)]
+    pub(crate) routes: Vec<RouteConfig>,
+}
+
+impl HttpConfig {
+    /// Checks a request against `MaxRequestHeaders` and
+    /// `MaxRequestHeaderBytes`, returning why it is refused.
+    pub(crate) fn header_limit_refusal(&self, headers: &hyper::HeaderMap) -> Option<String> {
+        let mut bytes = 0;
+        for (count, (name, value)) in headers.iter().enumerate() {
+            if count + 1 > self.max_request_headers {
+                return Some(format!(
+                    "more than {} request headers",
+                    self.max_request_headers
+                ));
+            }
+            // `name: value\r\n`
+            bytes += name.as_str().len() + value.len() + 4;
+            if bytes > self.max_request_header_bytes {
+                return Some(format!(
+                    "request headers exceed {} bytes",
+                    self.max_request_header_bytes
+                ));
+            }
+        }
+        None
+    }
+
+    /// Checks a request against `AllowedContentTypes`, returning why it is
+    /// refused.
+    pub(crate) fn content_type_refusal(&self, headers: &hyper::HeaderMap) -> Option<String> {
+        if self.allowed_content_types.is_empty() || !has_body(headers) {
+            return None;
+        }
+        let Some(value) = headers.get(hyper::header::CONTENT_TYPE) else {
+            return Some("request body has no content-type".into());
+        };
+        let media_type = media_type(value);
+        let allowed = self
+            .allowed_content_types
+            .iter()
+            .any(|pattern| media_type_matches(pattern, &media_type));
+        (!allowed).then(|| format!("content-type {media_type:?} is not allowed"))
+    }
+
+    pub(crate) fn is_not_found_path(&self, path: &str) -> bool {
+        self.not_found_paths
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => path.starts_with(prefix),
+                None => path == pattern,
+            })
+    }
+
+    pub(crate) fn strips_query_param(&self, name: &str) -> bool {
+        self.strip_query_params
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pattern,
+            })
+    }
+
+    /// The route with the longest prefix matching `path`.
+    fn route(&self, path: &str) -> Option<&RouteConfig> {
+        self.routes
+            .iter()
+            .filter(|r| r.matches(path))
+            .max_by_key(|r| r.prefix.trim_end_matches('/').len())
+    }
+
+    /// The methods allowed on `path`, or `None` when all are.
+    pub(crate) fn allowed_methods(&self, path: &str) -> Option<&[String]> {
+        let methods = self
+            .route(path)
+            .and_then(|r| r.allowed_methods.as_deref())
+            .unwrap_or(&self.allowed_methods);
+        (!methods.is_empty()).then_some(methods)
+    }
+
+    /// The upstream settings for `path`: those of the route with the longest
+    /// matching prefix, falling back to the global ones field by field.
+    pub(crate) fn route_settings(&self, path: &str, headers: &hyper::HeaderMap) -> RouteSettings {
+        let route = self.route(path);
+        let timeout = match route.and_then(|r| r.upstream_request_timeout_ms) {
+            Some(0) => None,
+            Some(ms) => Some(ms),
+            None => self.upstream_request_timeout_ms,
+        };
+        let retries = route
+            .and_then(|r| r.upstream_retries)
+            .unwrap_or(self.upstream_retries);
+        let buffer_body = match (
+            route.and_then(|r| r.buffer_body),
+            self.buffer_threshold_bytes,
+        ) {
+            (Some(buffer), _) => buffer,
+            (None, Some(threshold)) => {
+                retries > 0
+                    && request_body_length(headers).is_some_and(|len| len <= threshold as u64)
+            }
+            (None, None) => retries > 0,
+        };
+        RouteSettings {
+            timeout: timeout.map(Duration::from_millis),
+            // A streamed body cannot be replayed.
+            retries: if buffer_body { retries } else { 0 },
+            buffer_body,
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            upstream_retries: DEFAULT_UPSTREAM_RETRIES,
+            upstream_retry_delay_ms: DEFAULT_UPSTREAM_RETRY_DELAY_MS,
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            max_response_bytes: None,
+            buffer_threshold_bytes: None,
+            max_request_headers: DEFAULT_MAX_REQUEST_HEADERS,
+            max_request_header_bytes: DEFAULT_MAX_REQUEST_HEADER_BYTES,
+            forwarded_headers: true,
+            forwarded_style: default_forwarded_style(),
+            forwarded_proto_version: false,
+            compression: false,
+            compression_min_bytes: DEFAULT_COMPRESSION_MIN_BYTES,
+            upstream_request_timeout_ms: None,
+            circuit_breaker_threshold: DEFAULT_CIRCUIT_BREAKER_THRESHOLD,
+            circuit_breaker_window_ms: DEFAULT_CIRCUIT_BREAKER_WINDOW_MS,
+            circuit_breaker_cooldown_ms: DEFAULT_CIRCUIT_BREAKER_COOLDOWN_MS,
+            strip_path_prefix: None,
+            add_path_prefix: None,
+            strip_query_params: Vec::new(),
+            http2: false,
+            upstream_host: default_upstream_host(),
+            request_headers: BTreeMap::new(),
+            response_headers: BTreeMap::new(),
+            allowed_content_types: Vec::new(),
+            not_found_paths: Vec::new(),
+            not_found_status: DEFAULT_NOT_FOUND_STATUS,
+            not_found_body: String::new(),
+            not_found_content_type: default_not_found_content_type(),
+            close_on_drain: true,
+            rewrite_location: true,
+            header_rewrites: Vec::new(),
+            body_rewrites: Vec::new(),
+            body_rewrite_max_bytes: DEFAULT_BODY_REWRITE_MAX_BYTES,
+            body_rewrite_recompress: true,
+            connect_timeout_ms: DEFAULT_CONNECT_TIMEOUT_MS,
+            pool_idle_timeout_ms: DEFAULT_POOL_IDLE_TIMEOUT_MS,
+            pool_max_idle_per_host: DEFAULT_POOL_MAX_IDLE_PER_HOST,
+            tcp_nodelay: true,
+            maintenance_page: None,
+            maintenance_page_html_only: true,
+            max_concurrent_upstream: None,
+            upstream_queue_timeout_ms: DEFAULT_UPSTREAM_QUEUE_TIMEOUT_MS,
+            secret_env: None,
+            secret_header: default_secret_header(),
+            allowed_methods: Vec::new(),
+            routes: Vec::new(),
+        }
+    }
+}
+
+/// The declared length of a request body: 0 without one, `None` when it is
+/// chunked or the length is unreadable.
+fn request_body_length(headers: &hyper::HeaderMap) -> Option<u64> {
+    if headers.contains_key(hyper::header::TRANSFER_ENCODING) {
+        return None;
+    }
+    match headers.get(hyper::header::CONTENT_LENGTH) {
+        Some(value) => value.to_str().ok()?.parse().ok(),
+        None => Some(0),
+    }
+}
+
+/// The lowercased `type/subtype` of a content-type value, without parameters.
+pub(crate) fn media_type(value: &hyper::header::HeaderValue) -> String {
+    value
+        .to_str()
+        .unwrap_or_default()
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Matches a media type against an exact pattern, `type/*` or `*/*`.
+pub(crate) fn media_type_matches(pattern: &str, media_type: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    match pattern.strip_suffix("/*") {
+        Some("*") => true,
+        Some(kind) => media_type
+            .split_once('/')
+            .is_some_and(|(left, _)| left == kind),
+        None => pattern == media_type,
+    }
+}
+
+/// An `Http.HeaderRewrites` entry. `Replace` may refer to capture groups
+/// as `$1` or `${name}`.
+#[derive(Deserialize)]
+pub(crate) struct HeaderRewrite {
+    #[serde(rename = "Header")]
+    pub(crate) header: String,
+    #[serde(rename = "Match", deserialize_with = "deserialize_regex")]
+    pub(crate) pattern: regex::Regex,
+    #[serde(rename = "Replace")]
+    pub(crate) replace: String,
+}
+
+/// An `Http.BodyRewrites` entry. `ContentTypes` takes the same patterns as
+/// `AllowedContentTypes`.
+#[derive(Clone, Deserialize)]
+pub(crate) struct BodyRewrite {
+    #[serde(
+        rename = "ContentTypes",
+        default = "default_body_rewrite_content_types"
+    )]
+    pub(crate) content_types: Vec<String>,
+    #[serde(rename = "Match", deserialize_with = "deserialize_regex")]
+    pub(crate) pattern: regex::Regex,
+    #[serde(rename = "Replace")]
+    pub(crate) replace: String,
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<regex::Regex, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let pattern = String::deserialize(deserializer)?;
+    regex::Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_optional_regex<'de, D>(deserializer: D) -> Result<Option<regex::Regex>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|pattern| regex::Regex::new(&pattern).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// An `Http.Routes` entry, applying to `Prefix` and every path below it.
+#[derive(Deserialize)]
+pub(crate) struct RouteConfig {
+    #[serde(rename = "Prefix")]
+    pub(crate) prefix: String,
+    /// 0 lifts the global timeout for this route, e.g. for slow uploads.
+    #[serde(rename = "UpstreamRequestTimeoutMs", default)]
+    pub(crate) upstream_request_timeout_ms: Option<u64>,
+    #[serde(rename = "UpstreamRetries", default)]
+    pub(crate) upstream_retries: Option<u32>,
+    /// Buffer the request body before sending it, which retries require.
+    /// Defaults to buffering exactly when retries are enabled.
+    #[serde(rename = "BufferBody", default)]
+    pub(crate) buffer_body: Option<bool>,
+    /// Replaces `Http.AllowedMethods` for this route; empty allows all.
+    #[serde(rename = "AllowedMethods", default)]
+    pub(crate) allowed_methods: Option<Vec<String>>,
+}
+
+impl RouteConfig {
+    pub(crate) fn matches(&self, path: &str) -> bool {
+        path.strip_prefix(self.prefix.trim_end_matches('/'))
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+}
+
+/// Upstream settings resolved for one request.
+pub(crate) struct RouteSettings {
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) retries: u32,
+    pub(crate) buffer_body: bool,
+}
+
+fn default_upstream_queue_timeout_ms() -> u64 {
+    DEFAULT_UPSTREAM_QUEUE_TIMEOUT_MS
+}
+
+fn default_secret_header() -> String {
+    DEFAULT_SECRET_HEADER.into()
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    DEFAULT_CONNECT_TIMEOUT_MS
+}
+
+fn default_pool_idle_timeout_ms() -> u64 {
+    DEFAULT_POOL_IDLE_TIMEOUT_MS
+}
+
+fn default_pool_max_idle_per_host() -> usize {
+    DEFAULT_POOL_MAX_IDLE_PER_HOST
+}
+
+fn default_compression_min_bytes() -> u64 {
+    DEFAULT_COMPRESSION_MIN_BYTES
+}
+
+fn default_body_rewrite_max_bytes() -> usize {
+    DEFAULT_BODY_REWRITE_MAX_BYTES
+}
+
+fn default_body_rewrite_content_types() -> Vec<String> {
+    vec!["text/html".to_string()]
+}
+
+fn default_forwarded_style() -> String {
+    FORWARDED_STYLE_X.into()
+}
+
+fn default_upstream_host() -> String {
+    UPSTREAM_HOST_REWRITE.to_string()
+}
+
+fn default_not_found_status() -> u16 {
+    DEFAULT_NOT_FOUND_STATUS
+}
+
+fn default_not_found_content_type() -> String {
+    DEFAULT_NOT_FOUND_CONTENT_TYPE.to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_circuit_breaker_threshold() -> u32 {
+    DEFAULT_CIRCUIT_BREAKER_THRESHOLD
+}
+
+fn default_circuit_breaker_window_ms() -> u64 {
+    DEFAULT_CIRCUIT_BREAKER_WINDOW_MS
+}
+
+fn default_circuit_breaker_cooldown_ms() -> u64 {
+    DEFAULT_CIRCUIT_BREAKER_COOLDOWN_MS
+}
+
+fn default_upstream_retries() -> u32 {
+    DEFAULT_UPSTREAM_RETRIES
+}
+
+fn default_upstream_retry_delay_ms() -> u64 {
+    DEFAULT_UPSTREAM_RETRY_DELAY_MS
+}
+
+fn default_max_request_headers() -> usize {
+    DEFAULT_MAX_REQUEST_HEADERS
+}
+
+fn default_max_request_header_bytes() -> usize {
+    DEFAULT_MAX_REQUEST_HEADER_BYTES
+}
+
+fn default_max_body_bytes() -> usize {
+    DEFAULT_MAX_BODY_BYTES
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ObservabilityConfig {
+    /// Header carrying the request id; reused from the client when present,
+    /// otherwise generated, and always forwarded upstream and echoed back.
+    #[serde(rename = "RequestIdHeader", default = "default_request_id_header")]
+    pub(crate) request_id_header: String,
+    /// Serve Prometheus text metrics from `MetricsEndpoint` instead of
+    /// proxying that path.
+    #[serde(rename = "Metrics", default)]
+    pub(crate) metrics: bool,
+    #[serde(rename = "MetricsEndpoint", default = "default_metrics_endpoint")]
+    pub(crate) metrics_endpoint: String,
+    /// Answered by the proxy itself with the backend's readiness, without
+    /// ever spawning it, so monitors can tell a cold backend from an outage.
+    #[serde(rename = "HealthEndpoint", default = "default_health_endpoint")]
+    pub(crate) health_endpoint: String,
+    /// Names the env var holding the admin token. When set, a `POST` to
+    /// `RestartEndpoint` carrying the token in `x-wave-admin-token` stops the
+    /// backend so the next request starts a fresh one. Unset disables the
+    /// endpoint and the path is proxied like any other.
+    #[serde(rename = "AdminTokenEnv", default)]
+    pub(crate) admin_token_env: Option<String>,
+    #[serde(rename = "RestartEndpoint", default = "default_restart_endpoint")]
+    pub(crate) restart_endpoint: String,
+    /// Fraction of requests written to the access log once their response
+    /// has finished, spread evenly: 1 logs all, 0.01 one in a hundred, 0
+    /// none. Lines go to the `proxy::access` tracing target.
+    #[serde(rename = "AccessLogSampleRate", default)]
+    pub(crate) access_log_sample_rate: f64,
+    /// Placeholders: `{method}`, `{path}`, `{status}`, `{request_id}`,
+    /// `{ttfb_ms}` (until the response headers were ready), `{duration_ms}`
+    /// (until the body finished) and `{upstream_ms}` (backend time to
+    /// headers, `-` when the backend was not asked).
+    #[serde(rename = "AccessLogFormat", default = "default_access_log_format")]
+    pub(crate) access_log_format: String,
+    /// Add `Server-Timing: cold-start;dur=<ms>` to the first response after
+    /// the backend was (re)started, with how long startup took.
+    #[serde(rename = "ServerTiming", default)]
+    pub(crate) server_timing: bool,
+}
+
+impl Default for ObservabilityConfig {
+    fn default() -> Self {
+        Self {
+            request_id_header: default_request_id_header(),
+            metrics: false,
+            metrics_endpoint: default_metrics_endpoint(),
+            health_endpoint: default_health_endpoint(),
+            admin_token_env: None,
+            restart_endpoint: default_restart_endpoint(),
+            server_timing: false,
+            access_log_sample_rate: 0.0,
+            access_log_format: default_access_log_format(),
+        }
+    }
+}
+
+/// Session affinity for `Process.Instances > 1`. Requests carrying the
+/// sticky header (checked first) or cookie are hashed onto a fixed instance;
+/// requests without either are spread round-robin.
+#[derive(Deserialize, Default)]
+pub(crate) struct LoadBalancingConfig {
+    #[serde(rename = "StickyHeader", default)]
+    pub(crate) sticky_header: Option<String>,
+    #[serde(rename = "StickyCookie", default)]
+    pub(crate) sticky_cookie: Option<String>,
+}
+
+impl LoadBalancingConfig {
+    /// The affinity key of a request, if it has one.
+    pub(crate) fn session_key<'a>(&self, headers: &'a hyper::HeaderMap) -> Option<&'a str> {
+        if let Some(name) = &self.sticky_header
+            && let Some(value) = headers.get(name).and_then(|v| v.to_str().ok())
+            && !value.is_empty()
+        {
+            return Some(value);
+        }
+        let name = self.sticky_cookie.as_deref()?;
+        headers
+            .get_all(hyper::header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(key, value)| *key == name && !value.is_empty())
+            .map(|(_, value)| value)
+    }
+}
+
+/// In-memory LRU cache of GET responses the backend marks cacheable with
+/// `Cache-Control: max-age` (or `s-maxage`). Hits are answered without
+/// waking the backend, HEAD included, and each variant named by `Vary` is
+/// kept separately. Entries are keyed by the inbound `Host` and the target
+/// as rewritten for the backend, so parameters `StripQueryParams` removes
+/// share an entry. `no-store`, `no-cache`, `private`, `Set-Cookie` and
+/// requests with `Authorization` are never cached.
+#[derive(Deserialize)]
+pub(crate) struct CacheConfig {
+    #[serde(rename = "MaxEntries", default = "default_cache_max_entries")]
+    pub(crate) max_entries: usize,
+    /// Larger responses are passed through without being stored.
+    #[serde(rename = "MaxEntryBytes", default = "default_cache_max_entry_bytes")]
+    pub(crate) max_entry_bytes: usize,
+}
+
+fn default_cache_max_entries() -> usize {
+    DEFAULT_CACHE_MAX_ENTRIES
+}
+
+fn default_cache_max_entry_bytes() -> usize {
+    DEFAULT_CACHE_MAX_ENTRY_BYTES
+}
+
+/// CORS answered by the proxy: preflights never reach the backend, and
+/// allowed cross-origin responses get `Access-Control-*` headers, replacing
+/// any the backend set.
+#[derive(Deserialize)]
+pub(crate) struct CorsConfig {
+    /// Exact origins such as `https://app.example.com`, or `*` for any.
+    #[serde(rename = "AllowedOrigins", default)]
+    pub(crate) allowed_origins: Vec<String>,
+    /// Allow every origin by echoing it back, which unlike `*` also works
+    /// with credentials.
+    #[serde(rename = "ReflectOrigin", default)]
+    pub(crate) reflect_origin: bool,
+    #[serde(rename = "AllowedMethods", default = "default_cors_methods")]
+    pub(crate) allowed_methods: Vec<String>,
+    /// Request headers a preflight may ask for. Empty allows whatever the
+    /// preflight lists.
+    #[serde(rename = "AllowedHeaders", default)]
+    pub(crate) allowed_headers: Vec<String>,
+    #[serde(rename = "ExposedHeaders", default)]
+    pub(crate) exposed_headers: Vec<String>,
+    #[serde(rename = "AllowCredentials", default)]
+    pub(crate) allow_credentials: bool,
+    /// How long browsers may cache a preflight answer.
+    #[serde(rename = "MaxAgeSecs", default)]
+    pub(crate) max_age_secs: Option<u64>,
+}
+
+impl CorsConfig {
+    /// The `Access-Control-Allow-Origin` value for `origin`, if allowed.
+    pub(crate) fn allow_origin(
+        &self,
+        origin: &hyper::header::HeaderValue,
+    ) -> Option<hyper::header::HeaderValue> {
+        if self.reflect_origin {
+            return Some(origin.clone());
+        }
+        let text = origin.to_str().ok()?;
+        if self.allowed_origins.iter().any(|o| o == "*") {
+            return Some(hyper::header::HeaderValue::from_static("*"));
+        }
+        self.allowed_origins
+            .iter()
+            .any(|o| o.eq_ignore_ascii_case(text))
+            .then(|| origin.clone())
+    }
+
+    /// Whether the allowed origin depends on the request's `Origin`.
+    pub(crate) fn varies(&self) -> bool {
+        self.reflect_origin || !self.allowed_origins.iter().any(|o| o == "*")
+    }
+
+    /// Headers shared by preflight and actual responses.
+    pub(crate) fn apply(
+        &self,
+        headers: &mut hyper::HeaderMap,
+        allow_origin: hyper::header::HeaderValue,
+    ) {
+        use hyper::header::{
+            ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue,
+        };
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        if self.allow_credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        if self.varies() {
+            headers.append(hyper::header::VARY, HeaderValue::from_static("origin"));
+        }
+    }
+
+    /// Answers a preflight. A disallowed origin, method or header gets a
+    /// bare 204 without CORS headers, which the browser treats as a refusal.
+    pub(crate) fn preflight(
+        &self,
+        headers: &hyper::HeaderMap,
+    ) -> Result<Response<ResponseBody>, Error> {
+        use hyper::header::{
+            ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_MAX_AGE,
+            ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, HeaderValue, ORIGIN,
+        };
+        let mut response = Response::builder().status(204);
+        let method = headers
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let requested: Vec<&str> = headers
+            .get_all(ACCESS_CONTROL_REQUEST_HEADERS)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+            .collect();
+        let method_allowed = self.allowed_methods.iter().any(|m| m == method);
+        let headers_allowed = self.allowed_headers.is_empty()
+            || requested.iter().all(|h| {
+                self.allowed_headers
+                    .iter()
+                    .any(|a| a.eq_ignore_ascii_case(h))
+            });
+        if let Some(origin) = headers.get(ORIGIN).and_then(|o| self.allow_origin(o))
+            && method_allowed
+            && headers_allowed
+            && let Some(out) = response.headers_mut()
+        {
+            self.apply(out, origin);
+            out.insert(
+                ACCESS_CONTROL_ALLOW_METHODS,
+                HeaderValue::from_str(&self.allowed_methods.join(", "))?,
+            );
+            if !requested.is_empty() {
+                out.insert(
+                    ACCESS_CONTROL_ALLOW_HEADERS,
+                    HeaderValue::from_str(&requested.join(", "))?,
+                );
+            }
+            if let Some(secs) = self.max_age_secs {
+                out.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(secs));
+            }
+        } else if self.varies()
+            && let Some(out) = response.headers_mut()
+        {
+            out.append(hyper::header::VARY, HeaderValue::from_static("origin"));
+        }
+        Ok(response.body(ResponseBody::from(Bytes::new()))?)
+    }
+
+    /// Adds CORS headers to a proxied response for a cross-origin request.
+    pub(crate) fn decorate(
+        &self,
+        headers: &mut hyper::HeaderMap,
+        origin: Option<&hyper::header::HeaderValue>,
+    ) -> Result<(), Error> {
+        match origin.and_then(|o| self.allow_origin(o)) {
+            Some(allow_origin) => {
+                self.apply(headers, allow_origin);
+                if !self.exposed_headers.is_empty() {
+                    headers.insert(
+                        hyper::header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                        hyper::header::HeaderValue::from_str(&self.exposed_headers.join(", "))?,
+                    );
+                }
+            }
+            None if self.varies() => {
+                headers.append(
+                    hyper::header::VARY,
+                    hyper::header::HeaderValue::from_static("origin"),
+                );
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+fn default_cors_methods() -> Vec<String> {
+    DEFAULT_CORS_METHODS.iter().map(|m| m.to_string()).collect()
+}
+
+fn default_access_log_format() -> String {
+    DEFAULT_ACCESS_LOG_FORMAT.into()
+}
+
+fn default_request_id_header() -> String {
+    DEFAULT_REQUEST_ID_HEADER.into()
+}
+
+fn default_metrics_endpoint() -> String {
+    DEFAULT_METRICS_ENDPOINT.into()
+}
+
+fn default_health_endpoint() -> String {
+    DEFAULT_HEALTH_ENDPOINT.into()
+}
+
+fn default_restart_endpoint() -> String {
+    DEFAULT_RESTART_ENDPOINT.into()
+}
+
+/// `WAVE_CONFIG_PATH` wins over the default location. A relative path that
+/// doesn't exist from the working directory is retried next to the binary,
+/// since Vercel's output layout doesn't always match the local one.
+pub(crate) fn config_path() -> PathBuf {
+    let path = std::env::var_os(CONFIG_PATH_ENV)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(CONFIG_PATH));
+    if path.is_relative() && !path.exists() {
+        let beside_binary = std::env::current_exe()
+            .ok()
+            .and_then(|exe| Some(exe.parent()?.join(&path)));
+        if let Some(candidate) = beside_binary.filter(|p| p.exists()) {
+            return candidate;
+        }
+    }
+    path
+}
+
+/// Why a config could not be loaded.
+#[derive(Debug)]
+pub(crate) enum ConfigError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// Not JSON, or not shaped like `Config`; `field` is the JSON path.
+    Parse {
+        path: PathBuf,
+        field: String,
+        message: String,
+    },
+    /// Parsed but failed `validate_config`, one message per field.
+    Invalid {
+        path: PathBuf,
+        problems: Vec<String>,
+    },
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => write!(
+                f,
+                "could not read wave config at {}: {source}\n  (the proxy expects to run from the site root)",
+                path.display()
+            ),
+            ConfigError::Parse {
+                path,
+                field,
+                message,
+            } => write!(
+                f,
+                "could not parse wave config at {}:\n  - {field}: {message}",
+                path.display()
+            ),
+            ConfigError::Invalid { path, problems } => write!(
+                f,
+                "invalid wave config at {}:\n  - {}",
+                path.display(),
+                problems.join("\n  - ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl Config {
+    /// Reads, parses and validates the config at `path`, noting which
+    /// environment variables override it.
+    ///
+    /// With `WAVE_ENV=name`, `wave.config.name.json` next to it (if present)
+    /// is merged over it first: objects merge key by key, at any depth, and
+    /// any other value the overlay sets (arrays and `null` included)
+    /// replaces the base's. Validation sees only the merged result, so an
+    /// overlay can be as small as one field.
+    ///
+    /// A missing file loads [`FALLBACK_CONFIG`] instead, with a warning, so a
+    /// first run needs nothing but a `dist` binary; `WAVE_STRICT_CONFIG=1`
+    /// makes it an error again. A file that exists but doesn't parse or
+    /// validate is always an error.
+    pub(crate) fn load(path: &Path) -> Result<Config, ConfigError> {
+        Self::load_overlaid(path, std::env::var(ENV_OVERLAY_ENV).ok().as_deref())
+    }
+
+    /// `load` with `env` in place of `WAVE_ENV`.
+    fn load_overlaid(path: &Path, env: Option<&str>) -> Result<Config, ConfigError> {
+        let mut raw = match read_json(path) {
+            Err(ConfigError::Io { source, .. })
+                if source.kind() == std::io::ErrorKind::NotFound && !strict_config() =>
+            {
+                tracing::warn!(
+                    path = %path.display(),
+                    "wave config not found, using built-in defaults (set {STRICT_CONFIG_ENV}=1 to refuse)"
+                );
+                serde_json::from_str(FALLBACK_CONFIG).expect("fallback config is valid JSON")
+            }
+            other => other?,
+        };
+        let overlay = env.and_then(|env| overlay_path(path, env)).filter(|overlay| {
+            let exists = overlay.exists();
+            if !exists {
+                tracing::warn!(path = %overlay.display(), "{ENV_OVERLAY_ENV} is set but its config overlay does not exist");
+            }
+            exists
+        });
+        if let Some(overlay) = &overlay {
+            tracing::info!(path = %overlay.display(), "merging wave config overlay");
+            merge_json(&mut raw, read_json(overlay)?);
+        }
+        let mut cfg: Config =
+            serde_path_to_error::deserialize(&raw).map_err(|e| ConfigError::Parse {
+                path: path.to_path_buf(),
+                field: e.path().to_string(),
+                message: e.into_inner().to_string(),
+            })?;
+        cfg.raw = raw;
+        cfg.overlay = overlay;
+        cfg.watch.normalize();
+        validate_config(&cfg).map_err(|problems| ConfigError::Invalid {
+            path: path.to_path_buf(),
+            problems,
+        })?;
+        for var in [
+            CONFIG_PATH_ENV,
+            ENV_OVERLAY_ENV,
+            HEALTH_TIMEOUT_ENV,
+            NO_SPAWN_ENV,
+            STRICT_CONFIG_ENV,
+        ] {
+            if std::env::var_os(var).is_some() {
+                cfg.env_overrides.push(var);
+            }
+        }
+        cfg.source = path.to_path_buf();
+        Ok(cfg)
+    }
+}
+
+/// What a missing config file stands in for: the backend is `dist/main`,
+/// healthy once `/healthz` answers, on `Core.Port`'s default of 8080.
+const FALLBACK_CONFIG: &str =
+    r#"{"Core":{"DistDir":"dist"},"Watch":{"HealthcheckEndpoint":"/healthz"}}"#;
+
+fn strict_config() -> bool {
+    std::env::var(STRICT_CONFIG_ENV).is_ok_and(|v| v == "1")
+}
+
+fn read_json(path: &Path) -> Result<serde_json::Value, ConfigError> {
+    let data = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    serde_json::from_str(&data).map_err(|e| ConfigError::Parse {
+        path: path.to_path_buf(),
+        field: ".".to_string(),
+        message: e.to_string(),
+    })
+}
+
+/// `wave.config.json` becomes `wave.config.<WAVE_ENV>.json`.
+fn overlay_path(base: &Path, env: &str) -> Option<PathBuf> {
+    let env = env.trim();
+    if env.is_empty() || env.contains(['/', '\\']) {
+        return None;
+    }
+    let stem = base.file_stem()?.to_string_lossy();
+    let name = match base.extension() {
+        Some(ext) => format!("{stem}.{env}.{}", ext.to_string_lossy()),
+        None => format!("{stem}.{env}"),
+    };
+    Some(base.with_file_name(name))
+}
+
+fn merge_json(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    match (base, overlay) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_json(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Checks the values serde cannot, returning one message per offending field.
+fn validate_config(cfg: &Config) -> Result<(), Vec<String>> {
+    let mut problems = Vec::new();
+
+    if cfg.core.dist_dir.trim().is_empty() {
+        problems.push("Core.DistDir must not be empty".to_string());
+    }
+    if cfg
+        .core
+        .static_dir
+        .as_deref()
+        .is_some_and(|d| d.trim().is_empty())
+    {
+        problems.push("Core.StaticDir must not be empty when set".to_string());
+    }
+    let endpoints = [
+        ("HealthcheckEndpoint", Some(&cfg.watch.healthcheck_endpoint)),
+        ("LivenessEndpoint", cfg.watch.liveness_endpoint.as_ref()),
+        ("ReadinessEndpoint", cfg.watch.readiness_endpoint.as_ref()),
+    ];
+    for (field, endpoint) in endpoints {
+        match endpoint {
+            Some(path) if path.is_empty() => {
+                problems.push(format!("Watch.{field} must not be empty"));
+            }
+            Some(path) if hyper::Uri::from_str(path).is_ok_and(|uri| uri.scheme().is_some()) => {
+                problems.push(format!(
+                    "Watch.{field} must be a path on the backend, not a URL, got {path:?}"
+                ));
+            }
+            Some(path) if !path.starts_with('/') => {
+                problems.push(format!("Watch.{field} must start with '/', got {path:?}"));
+            }
+            Some(path) if hyper::http::uri::PathAndQuery::from_str(path).is_err() => {
+                problems.push(format!("Watch.{field} {path:?} is not a valid URI path"));
+            }
+            _ => {}
+        }
+    }
+    let (timeout, poll) = (
+        cfg.watch.healthcheck_timeout_ms,
+        cfg.watch.healthcheck_poll_ms,
+    );
+    if timeout == 0 {
+        problems.push("Watch.HealthcheckTimeoutMs must be positive".to_string());
+    }
+    if poll == 0 {
+        problems.push("Watch.HealthcheckPollMs must be positive".to_string());
+    } else if poll >= timeout {
+        problems.push(format!(
+            "Watch.HealthcheckPollMs ({poll}) must be smaller than Watch.HealthcheckTimeoutMs ({timeout})"
+        ));
+    }
+    if cfg.watch.healthcheck_poll_max_ms < poll {
+        problems.push(format!(
+            "Watch.HealthcheckPollMaxMs ({}) must not be smaller than Watch.HealthcheckPollMs ({poll})",
+            cfg.watch.healthcheck_poll_max_ms
+        ));
+    }
+    let factor = cfg.watch.healthcheck_poll_factor;
+    if !factor.is_finite() || factor < 1.0 {
+        problems.push(format!(
+            "Watch.HealthcheckPollFactor must be a number >= 1.0, got {factor}"
+        ));
+    }
+    let jitter = cfg.watch.jitter;
+    if !(0.0..1.0).contains(&jitter) {
+        problems.push(format!(
+            "Watch.Jitter must be at least 0 and below 1, got {jitter}"
+        ));
+    }
+    if let Some(expected) = &cfg.watch.healthcheck_expect_status {
+        if expected.is_empty() {
+            problems.push("Watch.HealthcheckExpectStatus must not be empty when set".to_string());
+        }
+        for status in expected {
+            if !(100..=599).contains(status) {
+                problems.push(format!(
+                    "Watch.HealthcheckExpectStatus contains invalid status {status}"
+                ));
+            }
+        }
+    }
+    if cfg
+        .core
+        .backend_socket
+        .as_deref()
+        .is_some_and(str::is_empty)
+    {
+        problems.push("Core.BackendSocket must not be empty when set".to_string());
+    }
+    if cfg.http.upstream_request_timeout_ms == Some(0) {
+        problems.push("Http.UpstreamRequestTimeoutMs must be positive when set".to_string());
+    }
+    if cfg.http.circuit_breaker_threshold > 0 && cfg.http.circuit_breaker_cooldown_ms == 0 {
+        problems.push("Http.CircuitBreakerCooldownMs must be positive".to_string());
+    }
+    if cfg.process.command.trim().is_empty() {
+        problems.push("Process.Command must not be empty".to_string());
+    }
+    if cfg.process.capture_logs && cfg.process.capture_log_lines == 0 {
+        problems.push("Process.CaptureLogLines must be positive".to_string());
+    }
+    if let Some(name) = &cfg.load_balancing.sticky_header
+        && hyper::header::HeaderName::try_from(name).is_err()
+    {
+        problems.push(format!(
+            "LoadBalancing.StickyHeader {name:?} is not a valid header name"
+        ));
+    }
+    if cfg
+        .load_balancing
+        .sticky_cookie
+        .as_deref()
+        .is_some_and(|name| name.is_empty() || name.contains(['=', ';', ' ']))
+    {
+        problems.push("LoadBalancing.StickyCookie must be a valid cookie name".to_string());
+    }
+    if let Some([first, last]) = cfg.core.port_range {
+        if first == 0 || first > last {
+            problems.push(format!(
+                "Core.PortRange [{first}, {last}] must be two nonzero ports, lowest first"
+            ));
+        } else if usize::from(last - first) + 1 < cfg.process.instances {
+            problems.push(format!(
+                "Core.PortRange [{first}, {last}] is too small for Process.Instances {}",
+                cfg.process.instances
+            ));
+        }
+        if cfg.core.backend_socket.is_some() {
+            problems.push("Core.PortRange cannot be combined with Core.BackendSocket".to_string());
+        }
+        if cfg.process.no_spawn() {
+            problems.push(
+                "Process.NoSpawn needs a fixed Core.Port to connect to, not Core.PortRange"
+                    .to_string(),
+            );
+        }
+    }
+    if cfg.process.no_spawn() && cfg.core.port == 0 && cfg.core.backend_socket.is_none() {
+        problems.push(
+            "Process.NoSpawn needs a fixed Core.Port (or Core.BackendSocket) to connect to"
+                .to_string(),
+        );
+    }
+    if cfg.process.idle_timeout_ms == Some(0) {
+        problems.push("Process.IdleTimeoutMs must be positive when set".to_string());
+    }
+    if cfg.core.backend_socket.is_some() && cfg.process.args.iter().any(|a| a.contains("${PORT}")) {
+        problems.push(
+            "Process.Args uses ${PORT}, which has no value with Core.BackendSocket".to_string(),
+        );
+    }
+    if let Some(pattern) = &cfg.process.port_pattern {
+        if pattern.captures_len() < 2 {
+            problems.push(format!(
+                "Process.PortPattern {:?} needs a capture group for the port",
+                pattern.as_str()
+            ));
+        }
+        if cfg.core.backend_socket.is_some() {
+            problems
+                .push("Process.PortPattern cannot be combined with Core.BackendSocket".to_string());
+        }
+    }
+    if cfg
+        .watch
+        .ready_signal
+        .as_deref()
+        .is_some_and(|path| path.trim().is_empty())
+    {
+        problems.push("Watch.ReadySignal must not be empty when set".to_string());
+    }
+    if cfg
+        .process
+        .pre_start
+        .first()
+        .is_some_and(|program| program.trim().is_empty())
+    {
+        problems.push("Process.PreStart must start with a program".to_string());
+    }
+    if !cfg.process.pre_start.is_empty() && cfg.process.pre_start_timeout_ms == 0 {
+        problems.push("Process.PreStartTimeoutMs must be positive".to_string());
+    }
+    if let Some(marker) = &cfg.process.reuse_marker {
+        if marker.trim().is_empty() {
+            problems.push("Process.ReuseMarker must not be empty when set".to_string());
+        }
+        if cfg.process.instances > 1 {
+            problems.push("Process.ReuseMarker needs Process.Instances = 1".to_string());
+        }
+        if cfg.process.no_spawn() {
+            problems
+                .push("Process.ReuseMarker has nothing to record with Process.NoSpawn".to_string());
+        }
+    }
+    if cfg.process.startup_attempts == 0 {
+        problems.push("Process.StartupAttempts must be at least 1".to_string());
+    }
+    if cfg.process.instances == 0 {
+        problems.push("Process.Instances must be at least 1".to_string());
+    } else if cfg.process.instances > 1 {
+        if cfg.watch.ready_signal.is_some() {
+            problems
+                .push("Process.Instances > 1 cannot share a single Watch.ReadySignal".to_string());
+        }
+        if cfg.core.backend_socket.is_some() {
+            problems.push(
+                "Process.Instances > 1 needs TCP ports and cannot be combined with Core.BackendSocket"
+                    .to_string(),
+            );
+        }
+        let last = usize::from(cfg.core.port) + cfg.process.instances - 1;
+        if cfg.core.port != 0 && last > usize::from(u16::MAX) {
+            problems.push(format!(
+                "Process.Instances {} starting at Core.Port {} runs past port {}",
+                cfg.process.instances,
+                cfg.core.port,
+                u16::MAX
+            ));
+        }
+    }
+    for key in cfg.process.env.keys() {
+        if key.is_empty() || key.contains(['=', '\0']) {
+            problems.push(format!("Process.Env has invalid variable name {key:?}"));
+        }
+    }
+    if let Err(e) = build_authority(cfg, cfg.core.port) {
+        problems.push(format!(
+            "Core.BackendHost {:?} does not form a valid URI: {e}",
+            cfg.core.backend_host
+        ));
+    }
+    if cfg.http.http2 && cfg.core.https {
+        problems.push(
+            "Http.Http2 is prior-knowledge h2c and cannot be combined with Core.Https".to_string(),
+        );
+    }
+    if cfg.http.max_concurrent_upstream == Some(0) {
+        problems.push("Http.MaxConcurrentUpstream must be positive when set".to_string());
+    }
+    if let Some(var) = &cfg.http.secret_env {
+        if hyper::header::HeaderName::try_from(&cfg.http.secret_header).is_err() {
+            problems.push(format!(
+                "Http.SecretHeader {:?} is not a valid header name",
+                cfg.http.secret_header
+            ));
+        }
+        // Never echo the value itself, only whether it is usable.
+        match std::env::var(var) {
+            Ok(value) if hyper::header::HeaderValue::from_str(&value).is_ok() => {}
+            Ok(_) => problems.push(format!(
+                "Http.SecretEnv: ${var} is not a valid header value"
+            )),
+            Err(_) => problems.push(format!("Http.SecretEnv: ${var} is not set")),
+        }
+    }
+    if let Some(var) = &cfg.observability.admin_token_env {
+        match std::env::var(var) {
+            Ok(token) if !token.is_empty() => {}
+            _ => problems.push(format!(
+                "Observability.AdminTokenEnv: ${var} is not set or empty"
+            )),
+        }
+        if !cfg.observability.restart_endpoint.starts_with('/') {
+            problems.push(format!(
+                "Observability.RestartEndpoint must start with '/', got {:?}",
+                cfg.observability.restart_endpoint
+            ));
+        }
+    }
+    let methods = std::iter::once(("Http.AllowedMethods".to_string(), &cfg.http.allowed_methods))
+        .chain(cfg.http.routes.iter().enumerate().filter_map(|(i, route)| {
+            Some((
+                format!("Http.Routes[{i}].AllowedMethods"),
+                route.allowed_methods.as_ref()?,
+            ))
+        }));
+    for (field, methods) in methods {
+        for method in methods {
+            if hyper::Method::from_bytes(method.as_bytes()).is_err() {
+                problems.push(format!("{field} entry {method:?} is not a valid method"));
+            }
+        }
+    }
+    for (i, route) in cfg.http.routes.iter().enumerate() {
+        if !route.prefix.starts_with('/') {
+            problems.push(format!(
+                "Http.Routes[{i}].Prefix must start with '/', got {:?}",
+                route.prefix
+            ));
+        }
+        if route.buffer_body == Some(false) && route.upstream_retries.is_some_and(|n| n > 0) {
+            problems.push(format!(
+                "Http.Routes[{i}] cannot retry with BufferBody false; a streamed body cannot be replayed"
+            ));
+        }
+    }
+    for (field, prefix) in [
+        ("StripPathPrefix", &cfg.http.strip_path_prefix),
+        ("AddPathPrefix", &cfg.http.add_path_prefix),
+    ] {
+        if let Some(prefix) = prefix
+            && !prefix.starts_with('/')
+        {
+            problems.push(format!("Http.{field} must start with '/', got {prefix:?}"));
+        }
+    }
+    for (field, headers) in [
+        ("RequestHeaders", &cfg.http.request_headers),
+        ("ResponseHeaders", &cfg.http.response_headers),
+    ] {
+        for (name, value) in headers {
+            if hyper::header::HeaderName::try_from(name).is_err() {
+                problems.push(format!("Http.{field} has invalid header name {name:?}"));
+            }
+            if hyper::header::HeaderValue::from_str(value).is_err() {
+                problems.push(format!(
+                    "Http.{field}.{name} has invalid header value {value:?}"
+                ));
+            }
+        }
+    }
+    for (field, value) in [
+        ("LivenessIntervalMs", cfg.watch.liveness_interval_ms),
+        ("LivenessTimeoutMs", cfg.watch.liveness_timeout_ms),
+        (
+            "LivenessFailureThreshold",
+            cfg.watch.liveness_failure_threshold.into(),
+        ),
+    ] {
+        if value == 0 {
+            problems.push(format!("Watch.{field} must be at least 1"));
+        }
+    }
+    if let Some(dir) = &cfg.process.working_dir
+        && !Path::new(dir).is_dir()
+    {
+        problems.push(format!(
+            "Process.WorkingDir {dir:?} is not an existing directory"
+        ));
+    }
+    let rate = cfg.observability.access_log_sample_rate;
+    if !(0.0..=1.0).contains(&rate) {
+        problems.push(format!(
+            "Observability.AccessLogSampleRate must be between 0 and 1, got {rate}"
+        ));
+    }
+    let mut rest = cfg.observability.access_log_format.as_str();
+    while let Some((_, after)) = rest.split_once('{') {
+        let Some((name, tail)) = after.split_once('}') else {
+            problems.push("Observability.AccessLogFormat has an unclosed '{'".to_string());
+            break;
+        };
+        if !ACCESS_LOG_FIELDS.contains(&name) {
+            problems.push(format!(
+                "Observability.AccessLogFormat has unknown placeholder {{{name}}}"
+            ));
+        }
+        rest = tail;
+    }
+    if let Some(cache) = &cfg.cache {
+        if cache.max_entries == 0 {
+            problems.push("Cache.MaxEntries must be at least 1".to_string());
+        }
+        if cache.max_entry_bytes == 0 {
+            problems.push("Cache.MaxEntryBytes must be positive".to_string());
+        }
+    }
+    if let Some(cors) = &cfg.cors {
+        for method in &cors.allowed_methods {
+            if hyper::Method::from_str(method).is_err() {
+                problems.push(format!("Cors.AllowedMethods has invalid method {method:?}"));
+            }
+        }
+        for (field, names) in [
+            ("AllowedHeaders", &cors.allowed_headers),
+            ("ExposedHeaders", &cors.exposed_headers),
+        ] {
+            for name in names {
+                if hyper::header::HeaderName::try_from(name).is_err() {
+                    problems.push(format!("Cors.{field} has invalid header name {name:?}"));
+                }
+            }
+        }
+        for origin in &cors.allowed_origins {
+            if hyper::header::HeaderValue::from_str(origin).is_err() {
+                problems.push(format!("Cors.AllowedOrigins has invalid origin {origin:?}"));
+            }
+        }
+        if cors.allow_credentials
+            && !cors.reflect_origin
+            && cors.allowed_origins.iter().any(|o| o == "*")
+        {
+            problems.push(
+                "Cors.AllowedOrigins \"*\" cannot be used with AllowCredentials; set ReflectOrigin instead"
+                    .to_string(),
+            );
+        }
+    }
+    let host = cfg.http.upstream_host.as_str();
+    if host != UPSTREAM_HOST_REWRITE
+        && host != UPSTREAM_HOST_PRESERVE
+        && hyper::http::uri::Authority::from_str(host).is_err()
+    {
+        problems.push(format!(
+            "Http.UpstreamHost must be \"rewrite\", \"preserve\" or a host[:port], got {host:?}"
+        ));
+    }
+    let style = cfg.http.forwarded_style.as_str();
+    if ![
+        FORWARDED_STYLE_X,
+        FORWARDED_STYLE_RFC7239,
+        FORWARDED_STYLE_BOTH,
+    ]
+    .contains(&style)
+    {
+        problems.push(format!(
+            "Http.ForwardedStyle must be \"x-forwarded\", \"rfc7239\" or \"both\", got {style:?}"
+        ));
+    }
+    for pattern in &cfg.http.allowed_content_types {
+        let valid = match pattern.split_once('/') {
+            Some((kind, sub)) => !kind.is_empty() && !sub.is_empty() && (kind != "*" || sub == "*"),
+            None => false,
+        };
+        if !valid || pattern.contains(';') {
+            problems.push(format!(
+                "Http.AllowedContentTypes entry {pattern:?} must look like type/subtype or type/*"
+            ));
+        }
+    }
+    if cfg.http.max_response_bytes == Some(0) {
+        problems.push("Http.MaxResponseBytes must be positive when set".to_string());
+    }
+    for pattern in &cfg.http.strip_query_params {
+        if pattern.is_empty() || pattern.trim_end_matches('*').contains('*') {
+            problems.push(format!(
+                "Http.StripQueryParams entry {pattern:?} must be a name, optionally ending in '*'"
+            ));
+        }
+    }
+    for pattern in &cfg.http.not_found_paths {
+        if !pattern.starts_with('/') || pattern.trim_end_matches('*').contains('*') {
+            problems.push(format!(
+                "Http.NotFoundPaths entry {pattern:?} must start with '/' and may only end in '*'"
+            ));
+        }
+    }
+    if !cfg.http.not_found_paths.is_empty() {
+        if hyper::StatusCode::from_u16(cfg.http.not_found_status).is_err() {
+            problems.push(format!(
+                "Http.NotFoundStatus {} is not a valid status code",
+                cfg.http.not_found_status
+            ));
+        }
+        if hyper::header::HeaderValue::from_str(&cfg.http.not_found_content_type).is_err() {
+            problems.push(format!(
+                "Http.NotFoundContentType {:?} is not a valid header value",
+                cfg.http.not_found_content_type
+            ));
+        }
+    }
+    for (i, rewrite) in cfg.http.body_rewrites.iter().enumerate() {
+        if rewrite.content_types.is_empty() {
+            problems.push(format!(
+                "Http.BodyRewrites[{i}].ContentTypes must not be empty"
+            ));
+        }
+    }
+    if let Some(threshold) = cfg.http.buffer_threshold_bytes
+        && threshold > cfg.http.max_body_bytes
+    {
+        problems.push(format!(
+            "Http.BufferThresholdBytes ({threshold}) must not exceed Http.MaxBodyBytes ({})",
+            cfg.http.max_body_bytes
+        ));
+    }
+    if cfg.http.max_request_headers == 0 {
+        problems.push("Http.MaxRequestHeaders must be at least 1".to_string());
+    }
+    if cfg.http.max_request_header_bytes == 0 {
+        problems.push("Http.MaxRequestHeaderBytes must be at least 1".to_string());
+    }
+    if cfg.http.body_rewrite_max_bytes == 0 {
+        problems.push("Http.BodyRewriteMaxBytes must be at least 1".to_string());
+    }
+    for (i, rewrite) in cfg.http.header_rewrites.iter().enumerate() {
+        if hyper::header::HeaderName::try_from(&rewrite.header).is_err() {
+            problems.push(format!(
+                "Http.HeaderRewrites[{i}].Header {:?} is not a valid header name",
+                rewrite.header
+            ));
+        }
+    }
+    if hyper::header::HeaderName::try_from(&cfg.observability.request_id_header).is_err() {
+        problems.push(format!(
+            "Observability.RequestIdHeader {:?} is not a valid header name",
+            cfg.observability.request_id_header
+        ));
+    }
+    if !cfg.observability.health_endpoint.starts_with('/') {
+        problems.push(format!(
+            "Observability.HealthEndpoint must start with '/', got {:?}",
+            cfg.observability.health_endpoint
+        ));
+    }
+    if cfg.observability.metrics && !cfg.observability.metrics_endpoint.starts_with('/') {
+        problems.push(format!(
+            "Observability.MetricsEndpoint must start with '/', got {:?}",
+            cfg.observability.metrics_endpoint
+        ));
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// A config from `raw`, with `Core` and `Watch` defaulted when absent.
+    pub(crate) fn test_config(mut raw: serde_json::Value) -> Config {
+        let sections = raw.as_object_mut().expect("config is an object");
+        sections
+            .entry("Core")
+            .or_insert_with(|| json!({ "DistDir": "dist" }));
+        sections
+            .entry("Watch")
+            .or_insert_with(|| json!({ "HealthcheckEndpoint": "/healthz" }));
+        let mut cfg: Config = serde_json::from_value(raw).expect("test config parses");
+        cfg.watch.normalize();
+        cfg
+    }
+
+    /// What `validate_config` objects to in `raw`.
+    fn problems(raw: serde_json::Value) -> Vec<String> {
+        validate_config(&test_config(raw)).err().unwrap_or_default()
+    }
+
+    #[test]
+    fn backend_host_must_form_a_uri() {
+        assert_eq!(
+            problems(json!({ "Core": { "DistDir": "dist", "BackendHost": "bad host" } })),
+            ["Core.BackendHost \"bad host\" does not form a valid URI: invalid uri character"]
+        );
+        for host in ["0.0.0.0", "::1", "[::1]", "backend.internal"] {
+            assert_eq!(
+                problems(json!({ "Core": { "DistDir": "dist", "BackendHost": host } })),
+                Vec::<String>::new(),
+                "{host}"
+            );
+        }
+    }
+
+    #[test]
+    fn env_values_interpolate_the_proxy_environment() {
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(interpolate_env("${PATH}"), path);
+        assert_eq!(interpolate_env("a-${PATH}-b"), format!("a-{path}-b"));
+        assert_eq!(interpolate_env("${WAVE_TEST_UNSET_VAR}x"), "x");
+        assert_eq!(interpolate_env("${PATH"), "${PATH");
+        assert_eq!(interpolate_env("$PATH"), "$PATH");
+    }
+
+    #[test]
+    fn env_names_are_validated() {
+        assert_eq!(
+            problems(json!({ "Process": { "Env": { "BAD=NAME": "x", "GOOD_NAME": "y" } } })),
+            ["Process.Env has invalid variable name \"BAD=NAME\""]
+        );
+    }
+
+    #[test]
+    fn health_timeout_prefers_the_env_then_the_field() {
+        let default = test_config(json!({})).watch;
+        assert_eq!(
+            default.healthcheck_timeout_with(None),
+            Duration::from_millis(DEFAULT_HEALTHCHECK_TIMEOUT_MS)
+        );
+        let watch = test_config(json!({
+            "Watch": { "HealthcheckEndpoint": "/healthz", "HealthcheckTimeoutMs": 2500 },
+        }))
+        .watch;
+        assert_eq!(
+            watch.healthcheck_timeout_with(None),
+            Duration::from_millis(2500)
+        );
+        assert_eq!(
+            watch.healthcheck_timeout_with(Some(" 40000 ")),
+            Duration::from_secs(40)
+        );
+        assert_eq!(
+            default.healthcheck_timeout_with(Some("40000")),
+            Duration::from_secs(40)
+        );
+        // An unusable value is ignored rather than disabling the wait.
+        for invalid in ["0", "-1", "10s", ""] {
+            assert_eq!(
+                watch.healthcheck_timeout_with(Some(invalid)),
+                Duration::from_millis(2500),
+                "{invalid:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn health_endpoints_must_be_backend_paths() {
+        let watch = |endpoint: &str| json!({ "Watch": { "HealthcheckEndpoint": endpoint } });
+        assert_eq!(
+            problems(watch("healthz")),
+            ["Watch.HealthcheckEndpoint must start with '/', got \"healthz\""]
+        );
+        assert_eq!(
+            problems(watch("http://x/healthz")),
+            [
+                "Watch.HealthcheckEndpoint must be a path on the backend, not a URL, got \"http://x/healthz\""
+            ]
+        );
+        assert_eq!(
+            problems(watch("  ")),
+            ["Watch.HealthcheckEndpoint must not be empty"]
+        );
+        assert!(problems(watch("/healthz")).is_empty());
+        // Surrounding whitespace is trimmed before the check.
+        let cfg = test_config(watch(" /healthz\n"));
+        assert_eq!(cfg.watch.healthcheck_endpoint, "/healthz");
+        assert!(validate_config(&cfg).is_ok());
+    }
+
+    #[test]
+    fn routes_resolve_by_longest_prefix() {
+        let http = test_config(json!({
+            "Http": {
+                "UpstreamRequestTimeoutMs": 5000,
+                "UpstreamRetries": 2,
+                "Routes": [
+                    { "Prefix": "/api", "UpstreamRequestTimeoutMs": 1000, "UpstreamRetries": 1 },
+                    { "Prefix": "/api/upload/", "UpstreamRequestTimeoutMs": 0, "BufferBody": false },
+                    { "Prefix": "/api/ping", "UpstreamRequestTimeoutMs": 50 },
+                ],
+            },
+        }))
+        .http;
+        let settings = |path| {
+            let s = http.route_settings(path, &hyper::HeaderMap::new());
+            (s.timeout.map(|t| t.as_millis()), s.retries, s.buffer_body)
+        };
+        assert_eq!(settings("/api/users"), (Some(1000), 1, true));
+        assert_eq!(settings("/api"), (Some(1000), 1, true));
+        // The upload route lifts the timeout and streams, so it cannot retry.
+        assert_eq!(settings("/api/upload"), (None, 0, false));
+        assert_eq!(settings("/api/upload/big"), (None, 0, false));
+        // Fields the route leaves out come from `Http`, not the shorter route.
+        assert_eq!(settings("/api/ping"), (Some(50), 2, true));
+        // Prefixes match whole segments.
+        assert_eq!(settings("/api/uploads"), (Some(1000), 1, true));
+        assert_eq!(settings("/apiary"), (Some(5000), 2, true));
+        assert_eq!(settings("/"), (Some(5000), 2, true));
+    }
+
+    fn body_headers(content_type: Option<&str>) -> hyper::HeaderMap {
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert(hyper::header::CONTENT_LENGTH, "2".parse().unwrap());
+        if let Some(content_type) = content_type {
+            headers.insert(hyper::header::CONTENT_TYPE, content_type.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn content_types_are_checked_against_the_allowlist() {
+        let http = test_config(json!({
+            "Http": { "AllowedContentTypes": ["application/json", "Image/*"] },
+        }))
+        .http;
+        let refusal = |content_type| http.content_type_refusal(&body_headers(content_type));
+        assert_eq!(refusal(Some("application/json")), None);
+        assert_eq!(refusal(Some("Application/JSON; charset=utf-8")), None);
+        assert_eq!(refusal(Some("image/png")), None);
+        assert_eq!(
+            refusal(Some("text/plain")),
+            Some("content-type \"text/plain\" is not allowed".into())
+        );
+        assert_eq!(
+            refusal(Some("application/json-patch+json")),
+            Some("content-type \"application/json-patch+json\" is not allowed".into())
+        );
+        assert_eq!(
+            refusal(None),
+            Some("request body has no content-type".into())
+        );
+        // Only bodies are checked.
+        assert_eq!(http.content_type_refusal(&hyper::HeaderMap::new()), None);
+
+        let any = test_config(json!({ "Http": { "AllowedContentTypes": ["*/*"] } })).http;
+        assert_eq!(
+            any.content_type_refusal(&body_headers(Some("text/plain"))),
+            None
+        );
+        let unset = test_config(json!({})).http;
+        assert_eq!(unset.content_type_refusal(&body_headers(None)), None);
+    }
+
+    #[test]
+    fn upstream_host_is_a_mode_or_an_authority() {
+        for host in ["rewrite", "preserve", "api.internal", "api.internal:9000"] {
+            assert!(
+                problems(json!({ "Http": { "UpstreamHost": host } })).is_empty(),
+                "{host}"
+            );
+        }
+        assert_eq!(
+            problems(json!({ "Http": { "UpstreamHost": "https://api.internal/" } })),
+            [
+                "Http.UpstreamHost must be \"rewrite\", \"preserve\" or a host[:port], got \"https://api.internal/\""
+            ]
+        );
+    }
+
+    #[test]
+    fn cors_origins_are_matched_starred_or_reflected() {
+        let origin = hyper::header::HeaderValue::from_static("https://App.example");
+        let cors = |fields: serde_json::Value| test_config(json!({ "Cors": fields })).cors.unwrap();
+
+        let listed = cors(json!({ "AllowedOrigins": ["https://app.example"] }));
+        assert_eq!(listed.allow_origin(&origin).unwrap(), origin);
+        assert!(
+            listed
+                .allow_origin(&"https://other.example".parse().unwrap())
+                .is_none()
+        );
+        assert!(listed.varies());
+        let any = cors(json!({ "AllowedOrigins": ["*"] }));
+        assert_eq!(any.allow_origin(&origin).unwrap(), "*");
+        assert!(!any.varies());
+        let reflected = cors(json!({ "ReflectOrigin": true, "AllowCredentials": true }));
+        assert_eq!(reflected.allow_origin(&origin).unwrap(), origin);
+
+        assert_eq!(
+            problems(json!({ "Cors": { "AllowedOrigins": ["*"], "AllowCredentials": true } })),
+            [
+                "Cors.AllowedOrigins \"*\" cannot be used with AllowCredentials; set ReflectOrigin instead"
+            ]
+        );
+    }
+
+    #[test]
+    fn working_dir_must_exist() {
+        let dir = std::env::temp_dir();
+        assert!(problems(json!({ "Process": { "WorkingDir": dir } })).is_empty());
+        assert_eq!(
+            problems(json!({ "Process": { "WorkingDir": "/no/such/dir" } })),
+            ["Process.WorkingDir \"/no/such/dir\" is not an existing directory"]
+        );
+    }
+
+    #[test]
+    fn header_limits_count_and_measure() {
+        let http = test_config(json!({
+            "Http": { "MaxRequestHeaders": 3, "MaxRequestHeaderBytes": 64 },
+        }))
+        .http;
+        let mut headers = hyper::HeaderMap::new();
+        for name in ["a", "b", "c"] {
+            headers.append(name, "1".parse().unwrap());
+        }
+        assert_eq!(http.header_limit_refusal(&headers), None);
+        headers.append("a", "2".parse().unwrap());
+        assert_eq!(
+            http.header_limit_refusal(&headers),
+            Some("more than 3 request headers".into())
+        );
+
+        // 6 + 54 + 4 bytes is exactly the limit.
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("cookie", "x".repeat(54).parse().unwrap());
+        assert_eq!(http.header_limit_refusal(&headers), None);
+        headers.insert("cookie", "x".repeat(55).parse().unwrap());
+        assert_eq!(
+            http.header_limit_refusal(&headers),
+            Some("request headers exceed 64 bytes".into())
+        );
+    }
+
+    #[test]
+    fn overlays_are_named_after_the_base() {
+        let base = Path::new("backend/wave.config.json");
+        assert_eq!(
+            overlay_path(base, " production ").unwrap(),
+            Path::new("backend/wave.config.production.json")
+        );
+        assert_eq!(
+            overlay_path(Path::new("wave"), "ci").unwrap(),
+            Path::new("wave.ci")
+        );
+        for env in ["", "../prod", "a\\b"] {
+            assert_eq!(overlay_path(base, env), None, "{env:?}");
+        }
+    }
+
+    #[test]
+    fn overlays_merge_field_by_field() {
+        let mut base = json!({
+            "Watch": { "HealthcheckEndpoint": "/healthz", "HealthcheckTimeoutMs": 10000 },
+            "Http": { "AllowedMethods": ["GET", "POST"], "UpstreamRetries": 2 },
+        });
+        merge_json(
+            &mut base,
+            json!({
+                "Watch": { "HealthcheckTimeoutMs": 30000 },
+                "Http": { "AllowedMethods": ["GET"], "StripPathPrefix": null },
+                "Cors": { "ReflectOrigin": true },
+            }),
+        );
+        assert_eq!(
+            base,
+            json!({
+                "Watch": { "HealthcheckEndpoint": "/healthz", "HealthcheckTimeoutMs": 30000 },
+                "Http": { "AllowedMethods": ["GET"], "UpstreamRetries": 2, "StripPathPrefix": null },
+                "Cors": { "ReflectOrigin": true },
+            })
+        );
+    }
+
+    #[test]
+    fn a_partial_overlay_is_validated_merged() {
+        let dir = std::env::temp_dir().join(format!("wave-overlay-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("wave.config.json");
+        let base = json!({
+            "Core": { "DistDir": "dist" },
+            "Watch": { "HealthcheckEndpoint": "/healthz", "HealthcheckTimeoutMs": 10000 },
+            "Http": { "UpstreamRetries": 2 },
+        });
+        std::fs::write(&path, base.to_string()).unwrap();
+        let overlay = dir.join("wave.config.production.json");
+        std::fs::write(&overlay, r#"{"Watch":{"HealthcheckTimeoutMs":30000}}"#).unwrap();
+        std::fs::write(
+            dir.join("wave.config.broken.json"),
+            r#"{"Watch":{"HealthcheckPollMs":20000,"HealthcheckPollMaxMs":20000}}"#,
+        )
+        .unwrap();
+
+        let cfg = Config::load_overlaid(&path, Some("production")).unwrap();
+        assert_eq!(cfg.watch.healthcheck_timeout_ms, 30000);
+        assert_eq!(cfg.watch.healthcheck_endpoint, "/healthz");
+        assert_eq!(cfg.http.upstream_retries, 2);
+        assert_eq!(cfg.overlay.as_deref(), Some(overlay.as_path()));
+
+        let cfg = Config::load_overlaid(&path, Some("staging")).unwrap();
+        assert_eq!(cfg.watch.healthcheck_timeout_ms, 10000);
+        assert_eq!(cfg.overlay, None);
+
+        // Valid on its own, but not over this base.
+        let Err(ConfigError::Invalid { problems, .. }) =
+            Config::load_overlaid(&path, Some("broken"))
+        else {
+            panic!("the merged config should be invalid");
+        };
+        assert_eq!(
+            problems,
+            [
+                "Watch.HealthcheckPollMs (20000) must be smaller than Watch.HealthcheckTimeoutMs (10000)"
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn allowed_methods_must_be_methods() {
+        assert_eq!(
+            problems(json!({
+                "Http": {
+                    "AllowedMethods": ["GET", "NOT A METHOD"],
+                    "Routes": [{ "Prefix": "/a", "AllowedMethods": ["P@ST"] }],
+                },
+            })),
+            [
+                "Http.AllowedMethods entry \"NOT A METHOD\" is not a valid method",
+                "Http.Routes[0].AllowedMethods entry \"P@ST\" is not a valid method",
+            ]
+        );
+    }
+
+    #[test]
+    fn small_bodies_are_buffered_and_large_ones_streamed() {
+        let http = test_config(json!({
+            "Http": {
+                "UpstreamRetries": 2,
+                "BufferThresholdBytes": 1024,
+                "Routes": [{ "Prefix": "/upload", "BufferBody": true }],
+            },
+        }))
+        .http;
+        let settings = |path, headers: &[(&str, &str)]| {
+            let mut map = hyper::HeaderMap::new();
+            for (name, value) in headers {
+                let name = hyper::header::HeaderName::from_bytes(name.as_bytes()).unwrap();
+                map.insert(name, value.parse().unwrap());
+            }
+            let s = http.route_settings(path, &map);
+            (s.buffer_body, s.retries)
+        };
+        assert_eq!(settings("/", &[]), (true, 2));
+        assert_eq!(settings("/", &[("content-length", "100")]), (true, 2));
+        assert_eq!(settings("/", &[("content-length", "1024")]), (true, 2));
+        assert_eq!(settings("/", &[("content-length", "1025")]), (false, 0));
+        assert_eq!(
+            settings("/", &[("transfer-encoding", "chunked")]),
+            (false, 0)
+        );
+        assert_eq!(settings("/", &[("content-length", "junk")]), (false, 0));
+        // A route's own choice wins over the threshold.
+        assert_eq!(
+            settings("/upload", &[("content-length", "4096")]),
+            (true, 2)
+        );
+
+        let no_retries = test_config(json!({
+            "Http": { "UpstreamRetries": 0, "BufferThresholdBytes": 1024 },
+        }))
+        .http;
+        let small = no_retries.route_settings("/", &hyper::HeaderMap::new());
+        assert!(!small.buffer_body);
+    }
+}
@@ -1,224 +1,6519 @@
-use http_body_util::{BodyExt, Empty, StreamBody};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty, Full, Limited, StreamBody};
 use hyper::body::{Bytes, Frame, Incoming};
 use hyper_util::client::legacy::Client;
-use hyper_util::rt::TokioExecutor;
-use serde::Deserialize;
+use hyper_util::client::legacy::connect::{Connected, Connection};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use rustls::pki_types::ServerName;
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::process::{Child, Command, Stdio};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Mutex, OnceLock};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
-use tokio::sync::OnceCell;
 use tokio::time::sleep;
 use tokio_stream::StreamExt;
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
 use vercel_runtime::{Error, Request, Response, ResponseBody, run, service_fn};
 
-const TIMEOUT: Duration = Duration::from_secs(10);
-const POLL: Duration = Duration::from_millis(25);
-const PORT: u16 = 8080;
+// Kept out of `api/` proper, where every file would become a function.
+#[path = "_proxy/config.rs"]
+mod config;
 
-#[derive(Deserialize)]
-struct Config {
-    #[serde(rename = "Core")]
-    core: CoreConfig,
-    #[serde(rename = "Watch")]
-    watch: WatchConfig,
-}
+use config::{
+    BodyRewrite, CacheConfig, Config, ConfigError, DEFAULT_REQUEST_ID_HEADER, FORWARDED_STYLE_BOTH,
+    FORWARDED_STYLE_RFC7239, HeaderRewrite, RouteSettings, UPSTREAM_HOST_PRESERVE,
+    UPSTREAM_HOST_REWRITE, config_path, expand_args, interpolate_env, is_vercel_dev, media_type,
+    media_type_matches,
+};
 
-#[derive(Deserialize)]
-struct CoreConfig {
-    #[serde(rename = "DistDir")]
-    dist_dir: String,
-}
+#[cfg(unix)]
+const EXIT_POLL: Duration = Duration::from_millis(25);
+const MAX_HEALTH_BODY_BYTES: usize = 64 * 1024;
+const CHECK_ENV: &str = "WAVE_CHECK";
+const LOG_TAIL_LINES: usize = 20;
+const DRAIN_POLL: Duration = Duration::from_millis(25);
+const SUPERVISE_INTERVAL: Duration = Duration::from_millis(500);
+const HEALTH_MISCONFIG_WINDOW: Duration = Duration::from_secs(2);
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const CONFIG_RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+const EPHEMERAL_PORT_ATTEMPTS: u32 = 3;
+const CRASH_REPORT_WINDOW: Duration = Duration::from_secs(5);
+const CRASH_CHECK_POLL: Duration = Duration::from_millis(10);
+const CRASH_CHECK_POLLS: u32 = 5;
+const RESTART_WAIT_POLL: Duration = Duration::from_millis(100);
+const REUSE_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+const SOCKET_PATH_ENV: &str = "WAVE_SOCKET_PATH";
+const READY_FILE_ENV: &str = "WAVE_READY_FILE";
+const WAVE_ERROR_HEADER: &str = "wave-error";
+const ADMIN_TOKEN_HEADER: &str = "x-wave-admin-token";
+const ACCESS_LOG_FIELDS: [&str; 7] = [
+    "method",
+    "path",
+    "status",
+    "request_id",
+    "ttfb_ms",
+    "duration_ms",
+    "upstream_ms",
+];
+/// Upper bounds, in seconds, of the upstream latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
 
-#[derive(Deserialize)]
-struct WatchConfig {
-    #[serde(rename = "HealthcheckEndpoint")]
-    healthcheck_endpoint: String,
+fn is_preflight(req: &Request) -> bool {
+    req.method() == hyper::Method::OPTIONS
+        && req.headers().contains_key(hyper::header::ORIGIN)
+        && req
+            .headers()
+            .contains_key(hyper::header::ACCESS_CONTROL_REQUEST_METHOD)
 }
 
 type HttpConnector = hyper_util::client::legacy::connect::HttpConnector;
+type ProxyBody = BoxBody<Bytes, Error>;
 
-static CONFIG: OnceCell<Config> = OnceCell::const_new();
-static GO: Mutex<Option<Child>> = Mutex::new(None);
-static PROXY_CLIENT: OnceLock<Client<HttpConnector, Incoming>> = OnceLock::new();
-static HEALTH_CLIENT: OnceLock<Client<HttpConnector, Empty<Bytes>>> = OnceLock::new();
+/// Replaced wholesale on reload. Callers hold their own `Arc`, so a request
+/// sees one config from start to finish and a superseded one is freed once
+/// the last of them is done.
+static CONFIG: RwLock<Option<Arc<Config>>> = RwLock::new(None);
+static GO: Mutex<Vec<Backend>> = Mutex::new(Vec::new());
+static PROXY_CLIENT: OnceLock<Client<Connector, ProxyBody>> = OnceLock::new();
+static HEALTH_CLIENT: OnceLock<Client<Connector, Empty<Bytes>>> = OnceLock::new();
 static READY: AtomicBool = AtomicBool::new(false);
+static DRAINING: AtomicBool = AtomicBool::new(false);
+static RESTARTS: AtomicU32 = AtomicU32::new(0);
+/// When the last crash was counted, in Unix milliseconds.
+static LAST_CRASH_MS: AtomicU64 = AtomicU64::new(0);
+static STARTED: OnceLock<Instant> = OnceLock::new();
+/// Unix time in milliseconds of the latest spawn; 0 before the first.
+static LAST_SPAWN_MS: AtomicU64 = AtomicU64::new(0);
+static LAST_EXIT: Mutex<Option<BackendExit>> = Mutex::new(None);
+/// Duration in milliseconds of the latest startup not yet reported through
+/// `Server-Timing`; 0 once taken.
+static COLD_START_MS: AtomicU64 = AtomicU64::new(0);
+/// When `Process.ReuseMarker` was last written, in Unix milliseconds.
+static MARKER_WRITTEN_MS: AtomicU64 = AtomicU64::new(0);
+/// Milliseconds after `STARTED` when the latest request arrived.
+static LAST_REQUEST_MS: AtomicU64 = AtomicU64::new(0);
 static INIT_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+static METRICS: Metrics = Metrics::new();
+static BREAKER: Breaker = Breaker::new();
+static BACKEND_LOGS: Mutex<VecDeque<LogLine>> = Mutex::new(VecDeque::new());
+static CACHE: Mutex<ResponseCache> = Mutex::new(ResponseCache::new());
 
-async fn config() -> &'static Config {
-    CONFIG
-        .get_or_init(|| async {
-            let data = std::fs::read_to_string("./backend/wave.config.json")
-                .expect("failed to read wave.config.json");
-            serde_json::from_str(&data).expect("failed to parse wave.config.json")
-        })
-        .await
+/// One running backend process. `index` is its slot among
+/// `Process.Instances`; only instances that passed the health check (`ready`)
+/// are sent requests. `child` is `None` under `NoSpawn`, where the process
+/// belongs to someone else. `adopted` is the pid of a backend taken over from
+/// `Process.ReuseMarker`: not our child, but ours to stop and watch all the
+/// same. With `PortPattern`, `announced` yields the port the child printed
+/// until `announced_port` takes it.
+struct Backend {
+    index: usize,
+    port: u16,
+    child: Option<Child>,
+    adopted: Option<u32>,
+    ready: bool,
+    announced: Option<tokio::sync::oneshot::Receiver<u16>>,
 }
 
-fn proxy_client() -> &'static Client<HttpConnector, Incoming> {
-    PROXY_CLIENT.get_or_init(|| Client::builder(TokioExecutor::new()).build_http())
+impl Backend {
+    /// The backend's process, when the proxy knows it.
+    fn pid(&self) -> Option<u32> {
+        self.child.as_ref().map(Child::id).or(self.adopted)
+    }
 }
 
-fn health_client() -> &'static Client<HttpConnector, Empty<Bytes>> {
-    HEALTH_CLIENT.get_or_init(|| Client::builder(TokioExecutor::new()).build_http())
+/// How a backend process ended on its own (not one the proxy stopped).
+/// `code` is the exit code; on Windows it is kept unsigned so NTSTATUS
+/// crashes like `0xC0000005` read as such. `signal` is only set on Unix.
+#[derive(Clone)]
+struct BackendExit {
+    instance: usize,
+    code: Option<i64>,
+    signal: Option<i32>,
+    status: String,
+    at_unix_ms: u64,
 }
 
-fn kill_child() {
-    let mut guard = GO.lock().unwrap_or_else(|e| e.into_inner());
-    if let Some(mut child) = guard.take() {
-        let _ = child.kill();
-        let _ = child.wait();
+impl BackendExit {
+    fn json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "instance": self.instance,
+            "code": self.code,
+            "signal": self.signal,
+            "status": self.status,
+            "at_unix_ms": self.at_unix_ms,
+        })
     }
 }
 
-async fn ensure_ready() -> Result<(), String> {
-    if READY.load(Ordering::Acquire) {
-        return Ok(());
-    }
+fn record_exit(instance: usize, status: std::process::ExitStatus) {
+    #[cfg(unix)]
+    let signal = std::os::unix::process::ExitStatusExt::signal(&status);
+    #[cfg(not(unix))]
+    let signal = None;
+    #[cfg(windows)]
+    let code = status.code().map(|c| i64::from(c as u32));
+    #[cfg(not(windows))]
+    let code = status.code().map(i64::from);
+    let status = match code {
+        #[cfg(windows)]
+        Some(code) if code >= 0xC000_0000 => format!("exit code: {code:#X}"),
+        _ => status.to_string(),
+    };
+    *LAST_EXIT.lock().unwrap_or_else(|e| e.into_inner()) = Some(BackendExit {
+        instance,
+        code,
+        signal,
+        status,
+        at_unix_ms: unix_millis(),
+    });
+}
 
-    let _lock = INIT_LOCK.lock().await;
+fn last_exit() -> Option<BackendExit> {
+    LAST_EXIT.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
 
-    // Double-check after acquiring lock
-    if READY.load(Ordering::Acquire) {
-        return Ok(());
+/// The exit of `instance` if it happened within `CRASH_REPORT_WINDOW`, i.e.
+/// it is the likely reason a request to it just failed.
+fn recent_exit(instance: usize) -> Option<BackendExit> {
+    let window = CRASH_REPORT_WINDOW.as_millis() as u64;
+    last_exit().filter(|exit| {
+        exit.instance == instance && unix_millis().saturating_sub(exit.at_unix_ms) <= window
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+struct LogLine {
+    stream: LogStream,
+    line: String,
+}
+/// Wakes `watch_liveness` early, e.g. after an upstream request timed out.
+static LIVENESS_CHECK: tokio::sync::Notify = tokio::sync::Notify::const_new();
+
+/// Process-wide counters behind the metrics endpoint.
+struct Metrics {
+    requests: AtomicU64,
+    /// Responses by status class, indexed by `status / 100 - 1`.
+    responses: [AtomicU64; 5],
+    /// Non-cumulative per-bucket counts; the last slot is `+Inf`.
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    latency_sum_micros: AtomicU64,
+    latency_count: AtomicU64,
+    spawns: AtomicU64,
+    circuit_opens: AtomicU64,
+    in_flight: AtomicU64,
+    /// Body bytes sent to and received from the backend, including bytes
+    /// spliced through upgraded connections.
+    request_bytes: AtomicU64,
+    response_bytes: AtomicU64,
+    /// Cacheable requests answered from `Cache`, and those that were not.
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            requests: AtomicU64::new(0),
+            responses: [const { AtomicU64::new(0) }; 5],
+            latency_buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len() + 1],
+            latency_sum_micros: AtomicU64::new(0),
+            latency_count: AtomicU64::new(0),
+            spawns: AtomicU64::new(0),
+            circuit_opens: AtomicU64::new(0),
+            in_flight: AtomicU64::new(0),
+            request_bytes: AtomicU64::new(0),
+            response_bytes: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+        }
     }
 
-    kill_child();
+    /// Spawns beyond the first start of every instance.
+    fn restarts(&self) -> u64 {
+        let instances = CONFIG
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .map_or(1, |cfg| cfg.process.instances);
+        self.spawns
+            .load(Ordering::Relaxed)
+            .saturating_sub(instances as u64)
+    }
 
-    let cfg = config().await;
-    let go_path = format!("./{}/main", cfg.core.dist_dir);
-    let health = &cfg.watch.healthcheck_endpoint;
-    let start = Instant::now();
+    fn record_response(&self, status: u16) {
+        if let Some(class) = self
+            .responses
+            .get(usize::from(status / 100).wrapping_sub(1))
+        {
+            class.fetch_add(1, Ordering::Relaxed);
+        }
+    }
 
-    if std::fs::metadata(&go_path).is_err() {
-        return Err(format!("go binary not found at {go_path}"));
+    fn record_upstream_latency(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.latency_count.fetch_add(1, Ordering::Relaxed);
     }
 
-    let child = Command::new(&go_path)
-        .env("PORT", PORT.to_string())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .map_err(|e| format!("spawn failed: {e}"))?;
+    fn render(&self) -> String {
+        use std::fmt::Write;
 
-    {
-        let mut guard = GO.lock().unwrap_or_else(|e| e.into_inner());
-        *guard = Some(child);
+        let mut out = String::new();
+        let _ = writeln!(out, "# TYPE wave_requests_total counter");
+        let _ = writeln!(
+            out,
+            "wave_requests_total {}",
+            self.requests.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(out, "# TYPE wave_responses_total counter");
+        for (i, count) in self.responses.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "wave_responses_total{{class=\"{}xx\"}} {}",
+                i + 1,
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(out, "# TYPE wave_upstream_latency_seconds histogram");
+        let mut cumulative = 0;
+        for (i, count) in self.latency_buckets.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let le = LATENCY_BUCKETS
+                .get(i)
+                .map_or("+Inf".to_string(), ToString::to_string);
+            let _ = writeln!(
+                out,
+                "wave_upstream_latency_seconds_bucket{{le=\"{le}\"}} {cumulative}"
+            );
+        }
+        let sum = self.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "wave_upstream_latency_seconds_sum {sum}");
+        let _ = writeln!(
+            out,
+            "wave_upstream_latency_seconds_count {}",
+            self.latency_count.load(Ordering::Relaxed)
+        );
+
+        let restarts = self.restarts();
+        let _ = writeln!(out, "# TYPE wave_backend_restarts_total counter");
+        let _ = writeln!(out, "wave_backend_restarts_total {restarts}");
+        let _ = writeln!(out, "# TYPE wave_backend_crashes_total counter");
+        let _ = writeln!(
+            out,
+            "wave_backend_crashes_total {}",
+            RESTARTS.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "# TYPE wave_backend_ready gauge");
+        let _ = writeln!(
+            out,
+            "wave_backend_ready {}",
+            u8::from(READY.load(Ordering::Acquire))
+        );
+        let _ = writeln!(out, "# TYPE wave_backend_instance_up gauge");
+        let instances = CONFIG
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .map_or(1, |cfg| cfg.process.instances);
+        let mut up = vec![false; instances];
+        for backend in GO.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            if let Some(slot) = up.get_mut(backend.index) {
+                *slot = backend.ready;
+            }
+        }
+        for (index, ready) in up.into_iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "wave_backend_instance_up{{instance=\"{index}\"}} {}",
+                u8::from(ready)
+            );
+        }
+        if let Some(exit) = last_exit() {
+            let _ = writeln!(out, "# TYPE wave_backend_last_exit_timestamp_seconds gauge");
+            let _ = writeln!(
+                out,
+                "wave_backend_last_exit_timestamp_seconds{{instance=\"{}\",code=\"{}\",signal=\"{}\"}} {}",
+                exit.instance,
+                exit.code.map(|c| c.to_string()).unwrap_or_default(),
+                exit.signal.map(|s| s.to_string()).unwrap_or_default(),
+                exit.at_unix_ms / 1000
+            );
+        }
+        let _ = writeln!(out, "# TYPE wave_circuit_opens_total counter");
+        let _ = writeln!(
+            out,
+            "wave_circuit_opens_total {}",
+            self.circuit_opens.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "# TYPE wave_upstream_request_bytes_total counter");
+        let _ = writeln!(
+            out,
+            "wave_upstream_request_bytes_total {}",
+            self.request_bytes.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "# TYPE wave_upstream_response_bytes_total counter");
+        let _ = writeln!(
+            out,
+            "wave_upstream_response_bytes_total {}",
+            self.response_bytes.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "# TYPE wave_cache_hits_total counter");
+        let _ = writeln!(
+            out,
+            "wave_cache_hits_total {}",
+            self.cache_hits.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "# TYPE wave_cache_misses_total counter");
+        let _ = writeln!(
+            out,
+            "wave_cache_misses_total {}",
+            self.cache_misses.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "# TYPE wave_upstream_in_flight gauge");
+        let _ = writeln!(
+            out,
+            "wave_upstream_in_flight {}",
+            self.in_flight.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "# TYPE wave_circuit_open gauge");
+        let _ = writeln!(out, "wave_circuit_open {}", u8::from(BREAKER.is_open()));
+        out
     }
+}
+
+/// Stops requests from piling onto a backend that keeps failing. After
+/// `CircuitBreakerThreshold` consecutive failures the circuit opens and
+/// requests get an immediate 503; once the cooldown passes a single probe is
+/// let through, and its outcome closes or re-opens the circuit.
+struct Breaker {
+    state: Mutex<BreakerState>,
+}
 
-    let uri: hyper::Uri = format!("http://127.0.0.1:{PORT}{health}").parse().unwrap();
-    let deadline = Instant::now() + TIMEOUT;
+struct BreakerState {
+    failures: u32,
+    window_start: Option<Instant>,
+    open_until: Option<Instant>,
+    /// Set while a half-open probe is in flight. A probe that never reports
+    /// back (e.g. the client went away) expires after another cooldown.
+    probe_started: Option<Instant>,
+}
 
-    while Instant::now() < deadline {
-        let req = hyper::Request::builder()
-            .uri(uri.clone())
-            .body(Empty::new())
-            .unwrap();
+impl Breaker {
+    const fn new() -> Self {
+        Self {
+            state: Mutex::new(BreakerState {
+                failures: 0,
+                window_start: None,
+                open_until: None,
+                probe_started: None,
+            }),
+        }
+    }
 
-        let is_healthy = health_client()
-            .request(req)
-            .await
-            .map(|res| res.status().is_success())
-            .unwrap_or(false);
+    fn lock(&self) -> std::sync::MutexGuard<'_, BreakerState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
 
-        if is_healthy {
-            READY.store(true, Ordering::Release);
-            println!("[proxy] go ready in {:?}", start.elapsed());
+    fn is_open(&self) -> bool {
+        self.lock().open_until.is_some()
+    }
+
+    /// Returns how long the caller should wait when the circuit is open.
+    fn allow(&self, cfg: &Config) -> Result<(), Duration> {
+        if cfg.http.circuit_breaker_threshold == 0 {
+            return Ok(());
+        }
+        let cooldown = Duration::from_millis(cfg.http.circuit_breaker_cooldown_ms);
+        let now = Instant::now();
+        let mut state = self.lock();
+        let Some(open_until) = state.open_until else {
             return Ok(());
+        };
+        if now < open_until {
+            return Err(open_until - now);
+        }
+        match state.probe_started {
+            Some(started) if now.duration_since(started) < cooldown => {
+                Err(cooldown - now.duration_since(started))
+            }
+            _ => {
+                state.probe_started = Some(now);
+                tracing::info!("circuit half-open, probing backend");
+                Ok(())
+            }
         }
-        sleep(POLL).await;
     }
 
-    kill_child();
-    Err("health check timed out".into())
+    fn record_success(&self) {
+        let mut state = self.lock();
+        if state.open_until.is_some() {
+            tracing::info!("circuit closed");
+        }
+        state.failures = 0;
+        state.window_start = None;
+        state.open_until = None;
+        state.probe_started = None;
+    }
+
+    fn record_failure(&self, cfg: &Config) {
+        let threshold = cfg.http.circuit_breaker_threshold;
+        if threshold == 0 {
+            return;
+        }
+        let window = Duration::from_millis(cfg.http.circuit_breaker_window_ms);
+        let cooldown = Duration::from_millis(cfg.http.circuit_breaker_cooldown_ms);
+        let now = Instant::now();
+        let mut state = self.lock();
+
+        if state.probe_started.take().is_some() {
+            state.open_until = Some(now + cooldown);
+            tracing::warn!(
+                cooldown_ms = cooldown.as_millis() as u64,
+                "probe failed, circuit re-opened"
+            );
+            return;
+        }
+        if state.open_until.is_some() {
+            return;
+        }
+        if state
+            .window_start
+            .is_none_or(|start| now.duration_since(start) > window)
+        {
+            state.window_start = Some(now);
+            state.failures = 0;
+        }
+        state.failures += 1;
+        if state.failures >= threshold {
+            state.open_until = Some(now + cooldown);
+            READY.store(false, Ordering::Release);
+            METRICS.circuit_opens.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!(
+                failures = state.failures,
+                cooldown_ms = cooldown.as_millis() as u64,
+                "circuit opened"
+            );
+        }
+    }
 }
 
-fn is_hop_by_hop_header(name: &str) -> bool {
-    matches!(
-        name.to_lowercase().as_str(),
-        "connection"
-            | "keep-alive"
-            | "proxy-authenticate"
-            | "proxy-authorization"
-            | "te"
-            | "trailers"
-            | "transfer-encoding"
-            | "upgrade"
-            | "host"
+fn config() -> Arc<Config> {
+    if let Some(cfg) = &*CONFIG.read().unwrap_or_else(|e| e.into_inner()) {
+        return cfg.clone();
+    }
+    let mut slot = CONFIG.write().unwrap_or_else(|e| e.into_inner());
+    let cfg = slot.get_or_insert_with(|| match read_config() {
+        Ok(cfg) => Arc::new(cfg),
+        Err(e) => {
+            tracing::error!("{e}");
+            std::process::exit(1);
+        }
+    });
+    cfg.clone()
+}
+
+fn read_config() -> Result<Config, ConfigError> {
+    let path = config_path();
+    tracing::info!(path = %path.display(), "loading wave config");
+    Config::load(&path)
+}
+
+/// Read once into the long-lived clients and caches, so a reload that
+/// changes any of these is refused.
+const PROXY_RESTART_FIELDS: [&str; 18] = [
+    "Core.BackendHost",
+    "Core.BackendSocket",
+    "Core.Https",
+    "Core.TlsCaFile",
+    "Core.TlsPinnedCertFile",
+    "Core.TlsServerName",
+    "Core.TlsClientCert",
+    "Core.TlsClientKey",
+    "Http.Http2",
+    "Http.ConnectTimeoutMs",
+    "Http.PoolIdleTimeoutMs",
+    "Http.PoolMaxIdlePerHost",
+    "Http.TcpNodelay",
+    "Http.SecretEnv",
+    "Http.SecretHeader",
+    "Http.MaxConcurrentUpstream",
+    "Http.MaintenancePage",
+    "Observability.AdminTokenEnv",
+];
+
+/// Only seen by a freshly spawned backend.
+const BACKEND_RESTART_FIELDS: [&str; 3] = ["Core.DistDir", "Core.Port", "Process"];
+
+fn changed_fields<'a>(
+    old: &serde_json::Value,
+    new: &serde_json::Value,
+    fields: &[&'a str],
+) -> Vec<&'a str> {
+    fields
+        .iter()
+        .copied()
+        .filter(|field| {
+            let pointer = format!("/{}", field.replace('.', "/"));
+            old.pointer(&pointer) != new.pointer(&pointer)
+        })
+        .collect()
+}
+
+/// Applies edits to the config file while `Watch.ReloadConfig` is on. The
+/// file's modification time is polled every `CONFIG_RELOAD_INTERVAL` rather
+/// than watched with inotify: one `stat` a second costs nothing next to a
+/// request, needs no extra dependency, and also notices edits on mounts that
+/// deliver no change events, such as bind mounts into a dev container.
+async fn watch_config() {
+    let modified = |cfg: &Config| {
+        let mtime = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        (mtime(&cfg.source), cfg.overlay.as_deref().and_then(mtime))
+    };
+    let mut seen = modified(&config());
+    loop {
+        sleep(CONFIG_RELOAD_INTERVAL).await;
+        let current = config();
+        if !current.watch.reload_config() {
+            continue;
+        }
+        let now = modified(&current);
+        if now == seen {
+            continue;
+        }
+        seen = now;
+        reload_config(&current).await;
+    }
+}
+
+/// Rereads `current`'s file and swaps the result in, restarting the backend
+/// when a field only it reads changed. A new config replaces the old one
+/// only after it parses and validates, so a half-saved file is skipped until
+/// the next write fixes it. Returns whether the new config was applied.
+async fn reload_config(current: &Config) -> bool {
+    tracing::info!(path = %current.source.display(), "reloading wave config");
+    let next = match Config::load(&current.source) {
+        Ok(next) => next,
+        Err(e) => {
+            tracing::warn!("keeping the previous config: {e}");
+            return false;
+        }
+    };
+    let pinned = changed_fields(&current.raw, &next.raw, &PROXY_RESTART_FIELDS);
+    if !pinned.is_empty() {
+        tracing::warn!(
+            fields = ?pinned,
+            "keeping the previous config: these fields only change when the proxy restarts"
+        );
+        return false;
+    }
+    let restart = changed_fields(&current.raw, &next.raw, &BACKEND_RESTART_FIELDS);
+    let next = Arc::new(next);
+    *CONFIG.write().unwrap_or_else(|e| e.into_inner()) = Some(next.clone());
+    tracing::info!("config reloaded");
+    log_effective_config(&next);
+    if !restart.is_empty() {
+        tracing::info!(fields = ?restart, "restarting backend for changed config");
+        restart_backend().await;
+    }
+    true
+}
+
+/// One line with the settings that matter when debugging a deployment.
+/// Only names are logged for env vars, headers and secrets, never values.
+fn log_effective_config(cfg: &Config) {
+    let backend = match &cfg.core.backend_socket {
+        Some(socket) => format!("unix:{socket}"),
+        None => match cfg.core.port_range {
+            Some([first, last]) => format!("{}:<{first}-{last}>", cfg.core.backend_host),
+            None if cfg.core.port == 0 => format!("{}:<ephemeral>", cfg.core.backend_host),
+            None => format!("{}:{}", cfg.core.backend_host, cfg.core.port),
+        },
+    };
+    let env_keys: Vec<&str> = cfg.process.env.keys().map(String::as_str).collect();
+    tracing::info!(
+        config = %cfg.source.display(),
+        overlay = cfg.overlay.as_ref().map(|p| tracing::field::display(p.display())),
+        env_overrides = ?cfg.env_overrides,
+        dist_dir = %cfg.core.dist_dir,
+        command = %cfg.process.command_path(&cfg.core.dist_dir).display(),
+        working_dir = cfg.process.working_dir.as_deref(),
+        backend = %backend,
+        https = cfg.core.https,
+        static_dir = cfg.core.static_dir.as_deref(),
+        readiness_endpoint = cfg.watch.readiness_endpoint(),
+        liveness_endpoint = cfg.watch.liveness_endpoint.as_deref(),
+        ready_signal = cfg.watch.ready_signal.as_deref(),
+        healthcheck_timeout_ms = cfg.watch.healthcheck_timeout().as_millis() as u64,
+        max_restarts = cfg.process.max_restarts,
+        restart_reset_ms = cfg.process.restart_reset_ms,
+        idle_timeout_ms = cfg.process.idle_timeout_ms,
+        startup_attempts = cfg.process.startup_attempts,
+        instances = cfg.process.instances,
+        no_spawn = cfg.process.no_spawn(),
+        sticky_header = cfg.load_balancing.sticky_header.as_deref(),
+        sticky_cookie = cfg.load_balancing.sticky_cookie.as_deref(),
+        upstream_retries = cfg.http.upstream_retries,
+        upstream_timeout_ms = cfg.http.upstream_request_timeout_ms,
+        routes = cfg.http.routes.len(),
+        max_concurrent_upstream = cfg.http.max_concurrent_upstream,
+        compression = cfg.http.compression,
+        child_env = ?env_keys,
+        secret_env = cfg.http.secret_env.as_deref(),
+        "effective config"
+    );
+}
+
+/// Client for proxied requests. With `Http.Http2` it speaks h2c (HTTP/2 with
+/// prior knowledge), multiplexing requests over a single connection.
+fn proxy_client() -> &'static Client<Connector, ProxyBody> {
+    PROXY_CLIENT.get_or_init(|| build_proxy_client(&config()))
+}
+
+fn build_proxy_client(cfg: &Config) -> Client<Connector, ProxyBody> {
+    let mut builder = client_builder(cfg);
+    builder.http2_only(cfg.http.http2);
+    builder.build(Connector::new(cfg))
+}
+
+/// Upgrades (WebSockets) only exist in HTTP/1.1, so they bypass an h2c
+/// `proxy_client`.
+fn upgrade_client() -> &'static Client<Connector, ProxyBody> {
+    static UPGRADE_CLIENT: OnceLock<Client<Connector, ProxyBody>> = OnceLock::new();
+    if !config().http.http2 {
+        return proxy_client();
+    }
+    UPGRADE_CLIENT.get_or_init(|| client_builder(&config()).build(Connector::new(&config())))
+}
+
+/// Health checks stay on HTTP/1.1 even with `Http.Http2`: they are one
+/// request at a time, and a backend still booting may not speak h2c yet.
+fn health_client() -> &'static Client<Connector, Empty<Bytes>> {
+    HEALTH_CLIENT.get_or_init(|| build_health_client(&config()))
+}
+
+fn build_health_client(cfg: &Config) -> Client<Connector, Empty<Bytes>> {
+    client_builder(cfg).build(Connector::new(cfg))
+}
+
+fn client_builder(cfg: &Config) -> hyper_util::client::legacy::Builder {
+    let mut builder = Client::builder(TokioExecutor::new());
+    builder
+        .pool_idle_timeout(Duration::from_millis(cfg.http.pool_idle_timeout_ms))
+        .pool_max_idle_per_host(cfg.http.pool_max_idle_per_host);
+    builder
+}
+
+/// Authority of the backend. Over a Unix socket it is only used for the
+/// `Host` header, so a fixed placeholder is used.
+fn build_authority(
+    cfg: &Config,
+    port: u16,
+) -> Result<hyper::http::uri::Authority, hyper::http::uri::InvalidUri> {
+    if cfg.core.backend_socket.is_some() {
+        return Ok(hyper::http::uri::Authority::from_static("localhost"));
+    }
+    let host = &cfg.core.backend_host;
+    if host.contains(':') && !host.starts_with('[') {
+        return format!("[{host}]:{port}").parse();
+    }
+    format!("{host}:{port}").parse()
+}
+
+/// The authority of the instance on `port`, built once per port. An
+/// ephemeral `Port` picks new ports on every spawn, so the cache is dropped
+/// once it holds more entries than there are instances.
+fn backend_authority(cfg: &Config, port: u16) -> hyper::http::uri::Authority {
+    static CACHE: RwLock<Vec<(u16, hyper::http::uri::Authority)>> = RwLock::new(Vec::new());
+
+    if let Some((_, authority)) = CACHE
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .find(|(cached, _)| *cached == port)
+    {
+        return authority.clone();
+    }
+    // Validated at config load, so only a host/port combination that cannot
+    // form an authority would land here.
+    let authority = build_authority(cfg, port).expect("backend authority validated at load");
+    let mut cache = CACHE.write().unwrap_or_else(|e| e.into_inner());
+    if cache.len() >= cfg.process.instances {
+        cache.clear();
+    }
+    cache.push((port, authority.clone()));
+    authority
+}
+
+fn backend_uri(
+    cfg: &Config,
+    port: u16,
+    path_and_query: hyper::http::uri::PathAndQuery,
+) -> Result<hyper::Uri, hyper::http::uri::InvalidUriParts> {
+    let mut parts = hyper::http::uri::Parts::default();
+    parts.scheme = Some(if cfg.core.https {
+        hyper::http::uri::Scheme::HTTPS
+    } else {
+        hyper::http::uri::Scheme::HTTP
+    });
+    parts.authority = Some(backend_authority(cfg, port));
+    parts.path_and_query = Some(path_and_query);
+    hyper::Uri::from_parts(parts)
+}
+
+/// Dials the backend over TCP, or over a Unix socket when `BackendSocket`
+/// is configured.
+#[derive(Clone)]
+enum Connector {
+    Tcp {
+        http: HttpConnector,
+        tls: Option<(tokio_rustls::TlsConnector, ServerName<'static>)>,
+    },
+    #[cfg(unix)]
+    Unix(Arc<Path>),
+}
+
+impl Connector {
+    fn new(cfg: &Config) -> Self {
+        #[cfg(unix)]
+        if let Some(path) = &cfg.core.backend_socket {
+            return Self::Unix(Path::new(path).into());
+        }
+        let mut http = HttpConnector::new();
+        http.set_nodelay(cfg.http.tcp_nodelay);
+        http.set_connect_timeout(
+            Some(Duration::from_millis(cfg.http.connect_timeout_ms)).filter(|t| !t.is_zero()),
+        );
+        let tls = cfg.core.https.then(|| {
+            http.enforce_http(false);
+            // Both were checked by `validate_config`.
+            let client = tls_client_config(cfg).expect("TLS settings validated at load");
+            let name = tls_server_name(cfg).expect("TLS server name validated at load");
+            (tokio_rustls::TlsConnector::from(Arc::new(client)), name)
+        });
+        Self::Tcp { http, tls }
+    }
+}
+
+fn tls_server_name(cfg: &Config) -> Result<ServerName<'static>, String> {
+    let name = cfg
+        .core
+        .tls_server_name
+        .as_deref()
+        .unwrap_or(&cfg.core.backend_host);
+    ServerName::try_from(
+        name.trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string(),
     )
+    .map_err(|e| format!("invalid TLS server name {name:?}: {e}"))
 }
 
-async fn handler(req: Request) -> Result<Response<ResponseBody>, Error> {
-    if let Err(e) = ensure_ready().await {
-        return Ok(Response::builder()
-            .status(503)
-            .body(ResponseBody::from(e))?);
+fn tls_client_config(cfg: &Config) -> Result<rustls::ClientConfig, String> {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("TLS setup failed: {e}"))?;
+    let builder = match (&cfg.core.tls_pinned_cert_file, &cfg.core.tls_ca_file) {
+        (Some(_), Some(_)) => {
+            return Err("Core.TlsPinnedCertFile and Core.TlsCaFile are mutually exclusive".into());
+        }
+        (Some(path), None) => {
+            let pinned = CertificateDer::pem_file_iter(path)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| format!("failed to read Core.TlsPinnedCertFile {path}: {e}"))?;
+            if pinned.is_empty() {
+                return Err(format!(
+                    "Core.TlsPinnedCertFile {path} contains no certificates"
+                ));
+            }
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedCert { pinned, provider }))
+        }
+        (None, Some(path)) => {
+            let mut roots = rustls::RootCertStore::empty();
+            let certs = CertificateDer::pem_file_iter(path)
+                .map_err(|e| format!("failed to read Core.TlsCaFile {path}: {e}"))?;
+            for cert in certs {
+                let cert = cert.map_err(|e| format!("invalid certificate in {path}: {e}"))?;
+                roots
+                    .add(cert)
+                    .map_err(|e| format!("unusable certificate in {path}: {e}"))?;
+            }
+            if roots.is_empty() {
+                return Err(format!("Core.TlsCaFile {path} contains no certificates"));
+            }
+            builder.with_root_certificates(roots)
+        }
+        (None, None) => builder.with_root_certificates(rustls::RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        }),
+    };
+    match (&cfg.core.tls_client_cert, &cfg.core.tls_client_key) {
+        (Some(cert), Some(key)) => {
+            let chain = CertificateDer::pem_file_iter(cert)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| format!("failed to read Core.TlsClientCert {cert}: {e}"))?;
+            let key = PrivateKeyDer::from_pem_file(key)
+                .map_err(|e| format!("failed to read Core.TlsClientKey {key}: {e}"))?;
+            builder
+                .with_client_auth_cert(chain, key)
+                .map_err(|e| format!("invalid TLS client certificate: {e}"))
+        }
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => Err("Core.TlsClientCert and Core.TlsClientKey must be set together".into()),
+    }
+}
+
+/// Accepts exactly the configured certificates. Handshake signatures are
+/// still verified, so the peer must hold the matching private key.
+#[derive(Debug)]
+struct PinnedCert {
+    pinned: Vec<rustls::pki_types::CertificateDer<'static>>,
+    provider: Arc<rustls::crypto::CryptoProvider>,
+}
+
+impl rustls::client::danger::ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        if self
+            .pinned
+            .iter()
+            .any(|cert| cert.as_ref() == end_entity.as_ref())
+        {
+            Ok(rustls::client::danger::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
     }
 
-    let path = req
-        .uri()
-        .path_and_query()
-        .map(|pq| pq.as_str())
-        .unwrap_or("/");
-    let uri: hyper::Uri = format!("http://127.0.0.1:{PORT}{path}").parse().unwrap();
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
 
-    let (parts, body) = req.into_parts();
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
 
-    let mut builder = hyper::Request::builder().method(parts.method).uri(uri);
-    for (k, v) in &parts.headers {
-        if !is_hop_by_hop_header(k.as_str()) {
-            builder = builder.header(k, v);
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+impl tower_service::Service<hyper::Uri> for Connector {
+    type Response = BackendStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<BackendStream, Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self {
+            Self::Tcp { http, .. } => http.poll_ready(cx).map_err(Error::from),
+            #[cfg(unix)]
+            Self::Unix(_) => Poll::Ready(Ok(())),
         }
     }
 
-    match proxy_client().request(builder.body(body)?).await {
-        Ok(res) => {
-            let (parts, incoming) = res.into_parts();
-            let mut response = Response::builder().status(parts.status);
-            for (k, v) in &parts.headers {
-                if !is_hop_by_hop_header(k.as_str()) {
-                    response = response.header(k, v);
-                }
+    fn call(&mut self, uri: hyper::Uri) -> Self::Future {
+        match self {
+            Self::Tcp { http, tls: None } => {
+                let connecting = http.call(uri);
+                Box::pin(async move { Ok(BackendStream::Tcp(connecting.await?)) })
+            }
+            Self::Tcp {
+                http,
+                tls: Some((tls, name)),
+            } => {
+                let connecting = http.call(uri);
+                let (tls, name) = (tls.clone(), name.clone());
+                Box::pin(async move {
+                    let tcp = connecting.await?.into_inner();
+                    let stream = tls.connect(name, tcp).await?;
+                    Ok(BackendStream::Tls(Box::new(TokioIo::new(stream))))
+                })
             }
+            #[cfg(unix)]
+            Self::Unix(path) => {
+                let path = path.clone();
+                Box::pin(async move {
+                    let stream = tokio::net::UnixStream::connect(&*path).await?;
+                    Ok(BackendStream::Unix(TokioIo::new(stream)))
+                })
+            }
+        }
+    }
+}
 
-            let stream = incoming.into_data_stream().map(|result| {
-                result
-                    .map(Frame::data)
-                    .map_err(|e| Error::from(e.to_string()))
-            });
+enum BackendStream {
+    Tcp(TokioIo<tokio::net::TcpStream>),
+    Tls(Box<TokioIo<tokio_rustls::client::TlsStream<tokio::net::TcpStream>>>),
+    #[cfg(unix)]
+    Unix(TokioIo<tokio::net::UnixStream>),
+}
 
-            Ok(response.body(ResponseBody::from(StreamBody::new(stream)))?)
+impl Connection for BackendStream {
+    fn connected(&self) -> Connected {
+        match self {
+            Self::Tcp(stream) => stream.connected(),
+            Self::Tls(_) => Connected::new(),
+            #[cfg(unix)]
+            Self::Unix(_) => Connected::new(),
         }
-        Err(e) => {
-            eprintln!("[proxy] backend unreachable: {e}");
-            READY.store(false, Ordering::Release);
-            panic!("backend connection failed: {e}");
+    }
+}
+
+impl hyper::rt::Read for BackendStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: hyper::rt::ReadBufCursor<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl hyper::rt::Write for BackendStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
         }
     }
 }
 
-fn shutdown() {
-    kill_child();
-    println!("[proxy] shutdown");
+/// Picks the instance for a request, as `(index, port)`. A session key
+/// always hashes to the same instance; without one, or when that instance is
+/// not ready, the next ready instance is taken round-robin.
+fn next_backend(cfg: &Config, session: Option<&str>) -> Option<(usize, u16)> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+    let guard = GO.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(key) = session {
+        // `DefaultHasher::new` uses fixed keys, so a key maps to the same
+        // instance for the life of the process.
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        std::hash::Hash::hash(key, &mut hasher);
+        let index = (std::hash::Hasher::finish(&hasher) % cfg.process.instances as u64) as usize;
+        if let Some(b) = guard.iter().find(|b| b.index == index && b.ready) {
+            return Some((b.index, b.port));
+        }
+        tracing::warn!(instance = index, "sticky instance is not ready, rerouting");
+    }
+    let ready = guard.iter().filter(|b| b.ready).count();
+    if ready == 0 {
+        return None;
+    }
+    let pick = NEXT.fetch_add(1, Ordering::Relaxed) % ready;
+    guard
+        .iter()
+        .filter(|b| b.ready)
+        .nth(pick)
+        .map(|b| (b.index, b.port))
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
-    tokio::spawn(async {
-        tokio::signal::ctrl_c().await.ok();
-        shutdown();
-        std::process::exit(0);
+/// Resolves the port to hand to the child. A configured port of `0` binds a
+/// throwaway listener to discover a free ephemeral port, then releases it.
+fn resolve_port(host: &str, configured: u16) -> Result<u16, String> {
+    if configured != 0 {
+        return Ok(configured);
+    }
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let listener = std::net::TcpListener::bind((host, 0))
+        .map_err(|e| format!("failed to discover free port: {e}"))?;
+    listener
+        .local_addr()
+        .map(|addr| addr.port())
+        .map_err(|e| format!("failed to discover free port: {e}"))
+}
+
+/// First port in `Core.PortRange` that binds, skipping the ports the other
+/// instances were given since their children may not have bound them yet.
+fn scan_port_range(host: &str, [first, last]: [u16; 2], index: usize) -> Result<u16, String> {
+    let taken: Vec<u16> = GO
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .filter(|b| b.index != index)
+        .map(|b| b.port)
+        .collect();
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let mut last_error = None;
+    for port in (first..=last).filter(|port| !taken.contains(port)) {
+        match std::net::TcpListener::bind((host, port)) {
+            Ok(_) => return Ok(port),
+            Err(e) => {
+                tracing::debug!(port, error = %e, "port in Core.PortRange is busy");
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(match last_error {
+        Some(e) => format!("no free port in Core.PortRange {first}-{last} (last error: {e})"),
+        None => format!(
+            "no free port in Core.PortRange {first}-{last}: every port is held by another instance"
+        ),
+    })
+}
+
+/// Stops every instance, in parallel so shutdown takes one grace period
+/// rather than one per instance.
+async fn kill_child() {
+    let backends = std::mem::take(&mut *GO.lock().unwrap_or_else(|e| e.into_inner()));
+    let grace = Duration::from_millis(config().process.shutdown_grace_ms);
+    let stopping: Vec<_> = backends
+        .into_iter()
+        .map(|b| tokio::spawn(stop_backend(b, grace)))
+        .collect();
+    for task in stopping {
+        let _ = task.await;
+    }
+}
+
+/// Counts a crash against `MaxRestarts`, returning the new count. The
+/// count starts over when the previous crash is `RestartResetMs` old.
+fn count_crash(cfg: &Config) -> u32 {
+    let now = unix_millis();
+    let last = LAST_CRASH_MS.swap(now, Ordering::AcqRel);
+    let reset = cfg.process.restart_reset_ms;
+    if reset > 0 && last > 0 && now.saturating_sub(last) >= reset {
+        RESTARTS.store(0, Ordering::Release);
+    }
+    RESTARTS.fetch_add(1, Ordering::AcqRel) + 1
+}
+
+/// Takes a single instance out of rotation, stops it and starts a
+/// replacement. When it was the last one, `READY` is cleared instead and
+/// the next request restarts everything. An instance that has exited, or
+/// is `unresponsive` to liveness checks, counts as a crash, before any
+/// replacement is started.
+fn lose_instance(index: usize, unresponsive: bool) {
+    let (lost, remaining) = {
+        let mut guard = GO.lock().unwrap_or_else(|e| e.into_inner());
+        let lost = guard
+            .iter()
+            .position(|b| b.index == index)
+            .map(|i| guard.remove(i));
+        (lost, guard.len())
+    };
+    if remaining == 0 {
+        READY.store(false, Ordering::Release);
+    }
+    // Already taken out by a concurrent failure or the supervisor.
+    let Some(mut lost) = lost else {
+        return;
+    };
+    let cfg = config();
+    // A crash usually shows up here first, as a reset connection, and the
+    // supervisor never gets to reap it.
+    let exit = match lost.child.as_mut().map(Child::try_wait) {
+        Some(Ok(Some(status))) => Some(Some(status)),
+        None if lost.adopted.is_some_and(|pid| !process_alive(pid)) => Some(None),
+        _ => None,
+    };
+    if let Some(status) = exit {
+        let crashes = count_crash(&cfg);
+        match status {
+            Some(status) => {
+                record_exit(index, status);
+                tracing::error!(%status, instance = index, crashes, "go exited unexpectedly");
+            }
+            None => {
+                tracing::error!(instance = index, crashes, "adopted go exited unexpectedly");
+            }
+        }
+    } else if unresponsive {
+        let crashes = count_crash(&cfg);
+        tracing::error!(crashes, instance = index, "go is unresponsive, restarting");
+    }
+    let grace = Duration::from_millis(cfg.process.shutdown_grace_ms);
+    tokio::spawn(async move {
+        stop_backend(lost, grace).await;
+        if remaining > 0 {
+            respawn(index).await;
+        }
     });
+}
+
+/// Stops whichever process `backend` stands for; under `NoSpawn` there is
+/// none.
+async fn stop_backend(backend: Backend, grace: Duration) {
+    if let Some(child) = backend.child {
+        terminate(child, grace).await;
+    } else if let Some(pid) = backend.adopted {
+        terminate_adopted(pid, grace).await;
+    }
+}
 
+/// Asks the child to exit with SIGTERM so it can flush logs and finish
+/// in-flight work, escalating to SIGKILL once `grace` runs out.
+async fn terminate(mut child: Child, grace: Duration) {
     #[cfg(unix)]
-    tokio::spawn(async {
-        use tokio::signal::unix::{SignalKind, signal};
-        if let Ok(mut sig) = signal(SignalKind::terminate()) {
-            sig.recv().await;
-            shutdown();
-            std::process::exit(0);
+    {
+        let pid = child.id() as libc::pid_t;
+        // SAFETY: `kill` has no memory-safety preconditions; `pid` is our own
+        // child, which has not been reaped yet so the pid cannot be reused.
+        if unsafe { libc::kill(pid, libc::SIGTERM) } == 0 {
+            let deadline = Instant::now() + grace;
+            while Instant::now() < deadline {
+                if let Ok(Some(_)) = child.try_wait() {
+                    return;
+                }
+                sleep(EXIT_POLL).await;
+            }
+            tracing::warn!(?grace, "go did not exit after SIGTERM, killing");
         }
-    });
+    }
+    #[cfg(not(unix))]
+    let _ = grace;
 
-    run(service_fn(handler)).await
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// `terminate` for an adopted backend. It is not our child, so there is no
+/// exit status to wait on: it counts as gone once `kill(pid, 0)` fails.
+async fn terminate_adopted(pid: u32, grace: Duration) {
+    #[cfg(unix)]
+    {
+        let Ok(raw) = libc::pid_t::try_from(pid) else {
+            return;
+        };
+        // SAFETY: `kill` has no memory-safety preconditions.
+        if unsafe { libc::kill(raw, libc::SIGTERM) } != 0 {
+            return;
+        }
+        let deadline = Instant::now() + grace;
+        while Instant::now() < deadline {
+            if !process_alive(pid) {
+                return;
+            }
+            sleep(EXIT_POLL).await;
+        }
+        tracing::warn!(
+            ?grace,
+            pid,
+            "adopted go did not exit after SIGTERM, killing"
+        );
+        // SAFETY: as above.
+        unsafe { libc::kill(raw, libc::SIGKILL) };
+    }
+    #[cfg(not(unix))]
+    let _ = (pid, grace);
+}
+
+async fn ensure_ready() -> Result<(), String> {
+    if READY.load(Ordering::Acquire) {
+        return Ok(());
+    }
+
+    // Initialization runs in its own task so a caller that goes away (client
+    // disconnect) cannot drop INIT_LOCK halfway through a spawn; the task
+    // finishes regardless and everyone queued behind it sees the result.
+    tokio::spawn(initialize().in_current_span())
+        .await
+        .unwrap_or_else(|e| Err(format!("backend initialization failed: {e}")))
+}
+
+/// `ensure_ready`, retried for up to `window` while attempts fail, so a
+/// request landing in a brief outage is served once the backend is back.
+/// Gives up early once `MaxRestarts` is exceeded or the proxy is draining.
+async fn ready_within(cfg: &Config, window: Duration) -> Result<(), String> {
+    let deadline = Instant::now() + window;
+    loop {
+        let e = match ensure_ready().await {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero()
+            || DRAINING.load(Ordering::Acquire)
+            || RESTARTS.load(Ordering::Acquire) > cfg.process.max_restarts
+        {
+            return Err(e);
+        }
+        tracing::debug!(error = %e, remaining = ?remaining, "backend not ready, waiting for the next attempt");
+        sleep(RESTART_WAIT_POLL.min(remaining)).await;
+    }
+}
+
+async fn initialize() -> Result<(), String> {
+    let _lock = INIT_LOCK.lock().await;
+
+    // Double-check after acquiring lock
+    if READY.load(Ordering::Acquire) {
+        return Ok(());
+    }
+
+    let cfg = config();
+    let restarts = RESTARTS.load(Ordering::Acquire);
+    if restarts > cfg.process.max_restarts {
+        return Err(format!(
+            "go backend crashed {restarts} times, exceeding MaxRestarts ({})",
+            cfg.process.max_restarts
+        ));
+    }
+
+    if let Some(marker) = &cfg.process.reuse_marker
+        && adopt_marked_backend(&cfg, Path::new(marker)).await
+    {
+        return Ok(());
+    }
+
+    let attempts = cfg.process.startup_attempts;
+    let mut failures = Vec::new();
+    for attempt in 1..=attempts {
+        match start_instances(&cfg).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempts == 1 => return Err(e),
+            Err(e) => {
+                tracing::warn!(attempt, attempts, error = %e, "backend startup attempt failed");
+                failures.push(format!("attempt {attempt}: {e}"));
+            }
+        }
+        if attempt < attempts {
+            let delay = cfg
+                .process
+                .startup_retry_delay_ms
+                .saturating_mul(2u64.saturating_pow(attempt - 1));
+            sleep(jittered(Duration::from_millis(delay), cfg.watch.jitter)).await;
+        }
+    }
+    Err(format!(
+        "backend failed to start after {attempts} attempts ({})",
+        failures.join("; ")
+    ))
+}
+
+/// One spawn and health-check cycle over every instance. Whatever was
+/// started is stopped again when it fails.
+async fn start_instances(cfg: &Config) -> Result<(), String> {
+    kill_child().await;
+
+    let start = Instant::now();
+    if cfg.process.no_spawn() {
+        tracing::info!("NoSpawn is set, waiting for an externally started backend");
+    } else {
+        check_command(&cfg.process.command_path(&cfg.core.dist_dir))?;
+        run_pre_start(cfg).await?;
+    }
+
+    if cfg.process.capture_logs {
+        BACKEND_LOGS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+    // Every instance is started before any is polled so they boot in
+    // parallel.
+    let mut ports = Vec::with_capacity(cfg.process.instances);
+    for index in 0..cfg.process.instances {
+        match spawn_instance(cfg, index) {
+            Ok(backend) => {
+                ports.push(backend.port);
+                GO.lock().unwrap_or_else(|e| e.into_inner()).push(backend);
+            }
+            Err(e) => {
+                kill_child().await;
+                return Err(e);
+            }
+        }
+    }
+
+    let deadline = Instant::now() + cfg.watch.healthcheck_timeout();
+    let mut polls = 0;
+    for (index, mut port) in ports.into_iter().enumerate() {
+        let mut attempt = 1;
+        loop {
+            port = announced_port(cfg, index, port).await;
+            let result = wait_healthy(cfg, Some(index), port, deadline).await;
+            // Another process may have taken a discovered port between
+            // discovery and the child binding it; that shows up as an early
+            // exit, and a fresh port usually fixes it.
+            let retry = result.is_err()
+                && (cfg.core.port == 0 || cfg.core.port_range.is_some())
+                && cfg.core.backend_socket.is_none()
+                && attempt < EPHEMERAL_PORT_ATTEMPTS
+                && !GO
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .iter()
+                    .any(|b| b.index == index);
+            match result {
+                Ok(n) => {
+                    polls += n;
+                    break;
+                }
+                Err(e) if !retry => {
+                    kill_child().await;
+                    return Err(e);
+                }
+                Err(e) => {
+                    attempt += 1;
+                    tracing::warn!(instance = index, port, attempt, error = %e, "retrying on a new port");
+                    match spawn_instance(cfg, index) {
+                        Ok(backend) => {
+                            port = backend.port;
+                            GO.lock().unwrap_or_else(|e| e.into_inner()).push(backend);
+                        }
+                        Err(e) => {
+                            kill_child().await;
+                            return Err(e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    for backend in GO.lock().unwrap_or_else(|e| e.into_inner()).iter_mut() {
+        backend.ready = true;
+    }
+    COLD_START_MS.store(
+        (start.elapsed().as_millis() as u64).max(1),
+        Ordering::Relaxed,
+    );
+    READY.store(true, Ordering::Release);
+    tracing::info!(elapsed = ?start.elapsed(), polls, instances = cfg.process.instances, "go ready");
+    if let Some(marker) = &cfg.process.reuse_marker {
+        write_reuse_marker(cfg, Path::new(marker));
+    }
+    Ok(())
+}
+
+/// What `Process.ReuseMarker` holds.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ReuseMarker {
+    pid: u32,
+    port: u16,
+    command: String,
+    written_at_unix_ms: u64,
+}
+
+fn write_reuse_marker(cfg: &Config, path: &Path) {
+    let Some((pid, port)) = GO
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .first()
+        .and_then(|b| Some((b.pid()?, b.port)))
+    else {
+        return;
+    };
+    let marker = ReuseMarker {
+        pid,
+        port,
+        command: cfg
+            .process
+            .command_path(&cfg.core.dist_dir)
+            .display()
+            .to_string(),
+        written_at_unix_ms: unix_millis(),
+    };
+    // Renamed into place so a reader never sees half a marker.
+    let staging = path.with_extension("tmp");
+    let written = serde_json::to_vec(&marker)
+        .map_err(std::io::Error::other)
+        .and_then(|json| std::fs::write(&staging, json))
+        .and_then(|()| std::fs::rename(&staging, path));
+    match written {
+        Ok(()) => MARKER_WRITTEN_MS.store(marker.written_at_unix_ms, Ordering::Relaxed),
+        Err(e) => {
+            tracing::warn!(path = %path.display(), error = %e, "failed to write reuse marker");
+        }
+    }
+}
+
+/// Rewrites the marker every quarter of `ReuseMarkerTtlMs` while the
+/// backend is up, so one that has been running for longer than the TTL is
+/// still adopted after the proxy dies.
+fn refresh_reuse_marker(cfg: &Config) {
+    let Some(marker) = &cfg.process.reuse_marker else {
+        return;
+    };
+    let due = MARKER_WRITTEN_MS.load(Ordering::Relaxed) + cfg.process.reuse_marker_ttl_ms / 4;
+    if READY.load(Ordering::Acquire) && unix_millis() >= due {
+        write_reuse_marker(cfg, Path::new(marker));
+    }
+}
+
+/// Takes over the backend recorded in `path` when it is recent, matches the
+/// configured command, is still running and answers its health check.
+/// `false` means a fresh spawn is needed.
+async fn adopt_marked_backend(cfg: &Config, path: &Path) -> bool {
+    let marker = match std::fs::read(path) {
+        Ok(data) => match serde_json::from_slice::<ReuseMarker>(&data) {
+            Ok(marker) => marker,
+            Err(e) => {
+                tracing::warn!(path = %path.display(), error = %e, "ignoring unreadable reuse marker");
+                return false;
+            }
+        },
+        Err(e) => {
+            tracing::debug!(path = %path.display(), error = %e, "no reuse marker");
+            return false;
+        }
+    };
+    let now = unix_millis();
+    let age = now.saturating_sub(marker.written_at_unix_ms);
+    if let Some(reason) = marker_rejection(cfg, &marker, now) {
+        tracing::info!(
+            pid = marker.pid,
+            port = marker.port,
+            "not reusing marked backend: {reason}"
+        );
+        return false;
+    }
+    if let Err(e) = wait_healthy(cfg, None, marker.port, Instant::now() + REUSE_CHECK_TIMEOUT).await
+    {
+        tracing::info!(pid = marker.pid, port = marker.port, error = %e, "not reusing marked backend: health check failed");
+        return false;
+    }
+
+    GO.lock().unwrap_or_else(|e| e.into_inner()).push(Backend {
+        index: 0,
+        port: marker.port,
+        child: None,
+        adopted: Some(marker.pid),
+        ready: true,
+        announced: None,
+    });
+    READY.store(true, Ordering::Release);
+    tracing::info!(
+        pid = marker.pid,
+        port = marker.port,
+        age_ms = age,
+        "reusing backend from marker"
+    );
+    write_reuse_marker(cfg, path);
+    true
+}
+
+/// Why `marker` cannot be adopted at `now_ms`, short of the health check;
+/// `None` means it may be.
+fn marker_rejection(cfg: &Config, marker: &ReuseMarker, now_ms: u64) -> Option<&'static str> {
+    let command = cfg.process.command_path(&cfg.core.dist_dir);
+    if now_ms.saturating_sub(marker.written_at_unix_ms) > cfg.process.reuse_marker_ttl_ms {
+        Some("it is too old")
+    } else if marker.command != command.display().to_string() {
+        Some("it is for a different command")
+    } else if !process_alive(marker.pid) {
+        Some("its process is gone")
+    } else if !runs_command(marker.pid, &command) {
+        Some("its pid now runs a different binary")
+    } else {
+        None
+    }
+}
+
+fn process_alive(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        let Ok(pid) = libc::pid_t::try_from(pid) else {
+            return false;
+        };
+        // Signal 0 only checks that the process exists. EPERM means it does
+        // but belongs to another user.
+        // SAFETY: `kill` has no memory-safety preconditions.
+        let signalled = unsafe { libc::kill(pid, 0) } == 0;
+        signalled || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        true
+    }
+}
+
+/// Whether `pid` is executing `command`, so a pid recycled by an unrelated
+/// process is not adopted. Only Linux can tell; elsewhere, or when
+/// `/proc/<pid>/exe` cannot be read, the health check has the last word.
+fn runs_command(pid: u32, command: &Path) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        match (
+            std::fs::read_link(format!("/proc/{pid}/exe")),
+            std::fs::canonicalize(command),
+        ) {
+            (Ok(exe), Ok(command)) => exe == command,
+            _ => true,
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (pid, command);
+        true
+    }
+}
+
+/// Fails early, with a clear message, on a backend that cannot be executed.
+fn check_command(go_path: &Path) -> Result<(), String> {
+    let meta = std::fs::metadata(go_path)
+        .map_err(|_| format!("backend binary not found at {}", go_path.display()))?;
+    if !meta.is_file() {
+        return Err(format!(
+            "backend binary at {} is not a file",
+            go_path.display()
+        ));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if meta.permissions().mode() & 0o111 == 0 {
+            return Err(format!(
+                "go binary at {} is not executable (chmod +x needed)",
+                go_path.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Runs `Process.PreStart`, if set, and waits for it to succeed. Nothing has
+/// been spawned yet, and a pre-start that times out is killed.
+async fn run_pre_start(cfg: &Config) -> Result<(), String> {
+    let Some((program, args)) = cfg.process.pre_start.split_first() else {
+        return Ok(());
+    };
+    let shown = cfg.process.pre_start.join(" ");
+    let mut command = tokio::process::Command::new(program);
+    command.args(args).stdin(Stdio::null()).kill_on_drop(true);
+    if let Some(dir) = &cfg.process.working_dir {
+        command.current_dir(dir);
+    }
+    for (key, value) in &cfg.process.env {
+        command.env(key, interpolate_env(value));
+    }
+
+    let start = Instant::now();
+    tracing::info!(command = %shown, "running pre-start command");
+    let timeout = Duration::from_millis(cfg.process.pre_start_timeout_ms);
+    let output = match tokio::time::timeout(timeout, command.output()).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => return Err(format!("pre-start command {shown:?} failed to run: {e}")),
+        Err(_) => {
+            return Err(format!(
+                "pre-start command {shown:?} did not finish within {}ms",
+                timeout.as_millis()
+            ));
+        }
+    };
+    let text = [&output.stdout, &output.stderr]
+        .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+        .join("");
+    if !output.status.success() {
+        let lines: Vec<&str> = text.lines().collect();
+        let tail = lines[lines.len().saturating_sub(LOG_TAIL_LINES)..].join("\n");
+        return Err(format!(
+            "pre-start command {shown:?} exited with {}: {tail}",
+            output.status
+        ));
+    }
+    for line in text.lines() {
+        tracing::debug!(line, "pre-start output");
+    }
+    tracing::info!(elapsed = ?start.elapsed(), "pre-start command finished");
+    Ok(())
+}
+
+/// Starts instance `index` on its own port (or the configured socket).
+fn spawn_instance(cfg: &Config, index: usize) -> Result<Backend, String> {
+    let configured = match cfg.core.port {
+        0 => 0,
+        // `validate_config` checked that the whole range fits.
+        first => first + index as u16,
+    };
+    if cfg.process.no_spawn() {
+        return Ok(Backend {
+            index,
+            port: configured,
+            child: None,
+            adopted: None,
+            ready: false,
+            announced: None,
+        });
+    }
+
+    let go_path = cfg.process.command_path(&cfg.core.dist_dir);
+    let mut command = match &cfg.process.working_dir {
+        Some(dir) => {
+            // A relative program path would otherwise be looked up from the
+            // new directory.
+            let absolute = std::path::absolute(&go_path)
+                .map_err(|e| format!("cannot resolve {}: {e}", go_path.display()))?;
+            let mut command = Command::new(absolute);
+            command.current_dir(dir);
+            command
+        }
+        None => Command::new(&go_path),
+    };
+    for (key, value) in &cfg.process.env {
+        command.env(key, interpolate_env(value));
+    }
+    if let Some(signal) = &cfg.watch.ready_signal {
+        match std::fs::symlink_metadata(signal) {
+            Ok(meta) if !is_fifo(&meta) => match std::fs::remove_file(signal) {
+                Ok(()) => tracing::debug!(path = %signal, "removed stale ready signal"),
+                Err(e) => return Err(format!("failed to remove stale ready signal {signal}: {e}")),
+            },
+            _ => {}
+        }
+        command.env(READY_FILE_ENV, signal);
+    }
+    let mut port = 0;
+    if let Some(socket) = &cfg.core.backend_socket {
+        match std::fs::remove_file(socket) {
+            Ok(()) => tracing::debug!(path = %socket, "removed stale backend socket"),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("failed to remove stale socket {socket}: {e}")),
+        }
+        command.env(SOCKET_PATH_ENV, socket);
+    } else {
+        port = match cfg.core.port_range {
+            Some(range) => scan_port_range(&cfg.core.backend_host, range, index)?,
+            None => resolve_port(&cfg.core.backend_host, configured)?,
+        };
+        command
+            .env("PORT", port.to_string())
+            .env("HOST", &cfg.core.backend_host);
+    }
+    command.args(expand_args(&cfg.process.args, port, &cfg.core.backend_host));
+
+    let stdio = |piped| {
+        if piped {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        }
+    };
+    let watch_stdout = cfg.process.capture_logs || cfg.process.port_pattern.is_some();
+    let mut child = command
+        .stdout(stdio(watch_stdout))
+        .stderr(stdio(cfg.process.capture_logs))
+        .spawn()
+        .map_err(|e| {
+            format!(
+                "spawn of {} failed ({:?}): {e}",
+                go_path.display(),
+                e.kind()
+            )
+        })?;
+    let keep = cfg
+        .process
+        .capture_logs
+        .then_some(cfg.process.capture_log_lines);
+    let mut announced = None;
+    if let Some(out) = child.stdout.take() {
+        let announce = cfg.process.port_pattern.clone().map(|pattern| {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            announced = Some(rx);
+            (pattern, tx)
+        });
+        capture_output(out, LogStream::Stdout, keep, announce);
+    }
+    if let Some(err) = child.stderr.take() {
+        capture_output(err, LogStream::Stderr, keep, None);
+    }
+    let shown_port = cfg.core.backend_socket.is_none().then_some(port);
+    tracing::info!(pid = child.id(), instance = index, port = shown_port, path = %go_path.display(), "spawned go backend");
+    METRICS.spawns.fetch_add(1, Ordering::Relaxed);
+    LAST_SPAWN_MS.store(unix_millis(), Ordering::Relaxed);
+
+    Ok(Backend {
+        index,
+        port,
+        child: Some(child),
+        adopted: None,
+        ready: false,
+        announced,
+    })
+}
+
+/// Waits up to `PortPatternTimeoutMs` for instance `index` to print its
+/// port and points it there; otherwise it stays on `port`. Returns the port
+/// to health-check.
+async fn announced_port(cfg: &Config, index: usize, port: u16) -> u16 {
+    let announced = GO
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter_mut()
+        .find(|b| b.index == index)
+        .and_then(|b| b.announced.take());
+    let Some(announced) = announced else {
+        return port;
+    };
+    let timeout = Duration::from_millis(cfg.process.port_pattern_timeout_ms);
+    let Ok(Ok(found)) = tokio::time::timeout(timeout, announced).await else {
+        tracing::warn!(
+            instance = index,
+            port,
+            ?timeout,
+            "backend did not print a port matching PortPattern, using the configured one"
+        );
+        return port;
+    };
+    if let Some(backend) = GO
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter_mut()
+        .find(|b| b.index == index)
+    {
+        backend.port = found;
+    }
+    tracing::info!(instance = index, port = found, "backend announced its port");
+    found
+}
+
+/// Polls the readiness endpoint of the instance on `port` until it passes
+/// or `deadline` is reached, returning the number of polls it took. Gives
+/// up early once `instance` exits; `None` is a backend not (yet) in `GO`,
+/// such as one about to be adopted.
+async fn wait_healthy(
+    cfg: &Config,
+    instance: Option<usize>,
+    port: u16,
+    deadline: Instant,
+) -> Result<u32, String> {
+    if let Some(signal) = &cfg.watch.ready_signal {
+        return wait_ready_signal(cfg, Path::new(signal), instance, port, deadline).await;
+    }
+    let health = cfg.watch.readiness_endpoint();
+    let path = hyper::http::uri::PathAndQuery::from_str(health)
+        .map_err(|e| format!("invalid health endpoint {health:?}: {e}"))?;
+    let uri = backend_uri(cfg, port, path).map_err(|e| format!("invalid health check URI: {e}"))?;
+    let start = Instant::now();
+    let mut poll = Duration::from_millis(cfg.watch.healthcheck_poll_ms);
+    let max_poll = Duration::from_millis(cfg.watch.healthcheck_poll_max_ms);
+
+    let mut polls = 0u32;
+    // Since when the backend has been answering, but with a status saying
+    // the health route does not exist.
+    let mut missing_since = None;
+    while Instant::now() < deadline {
+        let req = hyper::Request::builder()
+            .uri(uri.clone())
+            .body(Empty::new())
+            .unwrap();
+
+        polls += 1;
+        let result = health_client().request(req).await;
+        tracing::debug!(
+            poll = polls,
+            port,
+            elapsed = ?start.elapsed(),
+            status = result.as_ref().ok().map(|res| res.status().as_u16()),
+            error = result.as_ref().err().map(tracing::field::display),
+            "health check poll"
+        );
+
+        let is_healthy = match result {
+            Ok(res) if is_healthy_status(cfg, res.status()) => {
+                missing_since = None;
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                health_body_matches(cfg, res, remaining).await
+            }
+            Ok(res) if is_missing_route(res.status()) => {
+                let since = *missing_since.get_or_insert_with(Instant::now);
+                if since.elapsed() >= HEALTH_MISCONFIG_WINDOW {
+                    let field = if cfg.watch.readiness_endpoint.is_some() {
+                        "ReadinessEndpoint"
+                    } else {
+                        "HealthcheckEndpoint"
+                    };
+                    tracing::error!(
+                        status = res.status().as_u16(),
+                        endpoint = health,
+                        port,
+                        "health endpoint missing"
+                    );
+                    return Err(format!(
+                        "backend up but health endpoint {health} returned {} \u{2014} check {field}",
+                        res.status().as_u16()
+                    ));
+                }
+                false
+            }
+            _ => {
+                missing_since = None;
+                false
+            }
+        };
+
+        if is_healthy {
+            return Ok(polls);
+        }
+        if let Some(status) = instance.and_then(startup_exit) {
+            tracing::error!(%status, port, "go exited during startup");
+            return Err(format!("backend exited during startup ({status})"));
+        }
+        let wait = jittered(poll, cfg.watch.jitter);
+        sleep(wait.min(deadline.saturating_duration_since(Instant::now()))).await;
+        poll = poll
+            .mul_f64(cfg.watch.healthcheck_poll_factor)
+            .min(max_poll);
+    }
+
+    tracing::error!(timeout = ?cfg.watch.healthcheck_timeout(), polls, port, "health check timed out");
+    Err("health check timed out".into())
+}
+
+/// `delay` scaled by a random factor in `1 ± jitter`.
+fn jittered(delay: Duration, jitter: f64) -> Duration {
+    if jitter <= 0.0 {
+        return delay;
+    }
+    // Every `RandomState` gets fresh keys, which is random enough here.
+    let bits = std::hash::BuildHasher::hash_one(&std::hash::RandomState::new(), ());
+    let unit = (bits >> 11) as f64 / (1u64 << 53) as f64;
+    delay.mul_f64(1.0 + jitter * (2.0 * unit - 1.0))
+}
+
+/// `Watch.ReadySignal` in place of the HTTP health loop, polled every
+/// `HealthcheckPollMs`.
+async fn wait_ready_signal(
+    cfg: &Config,
+    path: &Path,
+    instance: Option<usize>,
+    port: u16,
+    deadline: Instant,
+) -> Result<u32, String> {
+    let poll = Duration::from_millis(cfg.watch.healthcheck_poll_ms);
+    let mut signal = ReadySignal::default();
+    let mut polls = 0u32;
+    while Instant::now() < deadline {
+        polls += 1;
+        if signal.seen(path) {
+            return Ok(polls);
+        }
+        if let Some(status) = instance.and_then(startup_exit) {
+            tracing::error!(%status, port, "go exited during startup");
+            return Err(format!("backend exited during startup ({status})"));
+        }
+        let wait = jittered(poll, cfg.watch.jitter);
+        sleep(wait.min(deadline.saturating_duration_since(Instant::now()))).await;
+    }
+    tracing::error!(timeout = ?cfg.watch.healthcheck_timeout(), polls, path = %path.display(), "ready signal did not arrive");
+    Err(format!(
+        "ready signal {} did not arrive in time",
+        path.display()
+    ))
+}
+
+/// State kept across polls of a ready signal. A named pipe is opened
+/// non-blocking on the first poll, so a child writing to it is not stuck
+/// waiting for a reader, and read until a full line arrives.
+#[derive(Default)]
+struct ReadySignal {
+    #[cfg(unix)]
+    pipe: Option<std::fs::File>,
+    #[cfg(unix)]
+    line: Vec<u8>,
+}
+
+impl ReadySignal {
+    fn seen(&mut self, path: &Path) -> bool {
+        let Ok(meta) = std::fs::metadata(path) else {
+            return false;
+        };
+        if !is_fifo(&meta) {
+            return true;
+        }
+        #[cfg(unix)]
+        {
+            use std::io::Read;
+            use std::os::unix::fs::OpenOptionsExt;
+
+            if self.pipe.is_none() {
+                self.pipe = std::fs::OpenOptions::new()
+                    .read(true)
+                    .custom_flags(libc::O_NONBLOCK)
+                    .open(path)
+                    .ok();
+            }
+            let Some(pipe) = self.pipe.as_mut() else {
+                return false;
+            };
+            let mut buf = [0u8; 256];
+            // `Ok(0)` means no writer has the pipe open (yet); `WouldBlock`
+            // that one does but has not written.
+            while let Ok(n @ 1..) = pipe.read(&mut buf) {
+                self.line.extend_from_slice(&buf[..n]);
+            }
+            self.line.contains(&b'\n')
+        }
+        #[cfg(not(unix))]
+        false
+    }
+}
+
+fn is_fifo(meta: &std::fs::Metadata) -> bool {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileTypeExt::is_fifo(&meta.file_type())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = meta;
+        false
+    }
+}
+
+/// How instance `index` ended, if it exited before passing its health
+/// check. An instance the supervisor already reaped counts as exited.
+fn startup_exit(index: usize) -> Option<String> {
+    let mut guard = GO.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(i) = guard.iter().position(|b| b.index == index) else {
+        return Some("reaped".into());
+    };
+    let status = guard[i].child.as_mut()?.try_wait().ok()??;
+    record_exit(guard.remove(i).index, status);
+    Some(status.to_string())
+}
+
+/// Replaces a single instance that died or stopped answering while the
+/// others keep serving. Gives up (leaving the instance out of rotation) once
+/// `MaxRestarts` is exceeded or the replacement fails its health check.
+async fn respawn(index: usize) {
+    let _lock = INIT_LOCK.lock().await;
+    let cfg = config();
+    // A full restart is already pending, or we are shutting down.
+    if !READY.load(Ordering::Acquire) || DRAINING.load(Ordering::Acquire) {
+        return;
+    }
+    if GO
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .any(|b| b.index == index)
+    {
+        return;
+    }
+    let restarts = RESTARTS.load(Ordering::Acquire);
+    if restarts > cfg.process.max_restarts {
+        tracing::error!(
+            instance = index,
+            crashes = restarts,
+            max_restarts = cfg.process.max_restarts,
+            "not replacing go instance, MaxRestarts exceeded"
+        );
+        return;
+    }
+
+    let backend = match spawn_instance(&cfg, index) {
+        Ok(backend) => backend,
+        Err(e) => {
+            tracing::error!(instance = index, error = %e, "failed to replace go instance");
+            return;
+        }
+    };
+    let port = backend.port;
+    GO.lock().unwrap_or_else(|e| e.into_inner()).push(backend);
+    let port = announced_port(&cfg, index, port).await;
+
+    let deadline = Instant::now() + cfg.watch.healthcheck_timeout();
+    let healthy = wait_healthy(&cfg, Some(index), port, deadline)
+        .await
+        .is_ok();
+    let failed = {
+        let mut guard = GO.lock().unwrap_or_else(|e| e.into_inner());
+        // The supervisor may already have reaped it if it crashed meanwhile.
+        let Some(i) = guard.iter().position(|b| b.index == index) else {
+            return;
+        };
+        if healthy {
+            guard[i].ready = true;
+            tracing::info!(instance = index, port, "go instance replaced");
+            return;
+        }
+        guard.remove(i)
+    };
+    let grace = Duration::from_millis(cfg.process.shutdown_grace_ms);
+    if let Some(child) = failed.child {
+        terminate(child, grace).await;
+    }
+}
+
+/// Echoes a child pipe to our own stdout/stderr while keeping the last
+/// `keep` lines in `BACKEND_LOGS`, and sends the port from the first line
+/// matching the `announce` pattern.
+fn capture_output<R>(
+    pipe: R,
+    stream: LogStream,
+    keep: Option<usize>,
+    mut announce: Option<(regex::Regex, tokio::sync::oneshot::Sender<u16>)>,
+) where
+    R: std::io::Read + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        use std::io::{BufRead, Write};
+
+        let mut reader = std::io::BufReader::new(pipe);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            let _ = match stream {
+                LogStream::Stdout => std::io::stdout().write_all(&buf),
+                LogStream::Stderr => std::io::stderr().write_all(&buf),
+            };
+
+            let line = String::from_utf8_lossy(&buf).trim_end().to_string();
+            if let Some((pattern, _)) = &announce
+                && let Some(port) = pattern
+                    .captures(&line)
+                    .and_then(|c| c.get(1)?.as_str().parse::<u16>().ok())
+                && let Some((_, tx)) = announce.take()
+            {
+                let _ = tx.send(port);
+            }
+            let Some(limit) = keep else {
+                continue;
+            };
+            let mut logs = BACKEND_LOGS.lock().unwrap_or_else(|e| e.into_inner());
+            while logs.len() >= limit {
+                logs.pop_front();
+            }
+            logs.push_back(LogLine { stream, line });
+        }
+    });
+}
+
+/// The last `n` captured lines, optionally from a single stream.
+fn recent_backend_logs(n: usize, stream: Option<LogStream>) -> Vec<String> {
+    let logs = BACKEND_LOGS.lock().unwrap_or_else(|e| e.into_inner());
+    let mut lines: Vec<String> = logs
+        .iter()
+        .rev()
+        .filter(|l| stream.is_none_or(|s| s == l.stream))
+        .take(n)
+        .map(|l| l.line.clone())
+        .collect();
+    lines.reverse();
+    lines
+}
+
+/// With `HealthcheckExpectBody` set, a matching status is not enough: the
+/// body must contain the marker too, so a stranger squatting on the port
+/// can't pass for our backend.
+async fn health_body_matches(
+    cfg: &Config,
+    res: hyper::Response<Incoming>,
+    remaining: Duration,
+) -> bool {
+    let Some(expected) = &cfg.watch.healthcheck_expect_body else {
+        return true;
+    };
+    let body = Limited::new(res.into_body(), MAX_HEALTH_BODY_BYTES).collect();
+    match tokio::time::timeout(remaining, body).await {
+        Ok(Ok(collected)) => {
+            let matched =
+                String::from_utf8_lossy(&collected.to_bytes()).contains(expected.as_str());
+            if !matched {
+                tracing::debug!(expected, "health check body did not match");
+            }
+            matched
+        }
+        _ => false,
+    }
+}
+
+/// A backend that keeps answering these on the health endpoint is running but
+/// has no such route, which waiting out the timeout won't fix.
+fn is_missing_route(status: hyper::StatusCode) -> bool {
+    status == hyper::StatusCode::NOT_FOUND || status == hyper::StatusCode::METHOD_NOT_ALLOWED
+}
+
+fn is_healthy_status(cfg: &Config, status: hyper::StatusCode) -> bool {
+    match &cfg.watch.healthcheck_expect_status {
+        Some(expected) => expected.contains(&status.as_u16()),
+        None => status.is_success(),
+    }
+}
+
+/// Watches for the child exiting on its own (panic, OOM) between requests so
+/// the next request respawns it instead of proxying into a dead port.
+/// Reaps the backend as soon as it exits, so a crash is noticed (and logged)
+/// without waiting for a request to fail and no zombie lingers in a warm
+/// container. SIGCHLD wakes it immediately on Unix; the interval is a
+/// fallback. Children are only touched under `GO`'s lock, the same one
+/// `kill_child` takes them out with, so the two never wait on the same
+/// process. With several instances, the survivors keep serving while a dead
+/// one is replaced.
+async fn supervise() {
+    let mut interval = tokio::time::interval(SUPERVISE_INTERVAL);
+    #[cfg(unix)]
+    let mut sigchld = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::child()).ok();
+    loop {
+        #[cfg(unix)]
+        match sigchld.as_mut() {
+            Some(sig) => {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = sig.recv() => {}
+                }
+            }
+            None => {
+                interval.tick().await;
+            }
+        }
+        #[cfg(not(unix))]
+        interval.tick().await;
+
+        reap_child();
+        refresh_reuse_marker(&config());
+    }
+}
+
+fn reap_child() {
+    let (exited, remaining) = {
+        let mut guard = GO.lock().unwrap_or_else(|e| e.into_inner());
+        let mut exited = Vec::new();
+        guard.retain_mut(|b| match b.child.as_mut().map(Child::try_wait) {
+            Some(Ok(Some(status))) => {
+                exited.push((b.index, Some(status)));
+                false
+            }
+            // An adopted backend leaves no exit status behind, only a pid
+            // that no longer answers.
+            None if b.adopted.is_some_and(|pid| !process_alive(pid)) => {
+                exited.push((b.index, None));
+                false
+            }
+            None | Some(Ok(None)) => true,
+            Some(Err(e)) => {
+                tracing::warn!(instance = b.index, error = %e, "failed to poll go backend status");
+                true
+            }
+        });
+        (exited, guard.len())
+    };
+
+    let cfg = config();
+    for (index, status) in exited {
+        let restarts = count_crash(&cfg);
+        match status {
+            Some(status) => {
+                record_exit(index, status);
+                tracing::error!(%status, instance = index, crashes = restarts, "go exited unexpectedly");
+            }
+            None => {
+                tracing::error!(
+                    instance = index,
+                    crashes = restarts,
+                    "adopted go exited unexpectedly"
+                );
+            }
+        }
+        if remaining > 0 {
+            tokio::spawn(respawn(index));
+        }
+    }
+    if remaining == 0 {
+        READY.store(false, Ordering::Release);
+    }
+}
+
+/// Stops the backend once nothing has arrived for `IdleTimeoutMs` and no
+/// response is still streaming; the next request respawns it.
+async fn watch_idle() {
+    let cfg = config();
+    let Some(timeout) = cfg.process.idle_timeout_ms.map(Duration::from_millis) else {
+        return;
+    };
+    if cfg.process.no_spawn() {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL.min(timeout));
+    loop {
+        interval.tick().await;
+        if !READY.load(Ordering::Acquire) {
+            continue;
+        }
+        let idle = |since: u64| uptime().saturating_sub(Duration::from_millis(since));
+        if idle(LAST_REQUEST_MS.load(Ordering::Relaxed)) < timeout {
+            continue;
+        }
+        // Taken so a request that is starting the backend right now is not
+        // undercut; the idle time is checked again under it.
+        let _lock = INIT_LOCK.lock().await;
+        let last = LAST_REQUEST_MS.load(Ordering::Relaxed);
+        if idle(last) < timeout || METRICS.in_flight.load(Ordering::Relaxed) > 0 {
+            continue;
+        }
+        tracing::info!(idle = ?idle(last), "backend idle, stopping it");
+        READY.store(false, Ordering::Release);
+        kill_child().await;
+    }
+}
+
+/// Polls `LivenessEndpoint` on every ready instance and restarts one that
+/// stays alive but stops answering. Skipped while a start or restart holds
+/// `INIT_LOCK`, for a backend the proxy did not spawn and cannot restart,
+/// and under `vc dev`, where a backend paused in a debugger looks hung. The
+/// settings are re-read every round so config reloads apply.
+async fn watch_liveness() {
+    if config().process.no_spawn() || is_vercel_dev() {
+        return;
+    }
+    // Consecutive failures per instance index.
+    let mut failures: Vec<u32> = Vec::new();
+    loop {
+        let cfg = config();
+        tokio::select! {
+            _ = sleep(Duration::from_millis(cfg.watch.liveness_interval_ms)) => {}
+            _ = LIVENESS_CHECK.notified() => {}
+        }
+        let Some(endpoint) = &cfg.watch.liveness_endpoint else {
+            continue;
+        };
+        let Ok(path) = hyper::http::uri::PathAndQuery::from_str(endpoint) else {
+            continue;
+        };
+        failures.resize(cfg.process.instances, 0);
+        if !READY.load(Ordering::Acquire) {
+            failures.fill(0);
+            continue;
+        }
+        let timeout = Duration::from_millis(cfg.watch.liveness_timeout_ms);
+
+        let ready: Vec<(usize, u16, Option<u32>)> = GO
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|b| b.ready)
+            .map(|b| (b.index, b.port, b.pid()))
+            .collect();
+        for (index, port, pid) in ready {
+            let Ok(uri) = backend_uri(&cfg, port, path.clone()) else {
+                continue;
+            };
+            let req = hyper::Request::builder()
+                .uri(uri)
+                .body(Empty::new())
+                .unwrap();
+            let alive = tokio::time::timeout(timeout, health_client().request(req))
+                .await
+                .is_ok_and(|res| res.is_ok_and(|res| res.status().is_success()));
+
+            if alive {
+                failures[index] = 0;
+                continue;
+            }
+            failures[index] += 1;
+            tracing::warn!(
+                failures = failures[index],
+                instance = index,
+                endpoint,
+                "liveness check failed"
+            );
+            if failures[index] >= cfg.watch.liveness_failure_threshold {
+                failures[index] = 0;
+                // Probing ran unlocked, so a restart may already have
+                // replaced the instance; only the one that was probed is
+                // given up on.
+                let _lock = INIT_LOCK.lock().await;
+                let probed = READY.load(Ordering::Acquire)
+                    && GO
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .iter()
+                        .any(|b| b.index == index && b.ready && b.pid() == pid);
+                if !probed {
+                    continue;
+                }
+                lose_instance(index, true);
+            }
+        }
+    }
+}
+
+fn is_hop_by_hop_header(name: &str) -> bool {
+    matches!(
+        name.to_lowercase().as_str(),
+        "connection"
+            | "keep-alive"
+            | "proxy-authenticate"
+            | "proxy-authorization"
+            | "te"
+            | "trailers"
+            | "transfer-encoding"
+            | "upgrade"
+            | "host"
+    )
+}
+
+/// Header names listed in `Connection` (RFC 9110 §7.6.1), lowercased. These
+/// apply to a single hop and must not be forwarded.
+fn connection_tokens(headers: &hyper::HeaderMap) -> Vec<String> {
+    headers
+        .get_all(hyper::header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+async fn handler(mut req: Request) -> Result<Response<ResponseBody>, Error> {
+    let cfg = config();
+    let header = hyper::header::HeaderName::try_from(&cfg.observability.request_id_header)
+        .unwrap_or(hyper::header::HeaderName::from_static(
+            DEFAULT_REQUEST_ID_HEADER,
+        ));
+    let request_id = match req.headers().get(&header) {
+        Some(id) if !id.is_empty() => id.clone(),
+        _ => {
+            let id = hyper::header::HeaderValue::from_str(&uuid::Uuid::new_v4().to_string())?;
+            req.headers_mut().insert(&header, id.clone());
+            id
+        }
+    };
+
+    let span = tracing::info_span!(
+        "request",
+        method = %req.method(),
+        path = req.uri().path(),
+        request_id = request_id.to_str().unwrap_or_default(),
+    );
+    METRICS.requests.fetch_add(1, Ordering::Relaxed);
+    LAST_REQUEST_MS.store(uptime().as_millis() as u64, Ordering::Relaxed);
+    let start = Instant::now();
+    let origin = req.headers().get(hyper::header::ORIGIN).cloned();
+    let preflight = is_preflight(&req);
+    let plain_errors = prefers_plain_text(req.headers());
+    let access = sample_access_log(cfg.observability.access_log_sample_rate).then(|| AccessEntry {
+        method: req.method().to_string(),
+        path: req.uri().path().to_string(),
+        request_id: request_id.to_str().unwrap_or_default().to_string(),
+        status: 0,
+        start,
+        ttfb: Duration::ZERO,
+        total: Duration::ZERO,
+        upstream: None,
+    });
+    let mut response = proxy(req).instrument(span).await?;
+    METRICS.record_response(response.status().as_u16());
+    if let Some(error) = response.extensions_mut().remove::<ErrorBody>()
+        && plain_errors
+    {
+        response.headers_mut().insert(
+            hyper::header::CONTENT_TYPE,
+            hyper::header::HeaderValue::from_static("text/plain; charset=utf-8"),
+        );
+        response.headers_mut().remove(hyper::header::CONTENT_LENGTH);
+        *response.body_mut() = ResponseBody::from(error.to_text());
+    }
+    if let Some((name, _)) = backend_secret() {
+        response.headers_mut().remove(name);
+    }
+    inject_headers(response.headers_mut(), &cfg.http.response_headers);
+    if let Some(cors) = &cfg.cors
+        && !preflight
+    {
+        cors.decorate(response.headers_mut(), origin.as_ref())?;
+    }
+    if cfg.observability.server_timing {
+        let cold_start = COLD_START_MS.swap(0, Ordering::Relaxed);
+        if cold_start > 0 {
+            let timing = format!("cold-start;dur={cold_start}");
+            response.headers_mut().append(
+                "server-timing",
+                hyper::header::HeaderValue::from_str(&timing)?,
+            );
+        }
+    }
+    response.headers_mut().insert(header, request_id);
+    if cfg.http.close_on_drain && DRAINING.load(Ordering::Acquire) {
+        response.headers_mut().insert(
+            hyper::header::CONNECTION,
+            hyper::header::HeaderValue::from_static("close"),
+        );
+    }
+    if let Some(mut entry) = access {
+        entry.status = response.status().as_u16();
+        entry.ttfb = start.elapsed();
+        entry.upstream = response.extensions().get::<UpstreamLatency>().map(|l| l.0);
+        return Ok(response.map(|body| {
+            ResponseBody(
+                AccessLogBody {
+                    inner: body.0,
+                    entry,
+                }
+                .boxed(),
+            )
+        }));
+    }
+    Ok(response)
+}
+
+/// Picks `rate` of all calls, evenly spaced rather than at random.
+fn sample_access_log(rate: f64) -> bool {
+    static SEEN: AtomicU64 = AtomicU64::new(0);
+    if rate <= 0.0 {
+        return false;
+    }
+    if rate >= 1.0 {
+        return true;
+    }
+    let n = SEEN.fetch_add(1, Ordering::Relaxed) as f64;
+    ((n + 1.0) * rate).floor() > (n * rate).floor()
+}
+
+/// Time the backend took to send its response headers, attached to the
+/// response for the access log.
+#[derive(Clone, Copy)]
+struct UpstreamLatency(Duration);
+
+struct AccessEntry {
+    method: String,
+    path: String,
+    request_id: String,
+    status: u16,
+    start: Instant,
+    ttfb: Duration,
+    total: Duration,
+    upstream: Option<Duration>,
+}
+
+impl AccessEntry {
+    /// Fills in the placeholders in one pass, so a path that happens to
+    /// contain one is not expanded again.
+    fn render(&self, format: &str) -> String {
+        let ms = |d: Duration| format!("{:.1}", d.as_secs_f64() * 1000.0);
+        let mut out = String::with_capacity(format.len() + self.path.len());
+        let mut rest = format;
+        while let Some((before, after)) = rest.split_once('{') {
+            out.push_str(before);
+            let Some((name, tail)) = after.split_once('}') else {
+                out.push('{');
+                rest = after;
+                break;
+            };
+            match name {
+                "method" => out.push_str(&self.method),
+                "path" => out.push_str(&self.path),
+                "status" => out.push_str(&self.status.to_string()),
+                "request_id" => out.push_str(&self.request_id),
+                "ttfb_ms" => out.push_str(&ms(self.ttfb)),
+                "duration_ms" => out.push_str(&ms(self.total)),
+                "upstream_ms" => out.push_str(&self.upstream.map_or("-".into(), ms)),
+                _ => {
+                    out.push('{');
+                    out.push_str(name);
+                    out.push('}');
+                }
+            }
+            rest = tail;
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+/// Writes the access log line once the response body is done with, whether
+/// it finished or the client went away. The total is taken at the last
+/// frame, since the server may hold on to the body a little longer.
+struct AccessLogBody {
+    inner: ProxyBody,
+    entry: AccessEntry,
+}
+
+impl Drop for AccessLogBody {
+    fn drop(&mut self) {
+        if self.entry.total.is_zero() {
+            self.entry.total = self.entry.start.elapsed();
+        }
+        let line = self.entry.render(&config().observability.access_log_format);
+        tracing::info!(target: "proxy::access", "{line}");
+    }
+}
+
+impl hyper::body::Body for AccessLogBody {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        let done = match &poll {
+            Poll::Ready(None | Some(Err(_))) => true,
+            Poll::Ready(Some(Ok(_))) => self.inner.is_end_stream(),
+            Poll::Pending => false,
+        };
+        if done && self.entry.total.is_zero() {
+            self.entry.total = self.entry.start.elapsed();
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+async fn proxy(req: Request) -> Result<Response<ResponseBody>, Error> {
+    let cfg = config();
+    if cfg.observability.metrics && req.uri().path() == cfg.observability.metrics_endpoint {
+        return Ok(Response::builder()
+            .status(200)
+            .header("content-type", "text/plain; version=0.0.4")
+            .body(ResponseBody::from(METRICS.render()))?);
+    }
+    if DRAINING.load(Ordering::Acquire) {
+        return error_response(ProxyError::ShuttingDown, "proxy is shutting down".into());
+    }
+    if req.uri().path() == cfg.observability.health_endpoint {
+        return health_status();
+    }
+    if let Some(token) = admin_token()
+        && req.uri().path() == cfg.observability.restart_endpoint
+    {
+        return admin_restart(&req, token);
+    }
+    if let Some(cors) = &cfg.cors
+        && is_preflight(&req)
+    {
+        return cors.preflight(req.headers());
+    }
+
+    if let Some(reason) = cfg.http.header_limit_refusal(req.headers()) {
+        return error_response(ProxyError::HeadersTooLarge, reason);
+    }
+
+    // Only origin-form targets (`/path?query`) make sense to forward. The
+    // asterisk form (`OPTIONS *`), anything else without a leading slash,
+    // and `CONNECT` (whose authority-form target would have the backend's
+    // 2xx taken for an open tunnel) are refused before they can wake the
+    // backend; any other target with no path falls back to `/`.
+    let path = req
+        .uri()
+        .path_and_query()
+        .cloned()
+        .unwrap_or_else(|| hyper::http::uri::PathAndQuery::from_static("/"));
+    if req.method() == hyper::Method::CONNECT || !path.path().starts_with('/') {
+        return error_response(
+            ProxyError::BadRequest,
+            format!("unsupported request target {:?}", req.uri().to_string()),
+        );
+    }
+    if let Some(methods) = cfg.http.allowed_methods(path.path())
+        && !is_allowed_method(methods, req.method())
+    {
+        let allow = allow_header(methods);
+        let mut response = error_response(
+            ProxyError::MethodNotAllowed,
+            format!("{} is not allowed here; allowed: {allow}", req.method()),
+        )?;
+        if let Ok(value) = hyper::header::HeaderValue::from_str(&allow) {
+            response.headers_mut().insert(hyper::header::ALLOW, value);
+        }
+        return Ok(response);
+    }
+    if let Some(reason) = cfg.http.content_type_refusal(req.headers()) {
+        return error_response(ProxyError::UnsupportedMediaType, reason);
+    }
+
+    if let Some(dir) = &cfg.core.static_dir
+        && let Some(response) = serve_static(&req, dir).await
+    {
+        return response;
+    }
+
+    if cfg.http.is_not_found_path(path.path()) {
+        return Ok(Response::builder()
+            .status(cfg.http.not_found_status)
+            .header("content-type", &cfg.http.not_found_content_type)
+            .body(ResponseBody::from(cfg.http.not_found_body.clone()))?);
+    }
+
+    let route = cfg.http.route_settings(path.path(), req.headers());
+    let path = match rewrite_path(&cfg, path) {
+        Some(Ok(path)) => path,
+        Some(Err(e)) => {
+            return error_response(
+                ProxyError::BadRequest,
+                format!("rewritten path is invalid: {e}"),
+            );
+        }
+        None => {
+            return error_response(
+                ProxyError::NotFound,
+                format!(
+                    "path is outside {:?}",
+                    cfg.http.strip_path_prefix.as_deref().unwrap_or_default()
+                ),
+            );
+        }
+    };
+
+    // Looked up before the breaker and readiness checks, so a hit is served
+    // even while the backend is asleep or failing, and keyed by what the
+    // backend will be asked for. Any other method on a path makes what was
+    // cached for it stale.
+    let mut cache_fill = None;
+    if let Some(cache) = &cfg.cache {
+        let key = cache_key(&req, &path);
+        if !req.method().is_safe() {
+            let resource = key.split('?').next().unwrap_or_default();
+            CACHE
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .invalidate(resource);
+        } else if is_cacheable_request(req.method(), req.headers()) {
+            let head = req.method() == hyper::Method::HEAD;
+            let hit =
+                CACHE
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .get(&key, req.headers(), head);
+            if let Some(response) = hit {
+                METRICS.cache_hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(response);
+            }
+            METRICS.cache_misses.fetch_add(1, Ordering::Relaxed);
+            if !head {
+                cache_fill = Some((cache, key, req.headers().clone()));
+            }
+        }
+    }
+
+    if let Err(wait) = BREAKER.allow(&cfg) {
+        return error_response(
+            ProxyError::CircuitOpen,
+            format!(
+                "backend is failing, circuit open for another {}ms",
+                wait.as_millis()
+            ),
+        );
+    }
+
+    if let Err(e) = ready_within(&cfg, Duration::from_millis(cfg.process.restart_wait_ms)).await {
+        BREAKER.record_failure(&cfg);
+        return startup_failed(&cfg, req.headers(), e);
+    }
+
+    let session = cfg.load_balancing.session_key(req.headers());
+    let Some((instance, port)) = next_backend(&cfg, session) else {
+        return error_response(
+            ProxyError::UpstreamError,
+            "no backend instance is running".into(),
+        );
+    };
+    let uri = match backend_uri(&cfg, port, path) {
+        Ok(uri) => uri,
+        Err(e) => {
+            return error_response(
+                ProxyError::BadRequest,
+                format!("cannot forward request target: {e}"),
+            );
+        }
+    };
+
+    let Some(slot) = UpstreamSlot::acquire(&cfg).await else {
+        return error_response(
+            ProxyError::Overloaded,
+            "too many concurrent requests to the backend".into(),
+        );
+    };
+    let response = if cfg.watch.proxy_websockets && is_websocket_upgrade(req.headers()) {
+        proxy_upgrade(req, uri, instance, &cfg, &route).await?
+    } else {
+        forward(req, uri, instance, &cfg, &route).await?
+    };
+    let response = match cache_fill {
+        Some((cache, key, headers)) => fill_cache(response, key, &headers, cache),
+        None => response,
+    };
+    // The slot is released once the body finishes streaming, not when the
+    // headers arrive, since the backend is busy until then.
+    Ok(response.map(|body| {
+        ResponseBody(
+            SlotBody {
+                inner: body.0,
+                _slot: slot,
+                finished: false,
+                span: tracing::Span::current(),
+            }
+            .boxed(),
+        )
+    }))
+}
+
+async fn forward(
+    req: Request,
+    uri: hyper::Uri,
+    instance: usize,
+    cfg: &Config,
+    route: &RouteSettings,
+) -> Result<Response<ResponseBody>, Error> {
+    let (parts, body) = req.into_parts();
+    let headers = upstream_headers(&parts, cfg, false);
+    let backend = uri.authority().cloned();
+
+    if !route.buffer_body {
+        // The body is handed to the upstream connection as is, so hyper polls
+        // the client for the next frame only once the previous one has been
+        // written upstream; a slow backend stalls the upload instead of
+        // filling memory.
+        //
+        // Notes whether the client broke off the upload, so the resulting
+        // upstream error is not blamed on the backend.
+        let aborted = Arc::new(AtomicBool::new(false));
+        let flag = aborted.clone();
+        let body = body.map_err(move |e| {
+            flag.store(true, Ordering::Relaxed);
+            Error::from(e)
+        });
+        let body = CountingBody::wrap(body.boxed(), &METRICS.request_bytes);
+        let upstream = upstream_request(&parts.method, uri, headers, body);
+        return match send_upstream(upstream, route.timeout).await {
+            Ok(res) => stream_response(res, &parts, backend.as_ref()),
+            Err(_) if aborted.load(Ordering::Relaxed) => client_closed(),
+            Err(e) => upstream_failed(e, instance).await,
+        };
+    }
+
+    let max = cfg.http.max_body_bytes;
+    let body = match Limited::new(body, max).collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(e) if e.is::<http_body_util::LengthLimitError>() => {
+            return error_response(
+                ProxyError::PayloadTooLarge,
+                format!("request body exceeds {max} bytes"),
+            );
+        }
+        Err(e) => {
+            tracing::debug!(error = %e, "failed to read request body");
+            return client_closed();
+        }
+    };
+
+    let mut attempt = 0;
+    let result = loop {
+        let upstream = upstream_request(
+            &parts.method,
+            uri.clone(),
+            headers.clone(),
+            CountingBody::wrap(full(body.clone()), &METRICS.request_bytes),
+        );
+
+        match send_upstream(upstream, route.timeout).await {
+            Err(e) if e.is_connect() && attempt < route.retries => {
+                let delay = cfg
+                    .http
+                    .upstream_retry_delay_ms
+                    .saturating_mul(2u64.saturating_pow(attempt));
+                attempt += 1;
+                tracing::warn!(
+                    attempt,
+                    retries = route.retries,
+                    delay_ms = delay,
+                    "backend refused connection, retrying"
+                );
+                sleep(Duration::from_millis(delay)).await;
+            }
+            result => break result,
+        }
+    };
+
+    match result {
+        Ok(res) => stream_response(res, &parts, backend.as_ref()),
+        Err(e) => upstream_failed(e, instance).await,
+    }
+}
+
+/// Applies `StripPathPrefix`/`AddPathPrefix` and `StripQueryParams`.
+/// `None` means the path is outside the stripped prefix.
+fn rewrite_path(
+    cfg: &Config,
+    path: hyper::http::uri::PathAndQuery,
+) -> Option<Result<hyper::http::uri::PathAndQuery, hyper::http::uri::InvalidUri>> {
+    if cfg.http.strip_path_prefix.is_none()
+        && cfg.http.add_path_prefix.is_none()
+        && cfg.http.strip_query_params.is_empty()
+    {
+        return Some(Ok(path));
+    }
+
+    let mut rest = path.path();
+    if let Some(prefix) = &cfg.http.strip_path_prefix {
+        let prefix = prefix.trim_end_matches('/');
+        rest = rest.strip_prefix(prefix)?;
+        if !rest.is_empty() && !rest.starts_with('/') {
+            return None;
+        }
+    }
+    let add = cfg
+        .http
+        .add_path_prefix
+        .as_deref()
+        .map_or("", |p| p.trim_end_matches('/'));
+    let rest = if rest.is_empty() && add.is_empty() {
+        "/"
+    } else {
+        rest
+    };
+    let query = path
+        .query()
+        .and_then(|query| strip_query_params(cfg, query));
+    let rewritten = match query {
+        Some(query) => format!("{add}{rest}?{query}"),
+        None => format!("{add}{rest}"),
+    };
+    Some(hyper::http::uri::PathAndQuery::from_str(&rewritten))
+}
+
+/// `query` without the parameters `StripQueryParams` names, compared by
+/// their decoded name. `None` when nothing is left.
+fn strip_query_params<'a>(cfg: &Config, query: &'a str) -> Option<std::borrow::Cow<'a, str>> {
+    if cfg.http.strip_query_params.is_empty() {
+        return Some(query.into());
+    }
+    let kept: Vec<&str> = query
+        .split('&')
+        .filter(|param| {
+            let name = param.split_once('=').map_or(*param, |(name, _)| name);
+            let name = percent_decode(&name.replace('+', " "));
+            !name.is_some_and(|name| cfg.http.strips_query_param(&name))
+        })
+        .collect();
+    (!kept.iter().all(|param| param.is_empty())).then(|| kept.join("&").into())
+}
+
+/// Copies the inbound headers minus hop-by-hop ones. Upgrade handshakes keep
+/// `Connection`/`Upgrade` so the backend can complete them.
+fn upstream_headers(
+    parts: &hyper::http::request::Parts,
+    cfg: &Config,
+    upgrade: bool,
+) -> hyper::HeaderMap {
+    let listed = connection_tokens(&parts.headers);
+    let mut headers = hyper::HeaderMap::new();
+    for (k, v) in &parts.headers {
+        let name = k.as_str();
+        let keep = upgrade && (name == "connection" || name == "upgrade");
+        if keep || !(is_hop_by_hop_header(name) || listed.iter().any(|t| t == name)) {
+            headers.append(k, v.clone());
+        }
+    }
+    // `TE` is hop-by-hop, but `trailers` says the client takes them, which
+    // gRPC backends insist on and the proxy can honour.
+    let accepts_trailers = parts
+        .headers
+        .get_all(hyper::header::TE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case("trailers"));
+    if accepts_trailers {
+        headers.insert(
+            hyper::header::TE,
+            hyper::header::HeaderValue::from_static("trailers"),
+        );
+    }
+    // Without a `Host` header hyper fills in the backend's authority.
+    let host = match cfg.http.upstream_host.as_str() {
+        UPSTREAM_HOST_REWRITE => None,
+        UPSTREAM_HOST_PRESERVE => {
+            parts.headers.get(hyper::header::HOST).cloned().or_else(|| {
+                hyper::header::HeaderValue::from_str(parts.uri.authority()?.as_str()).ok()
+            })
+        }
+        literal => hyper::header::HeaderValue::from_str(literal).ok(),
+    };
+    if let Some(host) = host {
+        headers.insert(hyper::header::HOST, host);
+    }
+
+    if cfg.http.forwarded_headers {
+        let style = cfg.http.forwarded_style.as_str();
+        if style != FORWARDED_STYLE_RFC7239 {
+            apply_forwarded_headers(parts, &mut headers);
+        }
+        if style == FORWARDED_STYLE_RFC7239 || style == FORWARDED_STYLE_BOTH {
+            apply_rfc7239_forwarded(parts, &mut headers);
+        }
+        if cfg.http.forwarded_proto_version {
+            headers.insert(
+                "x-forwarded-proto-version",
+                hyper::header::HeaderValue::from_static(http_version_name(parts.version)),
+            );
+        }
+    }
+    inject_headers(&mut headers, &cfg.http.request_headers);
+    if let Some((name, value)) = backend_secret() {
+        headers.insert(name, value.clone());
+    }
+    headers
+}
+
+/// Header name and value of the proxy-to-backend secret, read from the
+/// environment once and marked sensitive so it is never printed.
+fn backend_secret() -> Option<&'static (hyper::header::HeaderName, hyper::header::HeaderValue)> {
+    static SECRET: OnceLock<Option<(hyper::header::HeaderName, hyper::header::HeaderValue)>> =
+        OnceLock::new();
+    SECRET
+        .get_or_init(|| {
+            let cfg = config();
+            let var = cfg.http.secret_env.as_ref()?;
+            let name = hyper::header::HeaderName::try_from(&cfg.http.secret_header).ok()?;
+            let mut value = hyper::header::HeaderValue::try_from(std::env::var(var).ok()?).ok()?;
+            value.set_sensitive(true);
+            Some((name, value))
+        })
+        .as_ref()
+}
+
+/// Applies configured static headers. Entries were validated at load time.
+fn inject_headers(headers: &mut hyper::HeaderMap, extra: &BTreeMap<String, String>) {
+    for (name, value) in extra {
+        if let (Ok(name), Ok(value)) = (
+            hyper::header::HeaderName::try_from(name),
+            hyper::header::HeaderValue::from_str(value),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+/// The proxy only ever sees Vercel's loopback bridge as its socket peer, so
+/// the real client address comes from the headers the platform sets.
+fn client_ip(headers: &hyper::HeaderMap) -> Option<String> {
+    ["x-vercel-forwarded-for", "x-real-ip"]
+        .iter()
+        .filter_map(|name| headers.get(*name)?.to_str().ok())
+        .filter_map(|value| value.split(',').next())
+        .map(str::trim)
+        .find(|ip| !ip.is_empty())
+        .map(str::to_owned)
+}
+
+fn apply_forwarded_headers(parts: &hyper::http::request::Parts, headers: &mut hyper::HeaderMap) {
+    use hyper::header::{HOST, HeaderValue};
+
+    let inbound = &parts.headers;
+    let chain = inbound
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let forwarded_for = match client_ip(inbound) {
+        Some(ip) if chain.rsplit(',').next().map(str::trim) == Some(ip.as_str()) => chain,
+        Some(ip) if chain.is_empty() => ip,
+        Some(ip) => format!("{chain}, {ip}"),
+        None => chain,
+    };
+    if !forwarded_for.is_empty()
+        && let Ok(value) = HeaderValue::from_str(&forwarded_for)
+    {
+        headers.insert("x-forwarded-for", value);
+    }
+
+    if let Ok(value) = HeaderValue::from_str(client_scheme(parts)) {
+        headers.insert("x-forwarded-proto", value);
+    }
+
+    let host = inbound
+        .get(HOST)
+        .cloned()
+        .or_else(|| HeaderValue::from_str(parts.uri.authority()?.as_str()).ok());
+    if let Some(host) = host {
+        headers.insert("x-forwarded-host", host);
+    }
+}
+
+/// Appends this hop to `Forwarded` as `for=...;proto=...;host=...`. A client
+/// address that isn't known is sent as `unknown`, as RFC 7239 section 6.2
+/// allows.
+fn apply_rfc7239_forwarded(parts: &hyper::http::request::Parts, headers: &mut hyper::HeaderMap) {
+    let node = match client_ip(&parts.headers) {
+        Some(ip) => match ip.parse::<std::net::IpAddr>() {
+            Ok(std::net::IpAddr::V6(ip)) => format!("[{ip}]"),
+            Ok(ip) => ip.to_string(),
+            // Already an obfuscated identifier or `unknown`.
+            Err(_) if ip.starts_with('_') || ip == "unknown" => ip,
+            Err(_) => "unknown".to_string(),
+        },
+        None => "unknown".to_string(),
+    };
+    let mut element = format!(
+        "for={};proto={}",
+        forwarded_value(&node),
+        forwarded_value(client_scheme(parts))
+    );
+    let host = parts
+        .headers
+        .get(hyper::header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| Some(parts.uri.authority()?.as_str()));
+    if let Some(host) = host {
+        element.push_str(";host=");
+        element.push_str(&forwarded_value(host));
+    }
+
+    let mut chain: Vec<&str> = parts
+        .headers
+        .get_all(hyper::header::FORWARDED)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .collect();
+    chain.push(&element);
+    if let Ok(value) = hyper::header::HeaderValue::from_str(&chain.join(", ")) {
+        headers.insert(hyper::header::FORWARDED, value);
+    }
+}
+
+/// A `Forwarded` parameter value: a token as is, anything else (IPv6
+/// brackets, `host:port`) as a quoted string.
+fn forwarded_value(value: &str) -> String {
+    let is_tchar = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if !value.is_empty() && value.chars().all(is_tchar) {
+        return value.to_string();
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn http_version_name(version: hyper::Version) -> &'static str {
+    match version {
+        hyper::Version::HTTP_09 => "HTTP/0.9",
+        hyper::Version::HTTP_10 => "HTTP/1.0",
+        hyper::Version::HTTP_2 => "HTTP/2",
+        hyper::Version::HTTP_3 => "HTTP/3",
+        _ => "HTTP/1.1",
+    }
+}
+
+/// Whether the request announces a body, per its framing headers.
+fn has_body(headers: &hyper::HeaderMap) -> bool {
+    headers.contains_key(hyper::header::TRANSFER_ENCODING)
+        || headers
+            .get(hyper::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<u64>().ok())
+            .is_some_and(|n| n > 0)
+}
+
+fn client_scheme(parts: &hyper::http::request::Parts) -> &str {
+    parts
+        .uri
+        .scheme_str()
+        .or_else(|| parts.headers.get("x-forwarded-proto")?.to_str().ok())
+        .unwrap_or("http")
+}
+
+/// Rewrites an absolute `Location` naming the backend's authority to the
+/// client-facing one, keeping path, query and fragment. Relative values and
+/// ones pointing elsewhere come back as `None`.
+fn rewrite_location(
+    location: &str,
+    backend: &hyper::http::uri::Authority,
+    req: &hyper::http::request::Parts,
+) -> Option<hyper::header::HeaderValue> {
+    let (scheme, rest) = location.split_once("://")?;
+    // Only a leading scheme makes the value absolute; `://` further in is
+    // part of a relative path or query.
+    if !scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        || !scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+    {
+        return None;
+    }
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, tail) = rest.split_at(end);
+    if !authority.eq_ignore_ascii_case(backend.as_str()) {
+        return None;
+    }
+    let host = match req.headers.get(hyper::header::HOST) {
+        Some(host) => host.to_str().ok()?,
+        None => req.uri.authority()?.as_str(),
+    };
+    let tail = if tail.is_empty() { "/" } else { tail };
+    hyper::header::HeaderValue::from_str(&format!("{}://{host}{tail}", client_scheme(req))).ok()
+}
+
+fn apply_header_rewrites(headers: &mut hyper::HeaderMap, rewrites: &[HeaderRewrite]) {
+    for rewrite in rewrites {
+        let Ok(name) = hyper::header::HeaderName::try_from(&rewrite.header) else {
+            continue;
+        };
+        let hyper::header::Entry::Occupied(mut entry) = headers.entry(name) else {
+            continue;
+        };
+        for value in entry.iter_mut() {
+            let Ok(text) = value.to_str() else {
+                continue;
+            };
+            let replaced = rewrite.pattern.replace_all(text, rewrite.replace.as_str());
+            if let Ok(new) = hyper::header::HeaderValue::from_str(&replaced) {
+                *value = new;
+            }
+        }
+    }
+}
+
+enum UpstreamError {
+    Client(hyper_util::client::legacy::Error),
+    Timeout(Duration),
+}
+
+impl UpstreamError {
+    fn is_connect(&self) -> bool {
+        matches!(self, UpstreamError::Client(e) if e.is_connect())
+    }
+}
+
+/// A request counted against `MaxConcurrentUpstream` and the in-flight gauge.
+struct UpstreamSlot {
+    _permit: Option<tokio::sync::SemaphorePermit<'static>>,
+}
+
+impl UpstreamSlot {
+    /// Waits up to `UpstreamQueueTimeoutMs` for a free slot; `None` when the
+    /// backend stays saturated.
+    async fn acquire(cfg: &Config) -> Option<Self> {
+        static SLOTS: OnceLock<Option<tokio::sync::Semaphore>> = OnceLock::new();
+
+        let slots = SLOTS.get_or_init(|| {
+            cfg.http
+                .max_concurrent_upstream
+                .map(tokio::sync::Semaphore::new)
+        });
+        let permit = match slots {
+            Some(slots) => {
+                let wait = Duration::from_millis(cfg.http.upstream_queue_timeout_ms);
+                match tokio::time::timeout(wait, slots.acquire()).await {
+                    Ok(Ok(permit)) => Some(permit),
+                    _ => {
+                        tracing::warn!(
+                            limit = cfg.http.max_concurrent_upstream,
+                            "upstream concurrency limit reached"
+                        );
+                        return None;
+                    }
+                }
+            }
+            None => None,
+        };
+        METRICS.in_flight.fetch_add(1, Ordering::Relaxed);
+        Some(Self { _permit: permit })
+    }
+}
+
+impl Drop for UpstreamSlot {
+    fn drop(&mut self) {
+        METRICS.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Response body that holds an `UpstreamSlot` until it is dropped. Being
+/// dropped before the end means the client went away mid-response; that is
+/// only logged at debug level, and dropping the upstream body with it closes
+/// (or, over h2c, resets) the backend connection so the backend stops
+/// working on it.
+struct SlotBody {
+    inner: ProxyBody,
+    _slot: UpstreamSlot,
+    finished: bool,
+    span: tracing::Span,
+}
+
+impl Drop for SlotBody {
+    fn drop(&mut self) {
+        if !self.finished && !hyper::body::Body::is_end_stream(&self.inner) {
+            self.span.in_scope(|| {
+                tracing::debug!("client disconnected before the response finished");
+            });
+        }
+    }
+}
+
+impl hyper::body::Body for SlotBody {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if matches!(poll, Poll::Ready(None | Some(Err(_)))) {
+            self.finished = true;
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Adds the size of every data frame that passes through to `counter`, so
+/// streamed bodies are measured without buffering them.
+struct CountingBody {
+    inner: ProxyBody,
+    counter: &'static AtomicU64,
+    /// Bytes seen by this body, and where it is cut off with an error.
+    seen: u64,
+    limit: Option<u64>,
+}
+
+impl CountingBody {
+    fn wrap(inner: ProxyBody, counter: &'static AtomicU64) -> ProxyBody {
+        Self {
+            inner,
+            counter,
+            seen: 0,
+            limit: None,
+        }
+        .boxed()
+    }
+
+    /// Like `wrap`, but fails the body once more than `limit` bytes passed.
+    fn limited(inner: ProxyBody, counter: &'static AtomicU64, limit: u64) -> ProxyBody {
+        Self {
+            inner,
+            counter,
+            seen: 0,
+            limit: Some(limit),
+        }
+        .boxed()
+    }
+}
+
+impl hyper::body::Body for CountingBody {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll
+            && let Some(data) = frame.data_ref()
+        {
+            let len = data.len() as u64;
+            self.counter.fetch_add(len, Ordering::Relaxed);
+            self.seen += len;
+            if let Some(limit) = self.limit
+                && self.seen > limit
+            {
+                tracing::warn!(limit, "truncated backend response at MaxResponseBytes");
+                // Dropping the inner body now closes the backend connection
+                // instead of reading the rest.
+                self.inner = Empty::new().map_err(Error::from).boxed();
+                return Poll::Ready(Some(Err(format!(
+                    "backend response exceeded {limit} bytes"
+                )
+                .into())));
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Sends a request to the backend, recording time-to-response-headers.
+async fn send_upstream(
+    req: hyper::Request<ProxyBody>,
+    timeout: Option<Duration>,
+) -> Result<hyper::Response<Incoming>, UpstreamError> {
+    let start = Instant::now();
+    // An event stream may legitimately hold its headers back until the first
+    // event, so it is never subject to the time-to-first-byte limit.
+    let timeout = timeout.filter(|_| !is_event_stream(req.headers().get(hyper::header::ACCEPT)));
+    let client = if req.headers().contains_key(hyper::header::UPGRADE) {
+        upgrade_client()
+    } else {
+        proxy_client()
+    };
+    let request = client.request(req);
+    let result = match timeout {
+        Some(limit) => match tokio::time::timeout(limit, request).await {
+            Ok(result) => result.map_err(UpstreamError::Client),
+            Err(_) => Err(UpstreamError::Timeout(limit)),
+        },
+        None => request.await.map_err(UpstreamError::Client),
+    };
+    let elapsed = start.elapsed();
+    METRICS.record_upstream_latency(elapsed);
+    result.map(|mut res| {
+        res.extensions_mut().insert(UpstreamLatency(elapsed));
+        res
+    })
+}
+
+fn full(bytes: Bytes) -> ProxyBody {
+    Full::new(bytes).map_err(|never| match never {}).boxed()
+}
+
+fn upstream_request(
+    method: &hyper::Method,
+    uri: hyper::Uri,
+    headers: hyper::HeaderMap,
+    body: ProxyBody,
+) -> hyper::Request<ProxyBody> {
+    let mut upstream = hyper::Request::new(body);
+    *upstream.method_mut() = method.clone();
+    *upstream.uri_mut() = uri;
+    *upstream.headers_mut() = headers;
+    upstream
+}
+
+/// The client went away while sending its request. Its connection is most
+/// likely gone, so the response is mostly for the access log.
+fn client_closed() -> Result<Response<ResponseBody>, Error> {
+    tracing::debug!("client disconnected before the request was sent");
+    error_response(
+        ProxyError::ClientClosed,
+        "client closed the connection before the request body was read".into(),
+    )
+}
+
+async fn upstream_failed(
+    e: UpstreamError,
+    instance: usize,
+) -> Result<Response<ResponseBody>, Error> {
+    BREAKER.record_failure(&config());
+    match e {
+        UpstreamError::Client(e) => {
+            tracing::error!(error = %e, instance, "backend unreachable");
+            // Give a crashing backend a moment to be reapable so the 502 can
+            // say how it died. Any other failure (a reset mid-response, a
+            // client hanging up on its upload) leaves a running backend in
+            // rotation.
+            let exited = wait_for_exit(instance).await;
+            if exited || e.is_connect() {
+                lose_instance(instance, false);
+            }
+            let mut body =
+                ProxyError::UpstreamError.body(format!("backend connection failed: {e}"));
+            if let Some(exit) = recent_exit(instance) {
+                body["error"]["exit"] = exit.json();
+            }
+            error_with_body(ProxyError::UpstreamError, body)
+        }
+        UpstreamError::Timeout(limit) => {
+            tracing::error!(timeout_ms = limit.as_millis() as u64, "backend timed out");
+            // A single slow request is not proof the backend is hung; let the
+            // liveness check decide whether it needs a restart.
+            LIVENESS_CHECK.notify_one();
+            error_response(
+                ProxyError::Timeout,
+                format!("backend did not respond within {}ms", limit.as_millis()),
+            )
+        }
+    }
+}
+
+/// Waits a few polls for the process behind `instance` to exit, returning
+/// whether it did. One the supervisor already took out counts as not.
+async fn wait_for_exit(instance: usize) -> bool {
+    for _ in 0..CRASH_CHECK_POLLS {
+        {
+            let mut guard = GO.lock().unwrap_or_else(|e| e.into_inner());
+            let Some(backend) = guard.iter_mut().find(|b| b.index == instance) else {
+                return false;
+            };
+            let exited = match (&mut backend.child, backend.adopted) {
+                (Some(child), _) => matches!(child.try_wait(), Ok(Some(_))),
+                (None, Some(pid)) => !process_alive(pid),
+                (None, None) => return false,
+            };
+            if exited {
+                return true;
+            }
+        }
+        sleep(CRASH_CHECK_POLL).await;
+    }
+    false
+}
+
+/// Strips hop-by-hop headers, including any named by the `Connection` header
+/// of either the inbound request or the backend response.
+fn stream_response(
+    res: hyper::Response<Incoming>,
+    req: &hyper::http::request::Parts,
+    backend: Option<&hyper::http::uri::Authority>,
+) -> Result<Response<ResponseBody>, Error> {
+    BREAKER.record_success();
+    let cfg = config();
+    let (parts, incoming) = res.into_parts();
+    let mut listed = connection_tokens(&req.headers);
+    listed.extend(connection_tokens(&parts.headers));
+    let mut headers = hyper::HeaderMap::new();
+    for (k, v) in &parts.headers {
+        let name = k.as_str();
+        if !(is_hop_by_hop_header(name) || listed.iter().any(|t| t == name)) {
+            headers.append(k, v.clone());
+        }
+    }
+    if cfg.http.rewrite_location
+        && let Some(backend) = backend
+        && let Some(location) = headers.get(hyper::header::LOCATION)
+        && let Some(rewritten) = location
+            .to_str()
+            .ok()
+            .and_then(|l| rewrite_location(l, backend, req))
+    {
+        headers.insert(hyper::header::LOCATION, rewritten);
+    }
+    apply_header_rewrites(&mut headers, &cfg.http.header_rewrites);
+
+    let body = incoming.map_err(Error::from).boxed();
+    let body = match cfg.http.max_response_bytes {
+        Some(max) => {
+            let length = headers
+                .get(hyper::header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+            if let Some(length) = length.filter(|len| *len > max) {
+                tracing::warn!(length, max, "backend response exceeds MaxResponseBytes");
+                return error_response(
+                    ProxyError::UpstreamError,
+                    format!("backend response of {length} bytes exceeds the {max} byte limit"),
+                );
+            }
+            CountingBody::limited(body, &METRICS.response_bytes, max)
+        }
+        // Passed through as a body rather than a stream when not compressing,
+        // so the end of a fixed-length body is visible to `SlotBody`.
+        None => CountingBody::wrap(body, &METRICS.response_bytes),
+    };
+    let body = match body_rewrite_plan(&cfg, req, parts.status, &headers) {
+        Some((rules, encoding)) => rewrite_body(body, rules, encoding, &mut headers, &cfg),
+        None => body,
+    };
+    let encoding = response_encoding(req, parts.status, &headers);
+    let body = match encoding {
+        Some(encoding) => {
+            headers.remove(hyper::header::CONTENT_LENGTH);
+            headers.insert(
+                hyper::header::CONTENT_ENCODING,
+                hyper::header::HeaderValue::from_static(encoding.name()),
+            );
+            headers.append(
+                hyper::header::VARY,
+                hyper::header::HeaderValue::from_static("accept-encoding"),
+            );
+            encoding.compress(body)
+        }
+        None => body,
+    };
+
+    let mut response = Response::builder().status(parts.status);
+    if let Some(h) = response.headers_mut() {
+        *h = headers;
+    }
+    if let Some(latency) = parts.extensions.get::<UpstreamLatency>() {
+        response = response.extension(*latency);
+    }
+    Ok(response.body(ResponseBody(body))?)
+}
+
+/// The `BodyRewrites` that apply to a response, and the encoding its body
+/// has to be decoded from first. `None` when no rule lists the content type,
+/// there is no body, or it is in an encoding the proxy cannot decode.
+fn body_rewrite_plan(
+    cfg: &Config,
+    req: &hyper::http::request::Parts,
+    status: hyper::StatusCode,
+    headers: &hyper::HeaderMap,
+) -> Option<(Vec<BodyRewrite>, Option<Encoding>)> {
+    if cfg.http.body_rewrites.is_empty()
+        || req.method == hyper::Method::HEAD
+        || status == hyper::StatusCode::NO_CONTENT
+        || status == hyper::StatusCode::NOT_MODIFIED
+        || status.is_informational()
+        || headers.contains_key(hyper::header::CONTENT_RANGE)
+    {
+        return None;
+    }
+    let media_type = headers
+        .get(hyper::header::CONTENT_TYPE)
+        .map(media_type)
+        .unwrap_or_default();
+    let rules = cfg
+        .http
+        .body_rewrites
+        .iter()
+        .filter(|rule| {
+            rule.content_types
+                .iter()
+                .any(|pattern| media_type_matches(pattern, &media_type))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    if rules.is_empty() {
+        return None;
+    }
+    let encoding = match headers.get(hyper::header::CONTENT_ENCODING) {
+        None => None,
+        Some(value) => match value.to_str().ok()?.trim() {
+            coding if coding.eq_ignore_ascii_case("identity") => None,
+            coding => Some(Encoding::from_name(coding)?),
+        },
+    };
+    Some((rules, encoding))
+}
+
+fn rewrite_body(
+    body: ProxyBody,
+    rules: Vec<BodyRewrite>,
+    encoding: Option<Encoding>,
+    headers: &mut hyper::HeaderMap,
+    cfg: &Config,
+) -> ProxyBody {
+    headers.remove(hyper::header::CONTENT_LENGTH);
+    let body = match encoding {
+        Some(encoding) => {
+            headers.remove(hyper::header::CONTENT_ENCODING);
+            encoding.decompress(body)
+        }
+        None => body,
+    };
+    let body = RewriteBody {
+        inner: body,
+        rules,
+        max: cfg.http.body_rewrite_max_bytes,
+        state: RewriteState::Buffering(Vec::new()),
+        trailers: None,
+    }
+    .boxed();
+    match encoding {
+        Some(encoding) if cfg.http.body_rewrite_recompress => {
+            headers.insert(
+                hyper::header::CONTENT_ENCODING,
+                hyper::header::HeaderValue::from_static(encoding.name()),
+            );
+            encoding.compress(body)
+        }
+        _ => body,
+    }
+}
+
+/// Holds the body until it ends and sends it once with `rules` applied.
+/// Past `max` bytes it gives up and streams the rest through unchanged.
+struct RewriteBody {
+    inner: ProxyBody,
+    rules: Vec<BodyRewrite>,
+    max: usize,
+    state: RewriteState,
+    trailers: Option<hyper::HeaderMap>,
+}
+
+enum RewriteState {
+    Buffering(Vec<u8>),
+    Streaming,
+    Finished,
+}
+
+impl RewriteBody {
+    fn rewrite(&self, body: Vec<u8>) -> Bytes {
+        let mut text = match String::from_utf8(body) {
+            Ok(text) => text,
+            Err(e) => {
+                tracing::debug!("response body is not UTF-8; not rewriting it");
+                return Bytes::from(e.into_bytes());
+            }
+        };
+        for rule in &self.rules {
+            if let std::borrow::Cow::Owned(replaced) =
+                rule.pattern.replace_all(&text, rule.replace.as_str())
+            {
+                text = replaced;
+            }
+        }
+        Bytes::from(text)
+    }
+}
+
+impl hyper::body::Body for RewriteBody {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Error>>> {
+        loop {
+            match &mut self.state {
+                RewriteState::Streaming => return Pin::new(&mut self.inner).poll_frame(cx),
+                RewriteState::Finished => {
+                    return Poll::Ready(self.trailers.take().map(|map| Ok(Frame::trailers(map))));
+                }
+                RewriteState::Buffering(_) => {}
+            }
+            let frame = std::task::ready!(Pin::new(&mut self.inner).poll_frame(cx));
+            let this = &mut *self;
+            let RewriteState::Buffering(buffered) = &mut this.state else {
+                unreachable!("checked above");
+            };
+            match frame {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) if buffered.len() + data.len() > this.max => {
+                        tracing::warn!(
+                            max_bytes = this.max,
+                            "response body too large to rewrite; sending it unchanged"
+                        );
+                        let mut head = std::mem::take(buffered);
+                        head.extend_from_slice(&data);
+                        this.state = RewriteState::Streaming;
+                        return Poll::Ready(Some(Ok(Frame::data(Bytes::from(head)))));
+                    }
+                    Ok(data) => buffered.extend_from_slice(&data),
+                    Err(frame) => {
+                        if let Ok(map) = frame.into_trailers() {
+                            this.trailers = Some(map);
+                        }
+                    }
+                },
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => {
+                    let body = std::mem::take(buffered);
+                    this.state = RewriteState::Finished;
+                    let body = this.rewrite(body);
+                    if !body.is_empty() {
+                        return Poll::Ready(Some(Ok(Frame::data(body))));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Appends the trailers collected while the inner body was produced once it
+/// ends.
+struct TrailersAfter {
+    inner: ProxyBody,
+    trailers: Arc<Mutex<Option<hyper::HeaderMap>>>,
+}
+
+impl hyper::body::Body for TrailersAfter {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Error>>> {
+        match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(None) => Poll::Ready(
+                self.trailers
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .take()
+                    .map(|map| Ok(Frame::trailers(map))),
+            ),
+            poll => poll,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Encoding {
+    Brotli,
+    Gzip,
+    /// Only decoded (and re-encoded) for `BodyRewrites`; never negotiated.
+    Deflate,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Encoding> {
+        match name.to_ascii_lowercase().as_str() {
+            "br" => Some(Encoding::Brotli),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            _ => None,
+        }
+    }
+
+    /// Compresses frames as they arrive; nothing is buffered beyond what the
+    /// encoder needs to emit its next block.
+    fn compress(self, body: ProxyBody) -> ProxyBody {
+        use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZlibEncoder};
+
+        transcode(body, |reader| match self {
+            Encoding::Brotli => reader_body(BrotliEncoder::new(reader)),
+            Encoding::Gzip => reader_body(GzipEncoder::new(reader)),
+            Encoding::Deflate => reader_body(ZlibEncoder::new(reader)),
+        })
+    }
+
+    fn decompress(self, body: ProxyBody) -> ProxyBody {
+        use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, ZlibDecoder};
+
+        transcode(body, |reader| match self {
+            Encoding::Brotli => reader_body(BrotliDecoder::new(reader)),
+            Encoding::Gzip => reader_body(GzipDecoder::new(reader)),
+            Encoding::Deflate => reader_body(ZlibDecoder::new(reader)),
+        })
+    }
+}
+
+type BodyReader = tokio_util::io::StreamReader<
+    Pin<Box<dyn tokio_stream::Stream<Item = std::io::Result<Bytes>> + Send + Sync>>,
+    Bytes,
+>;
+
+/// Runs the data of `body` through a coder. Coders only take bytes, so
+/// trailers are set aside and sent after its last block.
+fn transcode(body: ProxyBody, coder: impl FnOnce(BodyReader) -> ProxyBody) -> ProxyBody {
+    let trailers = Arc::new(Mutex::new(None));
+    let seen = trailers.clone();
+    let data = http_body_util::BodyStream::new(body).filter_map(move |frame| match frame {
+        Ok(frame) => match frame.into_data() {
+            Ok(data) => Some(Ok(data)),
+            Err(frame) => {
+                if let Ok(map) = frame.into_trailers() {
+                    *seen.lock().unwrap_or_else(|e| e.into_inner()) = Some(map);
+                }
+                None
+            }
+        },
+        Err(e) => Some(Err(std::io::Error::other(e))),
+    });
+    let reader = tokio_util::io::StreamReader::new(Box::pin(data) as Pin<Box<_>>);
+    TrailersAfter {
+        inner: coder(reader),
+        trailers,
+    }
+    .boxed()
+}
+
+fn reader_body<R>(reader: R) -> ProxyBody
+where
+    R: tokio::io::AsyncRead + Send + Sync + 'static,
+{
+    let frames =
+        tokio_util::io::ReaderStream::new(reader).map(|r| r.map(Frame::data).map_err(Error::from));
+    StreamBody::new(frames).boxed()
+}
+
+/// Picks a compression for the response, or `None` when compression is off,
+/// the client does not accept it, or the body is already encoded, empty, or
+/// below `CompressionMinBytes`.
+fn response_encoding(
+    req: &hyper::http::request::Parts,
+    status: hyper::StatusCode,
+    headers: &hyper::HeaderMap,
+) -> Option<Encoding> {
+    let cfg = config();
+    if !cfg.http.compression
+        || req.method == hyper::Method::HEAD
+        || status == hyper::StatusCode::NO_CONTENT
+        || status == hyper::StatusCode::NOT_MODIFIED
+        || status.is_informational()
+        || headers.contains_key(hyper::header::CONTENT_ENCODING)
+        || headers.contains_key(hyper::header::CONTENT_RANGE)
+        // Encoders hold data back until they fill a block, which would stall
+        // events indefinitely.
+        || is_event_stream(headers.get(hyper::header::CONTENT_TYPE))
+        // gRPC frames its messages and compresses them itself.
+        || headers
+            .get(hyper::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("application/grpc"))
+    {
+        return None;
+    }
+    let length = headers
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+    if length.is_some_and(|len| len < cfg.http.compression_min_bytes) {
+        return None;
+    }
+
+    let accept = req
+        .headers
+        .get_all(hyper::header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let coding = params.next()?.trim().to_ascii_lowercase();
+            let q = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((coding, q))
+        })
+        .collect::<Vec<_>>();
+    let accepts = |coding: &str| {
+        accept
+            .iter()
+            .find(|(c, _)| c == coding)
+            .or_else(|| accept.iter().find(|(c, _)| c == "*"))
+            .is_some_and(|(_, q)| *q > 0.0)
+    };
+
+    [Encoding::Brotli, Encoding::Gzip]
+        .into_iter()
+        .find(|e| accepts(e.name()))
+}
+
+/// Responses kept for `Cache`, keyed by `cache_key`. A key holds one
+/// variant per combination of the request headers its `Vary` names.
+struct ResponseCache {
+    entries: BTreeMap<String, Vec<CachedResponse>>,
+    len: usize,
+    /// Bumped on every use; the entry with the lowest `last_used` is evicted.
+    tick: u64,
+}
+
+struct CachedResponse {
+    /// The headers `Vary` names, with the values the variant was fetched
+    /// with.
+    vary: Vec<(
+        hyper::header::HeaderName,
+        Option<hyper::header::HeaderValue>,
+    )>,
+    status: hyper::StatusCode,
+    headers: hyper::HeaderMap,
+    body: Bytes,
+    stored: Instant,
+    /// Freshness still left when it was stored, after the backend's `Age`.
+    ttl: Duration,
+    /// The backend's `Age`, which the age served on a hit starts from.
+    age: Duration,
+    last_used: u64,
+}
+
+impl CachedResponse {
+    fn matches(&self, headers: &hyper::HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| headers.get(name) == value.as_ref())
+    }
+}
+
+impl ResponseCache {
+    const fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            len: 0,
+            tick: 0,
+        }
+    }
+
+    /// A fresh variant of `key` for a request with `headers`, as a response.
+    /// Expired variants of `key` are dropped along the way.
+    fn get(
+        &mut self,
+        key: &str,
+        headers: &hyper::HeaderMap,
+        head: bool,
+    ) -> Option<Response<ResponseBody>> {
+        let variants = self.entries.get_mut(key)?;
+        let before = variants.len();
+        variants.retain(|v| v.stored.elapsed() < v.ttl);
+        self.len -= before - variants.len();
+        self.tick += 1;
+        let hit = variants.iter_mut().find(|v| v.matches(headers));
+        let response = hit.map(|hit| {
+            hit.last_used = self.tick;
+            let age = (hit.age + hit.stored.elapsed()).as_secs();
+            let mut response = Response::builder().status(hit.status);
+            if let Some(h) = response.headers_mut() {
+                *h = hit.headers.clone();
+                h.insert(hyper::header::AGE, hyper::header::HeaderValue::from(age));
+            }
+            let body = if head { Bytes::new() } else { hit.body.clone() };
+            response.body(ResponseBody(full(body)))
+        });
+        if variants.is_empty() {
+            self.entries.remove(key);
+        }
+        response?.ok()
+    }
+
+    /// Stores `entry`, replacing the variant of `key` it matches and evicting
+    /// the least recently used entries beyond `max_entries`.
+    fn insert(&mut self, key: String, mut entry: CachedResponse, max_entries: usize) {
+        self.tick += 1;
+        entry.last_used = self.tick;
+        let variants = self.entries.entry(key).or_default();
+        let before = variants.len();
+        variants.retain(|v| v.vary != entry.vary);
+        self.len -= before - variants.len();
+        variants.push(entry);
+        self.len += 1;
+        while self.len > max_entries {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .flat_map(|(key, variants)| variants.iter().map(move |v| (v.last_used, key)))
+                .min()
+                .map(|(_, key)| key.clone())
+            else {
+                break;
+            };
+            let variants = self.entries.get_mut(&oldest).expect("key was just found");
+            if let Some(index) = variants
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, v)| v.last_used)
+                .map(|(i, _)| i)
+            {
+                variants.swap_remove(index);
+                self.len -= 1;
+            }
+            if variants.is_empty() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    /// Drops every variant stored for `resource` (a key without its query),
+    /// whatever its query.
+    fn invalidate(&mut self, resource: &str) {
+        let len = &mut self.len;
+        self.entries.retain(|key, variants| {
+            let keep = key.split('?').next() != Some(resource);
+            if !keep {
+                *len -= variants.len();
+            }
+            keep
+        });
+    }
+}
+
+/// The cache key for `req` once its target is rewritten to `path`: the
+/// inbound host followed by the path and query, so hosts sharing a backend
+/// never see each other's responses.
+fn cache_key(req: &Request, path: &hyper::http::uri::PathAndQuery) -> String {
+    let host = match req.headers().get(hyper::header::HOST) {
+        Some(host) => host.to_str().unwrap_or_default(),
+        None => req.uri().authority().map_or("", |a| a.as_str()),
+    };
+    format!("{}{path}", host.to_ascii_lowercase())
+}
+
+/// Whether a request may be answered from the cache, and its response
+/// stored.
+fn is_cacheable_request(method: &hyper::Method, headers: &hyper::HeaderMap) -> bool {
+    (method == hyper::Method::GET || method == hyper::Method::HEAD)
+        && !headers.contains_key(hyper::header::AUTHORIZATION)
+        && !is_websocket_upgrade(headers)
+        && !cache_directives(headers)
+            .any(|d| d.eq_ignore_ascii_case("no-store") || d.eq_ignore_ascii_case("no-cache"))
+}
+
+fn cache_directives(headers: &hyper::HeaderMap) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(hyper::header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+}
+
+/// How long a response stays fresh in a shared cache: `s-maxage`, else
+/// `max-age`, less its `Age`. `None` when it must not be stored at all.
+fn cache_lifetime(status: hyper::StatusCode, headers: &hyper::HeaderMap) -> Option<Duration> {
+    if !matches!(status.as_u16(), 200 | 203 | 301 | 404 | 410)
+        || headers.contains_key(hyper::header::SET_COOKIE)
+    {
+        return None;
+    }
+    let mut max_age = None;
+    let mut s_maxage = None;
+    for directive in cache_directives(headers) {
+        let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+        let seconds = || value.trim_matches('"').parse::<u64>().ok();
+        match name.to_ascii_lowercase().as_str() {
+            "no-store" | "no-cache" | "private" => return None,
+            "max-age" => max_age = seconds(),
+            "s-maxage" => s_maxage = seconds(),
+            _ => {}
+        }
+    }
+    let age = header_seconds(headers, hyper::header::AGE);
+    let ttl = s_maxage.or(max_age)?.checked_sub(age)?;
+    (ttl > 0).then(|| Duration::from_secs(ttl))
+}
+
+fn header_seconds(headers: &hyper::HeaderMap, name: hyper::header::HeaderName) -> u64 {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok()?.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// The request headers `Vary` names, lowercased. `None` for `Vary: *`,
+/// which no request can be known to match.
+fn vary_names(headers: &hyper::HeaderMap) -> Option<Vec<hyper::header::HeaderName>> {
+    let mut names = Vec::new();
+    for name in headers
+        .get_all(hyper::header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        if name == "*" {
+            return None;
+        }
+        if let Ok(name) = hyper::header::HeaderName::try_from(name)
+            && !names.contains(&name)
+        {
+            names.push(name);
+        }
+    }
+    Some(names)
+}
+
+/// Arranges for a cacheable `response` to be stored under `key` once its
+/// body has streamed through in full. Anything else passes untouched.
+fn fill_cache(
+    response: Response<ResponseBody>,
+    key: String,
+    req_headers: &hyper::HeaderMap,
+    cache: &CacheConfig,
+) -> Response<ResponseBody> {
+    let Some(ttl) = cache_lifetime(response.status(), response.headers()) else {
+        return response;
+    };
+    let Some(names) = vary_names(response.headers()) else {
+        return response;
+    };
+    if response
+        .headers()
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<usize>().ok())
+        .is_some_and(|len| len > cache.max_entry_bytes)
+    {
+        return response;
+    }
+    let entry = CachedResponse {
+        vary: names
+            .into_iter()
+            .map(|name| {
+                let value = req_headers.get(&name).cloned();
+                (name, value)
+            })
+            .collect(),
+        status: response.status(),
+        headers: response.headers().clone(),
+        body: Bytes::new(),
+        stored: Instant::now(),
+        ttl,
+        age: Duration::from_secs(header_seconds(response.headers(), hyper::header::AGE)),
+        last_used: 0,
+    };
+    let (max_entries, max_bytes) = (cache.max_entries, cache.max_entry_bytes);
+    response.map(|body| {
+        ResponseBody(
+            CacheFill {
+                inner: body.0,
+                pending: Some((key, entry)),
+                chunks: Vec::new(),
+                size: 0,
+                max_entries,
+                max_bytes,
+            }
+            .boxed(),
+        )
+    })
+}
+
+/// Copies the body it passes through, and stores the copy once the body
+/// ends, unless it broke off, grew past `max_bytes` or carried trailers.
+struct CacheFill {
+    inner: ProxyBody,
+    pending: Option<(String, CachedResponse)>,
+    chunks: Vec<Bytes>,
+    size: usize,
+    max_entries: usize,
+    max_bytes: usize,
+}
+
+impl CacheFill {
+    fn store(&mut self) {
+        let Some((key, mut entry)) = self.pending.take() else {
+            return;
+        };
+        let mut body = Vec::with_capacity(self.size);
+        for chunk in self.chunks.drain(..) {
+            body.extend_from_slice(&chunk);
+        }
+        entry.body = Bytes::from(body);
+        tracing::debug!(key, bytes = self.size, ttl = ?entry.ttl, "cached response");
+        CACHE
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(key, entry, self.max_entries);
+    }
+}
+
+impl hyper::body::Body for CacheFill {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if self.pending.is_none() {
+            return poll;
+        }
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => match frame.data_ref() {
+                Some(data) if self.size + data.len() <= self.max_bytes => {
+                    self.size += data.len();
+                    let data = data.clone();
+                    self.chunks.push(data);
+                    // A fixed-length body is not polled again once the
+                    // last of it is out.
+                    if self.inner.is_end_stream() {
+                        self.store();
+                    }
+                }
+                _ => {
+                    self.pending = None;
+                    self.chunks.clear();
+                }
+            },
+            Poll::Ready(None) => self.store(),
+            Poll::Ready(Some(Err(_))) => self.pending = None,
+            Poll::Pending => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Serves `req` from `Core.StaticDir` when it names an existing file there.
+/// `None` means the request should go to the backend.
+async fn serve_static<B>(
+    req: &hyper::Request<B>,
+    dir: &str,
+) -> Option<Result<Response<ResponseBody>, Error>> {
+    if req.method() != hyper::Method::GET && req.method() != hyper::Method::HEAD {
+        return None;
+    }
+    let relative = static_relative_path(req.uri().path())?;
+    let root = tokio::fs::canonicalize(dir).await.ok()?;
+    // Canonicalizing resolves symlinks too, so a link pointing outside the
+    // root is refused just like a `..` segment would be.
+    let path = tokio::fs::canonicalize(root.join(relative)).await.ok()?;
+    if !path.starts_with(&root) {
+        return None;
+    }
+    let meta = tokio::fs::metadata(&path).await.ok()?;
+    if !meta.is_file() {
+        return None;
+    }
+
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    let etag = format!("W/\"{:x}-{modified:x}\"", meta.len());
+    let content_type = static_content_type(&path);
+    let not_modified = req
+        .headers()
+        .get_all(hyper::header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.trim_start_matches("W/") == etag.trim_start_matches("W/")
+        });
+
+    let response = Response::builder()
+        .header(hyper::header::ETAG, &etag)
+        .header(hyper::header::CONTENT_TYPE, content_type);
+    if not_modified {
+        return Some(
+            response
+                .status(304)
+                .body(ResponseBody::from(()))
+                .map_err(Error::from),
+        );
+    }
+    let response = response
+        .status(200)
+        .header(hyper::header::CONTENT_LENGTH, meta.len());
+    if req.method() == hyper::Method::HEAD {
+        return Some(response.body(ResponseBody::from(())).map_err(Error::from));
+    }
+
+    let file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            tracing::warn!(path = %path.display(), error = %e, "failed to open static file");
+            return None;
+        }
+    };
+    let frames =
+        tokio_util::io::ReaderStream::new(file).map(|r| r.map(Frame::data).map_err(Error::from));
+    Some(
+        response
+            .body(ResponseBody(StreamBody::new(frames).boxed()))
+            .map_err(Error::from),
+    )
+}
+
+/// Maps a request path onto a path relative to the static root, refusing
+/// anything that could step outside it.
+fn static_relative_path(path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(path)?;
+    let mut relative = PathBuf::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            s if s.contains(['\\', '\0']) => return None,
+            s => relative.push(s),
+        }
+    }
+    if relative.as_os_str().is_empty() {
+        return None;
+    }
+    Some(relative)
+}
+
+fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            // `from_str_radix` alone would take a sign, as in `%+1`.
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let hex = std::str::from_utf8(hex).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn static_content_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    match ext.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json" | "map") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("mp3") => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Whether an `Accept` or `Content-Type` value names `text/event-stream`.
+fn is_event_stream(value: Option<&hyper::header::HeaderValue>) -> bool {
+    value.and_then(|v| v.to_str().ok()).is_some_and(|v| {
+        v.split(',').any(|item| {
+            item.split(';')
+                .next()
+                .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/event-stream"))
+        })
+    })
+}
+
+fn is_websocket_upgrade(headers: &hyper::HeaderMap) -> bool {
+    headers
+        .get(hyper::header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case("websocket"))
+        })
+}
+
+/// Forwards an upgrade handshake with its `Connection`/`Upgrade` headers
+/// intact and, once the backend answers 101, splices the two upgraded
+/// connections together until either side closes.
+async fn proxy_upgrade(
+    mut req: Request,
+    uri: hyper::Uri,
+    instance: usize,
+    cfg: &Config,
+    route: &RouteSettings,
+) -> Result<Response<ResponseBody>, Error> {
+    let client_upgrade = hyper::upgrade::on(&mut req);
+    let (parts, _) = req.into_parts();
+    let headers = upstream_headers(&parts, cfg, true);
+    let backend = uri.authority().cloned();
+
+    let upstream = upstream_request(&parts.method, uri, headers, full(Bytes::new()));
+    let mut res = match send_upstream(upstream, route.timeout).await {
+        Ok(res) => res,
+        Err(e) => return upstream_failed(e, instance).await,
+    };
+
+    if res.status() != hyper::StatusCode::SWITCHING_PROTOCOLS {
+        return stream_response(res, &parts, backend.as_ref());
+    }
+
+    BREAKER.record_success();
+    let backend_upgrade = hyper::upgrade::on(&mut res);
+    let mut response = Response::builder().status(res.status());
+    for (k, v) in res.headers() {
+        response = response.header(k, v);
+    }
+
+    tokio::spawn(
+        async move {
+            match tokio::try_join!(client_upgrade, backend_upgrade) {
+                Ok((client, backend)) => {
+                    let mut client = TokioIo::new(client);
+                    let mut backend = TokioIo::new(backend);
+                    match tokio::io::copy_bidirectional(&mut client, &mut backend).await {
+                        Ok((sent, received)) => {
+                            METRICS.request_bytes.fetch_add(sent, Ordering::Relaxed);
+                            METRICS
+                                .response_bytes
+                                .fetch_add(received, Ordering::Relaxed);
+                        }
+                        Err(e) => {
+                            tracing::debug!(error = %e, "upgraded connection closed with error")
+                        }
+                    }
+                }
+                Err(e) => tracing::warn!(error = %e, "upgrade failed"),
+            }
+        }
+        .in_current_span(),
+    );
+
+    Ok(response.body(ResponseBody::from(()))?)
+}
+
+/// The 503 for a backend that could not be started: the maintenance page
+/// for browsers when one is configured, otherwise the JSON error with
+/// captured stderr attached when log capture is on.
+fn startup_failed(
+    cfg: &Config,
+    headers: &hyper::HeaderMap,
+    e: String,
+) -> Result<Response<ResponseBody>, Error> {
+    static PAGE: FileOnce = FileOnce::new();
+    starting_response(
+        cfg,
+        PAGE.get(cfg.http.maintenance_page.as_deref()),
+        headers,
+        e,
+    )
+}
+
+fn starting_response(
+    cfg: &Config,
+    page: Option<&Bytes>,
+    headers: &hyper::HeaderMap,
+    e: String,
+) -> Result<Response<ResponseBody>, Error> {
+    let wants_html = !cfg.http.maintenance_page_html_only || accepts_html(headers);
+    if wants_html && let Some(page) = page {
+        return Ok(Response::builder()
+            .status(ProxyError::Starting.status())
+            .header("content-type", "text/html; charset=utf-8")
+            .header("cache-control", "no-store")
+            .header(WAVE_ERROR_HEADER, ProxyError::Starting.code())
+            .body(ResponseBody(full(page.clone())))?);
+    }
+    let mut body = ProxyError::Starting.body(e);
+    if cfg.process.capture_logs {
+        body["error"]["stderr"] =
+            serde_json::json!(recent_backend_logs(LOG_TAIL_LINES, Some(LogStream::Stderr)));
+    }
+    error_with_body(ProxyError::Starting, body)
+}
+
+/// A file such as `MaintenancePage`, read on first use and kept in memory.
+/// A missing or unreadable file is reported once and then treated as unset.
+struct FileOnce(OnceLock<Option<Bytes>>);
+
+impl FileOnce {
+    const fn new() -> Self {
+        Self(OnceLock::new())
+    }
+
+    fn get(&self, path: Option<&str>) -> Option<&Bytes> {
+        self.0
+            .get_or_init(|| {
+                let path = path?;
+                match std::fs::read(path) {
+                    Ok(page) => Some(Bytes::from(page)),
+                    Err(e) => {
+                        tracing::warn!(path = %path, error = %e, "failed to read maintenance page");
+                        None
+                    }
+                }
+            })
+            .as_ref()
+    }
+}
+
+fn accepts_html(headers: &hyper::HeaderMap) -> bool {
+    headers
+        .get_all(hyper::header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|item| {
+            item.split(';')
+                .next()
+                .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/html"))
+        })
+}
+
+/// Reports readiness without touching the backend: 200 when ready, 503
+/// otherwise.
+fn health_status() -> Result<Response<ResponseBody>, Error> {
+    let ready = READY.load(Ordering::Acquire);
+    let uptime = uptime();
+    let restarts = METRICS.restarts();
+    let last_restart = (restarts > 0).then(|| LAST_SPAWN_MS.load(Ordering::Relaxed));
+    let body = serde_json::json!({
+        "ready": ready,
+        "uptime_seconds": uptime.as_secs(),
+        "restarts": restarts,
+        "last_restart_unix_ms": last_restart,
+        "last_exit": last_exit().map(|exit| exit.json()),
+    });
+    Ok(Response::builder()
+        .status(if ready { 200 } else { 503 })
+        .header("content-type", "application/json")
+        .header("cache-control", "no-store")
+        .body(ResponseBody::from(body))?)
+}
+
+fn admin_token() -> Option<&'static str> {
+    static TOKEN: OnceLock<Option<String>> = OnceLock::new();
+    TOKEN
+        .get_or_init(|| std::env::var(config().observability.admin_token_env.as_ref()?).ok())
+        .as_deref()
+}
+
+fn is_allowed_method(methods: &[String], method: &hyper::Method) -> bool {
+    let listed = |name: &str| methods.iter().any(|m| m.eq_ignore_ascii_case(name));
+    listed(method.as_str()) || (method == hyper::Method::HEAD && listed("GET"))
+}
+
+/// `Http.AllowedMethods` as an `Allow` value, with the implied `HEAD`.
+fn allow_header(methods: &[String]) -> String {
+    let mut allow: Vec<String> = methods.iter().map(|m| m.to_ascii_uppercase()).collect();
+    if allow.iter().any(|m| m == "GET") && !allow.iter().any(|m| m == "HEAD") {
+        allow.push("HEAD".to_string());
+    }
+    allow.join(", ")
+}
+
+/// `RestartEndpoint`: stops every instance and clears `READY` (and the crash
+/// count, so a backend that hit `MaxRestarts` gets another chance). The
+/// restart itself happens on the next request.
+fn admin_restart(req: &Request, token: &str) -> Result<Response<ResponseBody>, Error> {
+    if req.method() != hyper::Method::POST {
+        let mut response = error_response(
+            ProxyError::MethodNotAllowed,
+            format!("{} only accepts POST", req.uri().path()),
+        )?;
+        response.headers_mut().insert(
+            hyper::header::ALLOW,
+            hyper::header::HeaderValue::from_static("POST"),
+        );
+        return Ok(response);
+    }
+    let presented = req
+        .headers()
+        .get(ADMIN_TOKEN_HEADER)
+        .map(hyper::header::HeaderValue::as_bytes);
+    if !presented.is_some_and(|presented| constant_time_eq(presented, token.as_bytes())) {
+        tracing::warn!(
+            client_ip = client_ip(req.headers()),
+            "rejected admin restart"
+        );
+        return error_response(
+            ProxyError::Unauthorized,
+            format!("missing or wrong {ADMIN_TOKEN_HEADER}"),
+        );
+    }
+
+    tracing::warn!(
+        client_ip = client_ip(req.headers()),
+        user_agent = req
+            .headers()
+            .get(hyper::header::USER_AGENT)
+            .and_then(|v| v.to_str().ok()),
+        "backend restart requested via admin endpoint"
+    );
+    tokio::spawn(restart_backend().in_current_span());
+    let body = serde_json::json!({ "restarting": true });
+    Ok(Response::builder()
+        .status(202)
+        .header("content-type", "application/json")
+        .header("cache-control", "no-store")
+        .body(ResponseBody::from(body))?)
+}
+
+/// Stops every instance and resets the crash budget, so the next request
+/// starts a fresh backend.
+async fn restart_backend() {
+    // Taken so the stop cannot interleave with a spawn in progress.
+    let _lock = INIT_LOCK.lock().await;
+    READY.store(false, Ordering::Release);
+    RESTARTS.store(0, Ordering::Release);
+    kill_child().await;
+    tracing::info!("backend stopped, next request restarts it");
+}
+
+/// Compares without exiting at the first differing byte, so response timing
+/// does not leak how much of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn uptime() -> Duration {
+    STARTED.get().map_or(Duration::ZERO, Instant::elapsed)
+}
+
+fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Every error the proxy itself produces. The code goes out in the
+/// `wave-error` header and as `error.code` in the body, so clients can tell
+/// a backend that is still starting (retry soon) from one that failed.
+#[derive(Clone, Copy, Debug)]
+enum ProxyError {
+    Starting,
+    UpstreamError,
+    Timeout,
+    CircuitOpen,
+    Overloaded,
+    ShuttingDown,
+    BadRequest,
+    NotFound,
+    PayloadTooLarge,
+    ClientClosed,
+    MethodNotAllowed,
+    Unauthorized,
+    UnsupportedMediaType,
+    HeadersTooLarge,
+}
+
+impl ProxyError {
+    fn status(self) -> hyper::StatusCode {
+        use hyper::StatusCode;
+        match self {
+            ProxyError::Starting
+            | ProxyError::CircuitOpen
+            | ProxyError::Overloaded
+            | ProxyError::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+            ProxyError::UpstreamError => StatusCode::BAD_GATEWAY,
+            ProxyError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ProxyError::BadRequest => StatusCode::BAD_REQUEST,
+            ProxyError::NotFound => StatusCode::NOT_FOUND,
+            ProxyError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            // Not a registered status; the nginx convention for this case.
+            ProxyError::ClientClosed => StatusCode::from_u16(499).expect("499 is in range"),
+            ProxyError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ProxyError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ProxyError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ProxyError::HeadersTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+        }
+    }
+
+    fn code(self) -> &'static str {
+        match self {
+            ProxyError::Starting => "starting",
+            ProxyError::UpstreamError => "upstream_error",
+            ProxyError::Timeout => "timeout",
+            ProxyError::CircuitOpen => "circuit_open",
+            ProxyError::Overloaded => "overloaded",
+            ProxyError::ShuttingDown => "shutting_down",
+            ProxyError::BadRequest => "bad_request",
+            ProxyError::NotFound => "not_found",
+            ProxyError::PayloadTooLarge => "payload_too_large",
+            ProxyError::ClientClosed => "client_closed",
+            ProxyError::MethodNotAllowed => "method_not_allowed",
+            ProxyError::Unauthorized => "unauthorized",
+            ProxyError::UnsupportedMediaType => "unsupported_media_type",
+            ProxyError::HeadersTooLarge => "headers_too_large",
+        }
+    }
+
+    fn body(self, message: String) -> serde_json::Value {
+        serde_json::json!({ "error": { "code": self.code(), "message": message } })
+    }
+}
+
+fn error_response(kind: ProxyError, message: String) -> Result<Response<ResponseBody>, Error> {
+    error_with_body(kind, kind.body(message))
+}
+
+/// Every error the proxy itself answers with goes through here, as JSON
+/// shaped `{"error": {"code", "message"}}`. The body also rides along as an
+/// extension so `handler` can render it as text for clients that ask.
+fn error_with_body(
+    kind: ProxyError,
+    body: serde_json::Value,
+) -> Result<Response<ResponseBody>, Error> {
+    Ok(Response::builder()
+        .status(kind.status())
+        .header("content-type", "application/json")
+        .header(WAVE_ERROR_HEADER, kind.code())
+        .extension(ErrorBody(body.clone()))
+        .body(ResponseBody::from(body))?)
+}
+
+#[derive(Clone)]
+struct ErrorBody(serde_json::Value);
+
+impl ErrorBody {
+    fn to_text(&self) -> String {
+        let error = &self.0["error"];
+        let mut text = format!(
+            "{}: {}\n",
+            error["code"].as_str().unwrap_or_default(),
+            error["message"].as_str().unwrap_or_default()
+        );
+        if let Some(status) = error["exit"]["status"].as_str() {
+            text.push_str(&format!("backend {status}\n"));
+        }
+        for line in error["stderr"].as_array().into_iter().flatten() {
+            text.push_str(line.as_str().unwrap_or_default());
+            text.push('\n');
+        }
+        text
+    }
+}
+
+/// Whether `Accept` ranks `text/plain` above JSON. Without an `Accept`
+/// header, or with one that does not mention text, errors stay JSON.
+fn prefers_plain_text(headers: &hyper::HeaderMap) -> bool {
+    let mut text = 0.0f32;
+    let mut json = 0.0f32;
+    for item in headers
+        .get_all(hyper::header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+    {
+        let mut params = item.split(';');
+        let mime = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match mime.as_str() {
+            "text/plain" | "text/*" => text = text.max(q),
+            "application/json" | "application/*" | "*/*" => json = json.max(q),
+            _ => {}
+        }
+    }
+    text > json
+}
+
+/// Kills whatever backends are still registered when `main` unwinds or
+/// returns without having gone through `shutdown`, e.g. `run` failing to bind
+/// or a panic on the main task. Statics are never dropped, so nothing else
+/// would stop the children. There is no grace period here: the runtime may
+/// already be gone.
+struct ChildGuard;
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        let backends = std::mem::take(&mut *GO.lock().unwrap_or_else(|e| e.into_inner()));
+        for backend in backends {
+            if let Some(mut child) = backend.child {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            #[cfg(unix)]
+            if let Some(pid) = backend
+                .adopted
+                .and_then(|pid| libc::pid_t::try_from(pid).ok())
+            {
+                // SAFETY: `kill` has no memory-safety preconditions.
+                unsafe { libc::kill(pid, libc::SIGKILL) };
+            }
+        }
+    }
+}
+
+/// `--check` (or `WAVE_CHECK=1`): starts the backend once, waits for it to
+/// pass its health check and stops it again, without ever serving. Meant as
+/// a CI smoke test; the config was already loaded and validated by then.
+/// Returns the exit code.
+async fn self_check() -> i32 {
+    let start = Instant::now();
+    let result = ensure_ready().await;
+    kill_child().await;
+    match result {
+        Ok(()) => {
+            tracing::info!(elapsed = ?start.elapsed(), "self-check passed");
+            0
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "self-check failed");
+            1
+        }
+    }
+}
+
+/// Stops taking new requests, gives in-flight ones up to `DrainTimeoutMs`
+/// to finish, then stops the backend. Its reuse marker goes with it: reuse
+/// is for a proxy that died without getting here.
+async fn shutdown() {
+    DRAINING.store(true, Ordering::Release);
+    let grace = Duration::from_millis(config().process.drain_timeout_ms);
+    let deadline = Instant::now() + grace;
+    while METRICS.in_flight.load(Ordering::Relaxed) > 0 && Instant::now() < deadline {
+        sleep(DRAIN_POLL).await;
+    }
+    let remaining = METRICS.in_flight.load(Ordering::Relaxed);
+    if remaining > 0 {
+        tracing::warn!(in_flight = remaining, ?grace, "drain timed out");
+    }
+    kill_child().await;
+    if let Some(marker) = &config().process.reuse_marker {
+        let _ = std::fs::remove_file(marker);
+    }
+    tracing::info!("shutdown");
+}
+
+/// `vercel_runtime::run` exposes no lifecycle hook of its own: its only stop
+/// signal is SIGTERM (Ctrl-C off Unix), on which it stops accepting and
+/// returns. What is guaranteed, in order:
+///
+/// 1. SIGTERM: `run` returns, then `shutdown` drains and stops the backend
+///    before `main` returns.
+/// 2. Ctrl-C / console close: the signal tasks below run `shutdown` and exit.
+/// 3. Anything else that ends `main` (bind error, panic): `ChildGuard` kills
+///    the backend without draining.
+///
+/// SIGKILL of the proxy, or `std::process::exit` from elsewhere, skips all
+/// three and can orphan the backend.
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .with_ansi(false)
+        .init();
+
+    STARTED.get_or_init(Instant::now);
+    let _guard = ChildGuard;
+
+    // Load eagerly so a broken config fails the cold start, not a request.
+    let cfg = config();
+    log_effective_config(&cfg);
+    backend_secret();
+
+    if std::env::args().any(|arg| arg == "--check")
+        || std::env::var(CHECK_ENV).as_deref() == Ok("1")
+    {
+        std::process::exit(self_check().await);
+    }
+
+    if cfg.process.prewarm_on_start {
+        // Requests arriving mid-warmup wait on the same INIT_LOCK rather than
+        // spawning a second backend.
+        tokio::spawn(async {
+            if let Err(e) = ensure_ready().await {
+                tracing::warn!(error = %e, "prewarm failed, retrying on first request");
+            }
+        });
+    }
+
+    tokio::spawn(supervise());
+    tokio::spawn(watch_liveness());
+    tokio::spawn(watch_idle());
+    tokio::spawn(watch_config());
+
+    tokio::spawn(async {
+        tokio::signal::ctrl_c().await.ok();
+        shutdown().await;
+        std::process::exit(0);
+    });
+
+    // Closing the console window or logging off/shutting down never raises
+    // Ctrl-C, so without these the child would outlive the proxy.
+    #[cfg(windows)]
+    tokio::spawn(async {
+        use tokio::signal::windows::{ctrl_close, ctrl_shutdown};
+        let (Ok(mut close), Ok(mut system)) = (ctrl_close(), ctrl_shutdown()) else {
+            return;
+        };
+        tokio::select! {
+            _ = close.recv() => {}
+            _ = system.recv() => {}
+        }
+        shutdown().await;
+        std::process::exit(0);
+    });
+
+    // `run` handles SIGTERM itself: it stops accepting connections and
+    // returns without waiting for the ones still open, so draining happens
+    // here before main returns and the runtime drops them.
+    let result = run(service_fn(handler)).await;
+    shutdown().await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::tests::test_config;
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Held by every test that goes through the process-wide state
+    /// (`CONFIG`, `GO`, `READY`, ...), which the harness would otherwise
+    /// share between threads.
+    static GLOBALS: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    /// Makes `cfg` the global config, with no backend registered.
+    fn install(cfg: Config) -> Arc<Config> {
+        let cfg = Arc::new(cfg);
+        *CONFIG.write().unwrap_or_else(|e| e.into_inner()) = Some(cfg.clone());
+        GO.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        READY.store(false, Ordering::Release);
+        cfg
+    }
+
+    /// A backend that answers every request with `200 ok`.
+    async fn serve_ok() -> u16 {
+        serve_raw(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok").await
+    }
+
+    /// A backend that answers every request with `response`, verbatim.
+    async fn serve_raw(response: &'static [u8]) -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    let _ = stream.read(&mut buf).await;
+                    let _ = stream.write_all(response).await;
+                });
+            }
+        });
+        port
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("wave-test-{}-{name}", uuid::Uuid::new_v4()))
+    }
+
+    /// A pid that belonged to a process which has exited and been reaped.
+    fn dead_pid() -> u32 {
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        pid
+    }
+
+    /// Config whose `Command` is this test binary, so `/proc/self/exe`
+    /// matches it.
+    fn reuse_config(marker: &Path) -> Config {
+        let exe = std::env::current_exe().unwrap();
+        test_config(json!({
+            "Core": { "DistDir": exe.parent().unwrap() },
+            "Watch": { "HealthcheckEndpoint": "/healthz" },
+            "Process": {
+                "Command": exe.file_name().unwrap().to_str().unwrap(),
+                "ReuseMarker": marker,
+                "ReuseMarkerTtlMs": 60_000,
+            },
+        }))
+    }
+
+    fn marker_for(cfg: &Config, pid: u32, port: u16, written_at_unix_ms: u64) -> ReuseMarker {
+        ReuseMarker {
+            pid,
+            port,
+            command: cfg
+                .process
+                .command_path(&cfg.core.dist_dir)
+                .display()
+                .to_string(),
+            written_at_unix_ms,
+        }
+    }
+
+    #[test]
+    fn reuse_marker_rejects_an_expired_marker() {
+        let cfg = reuse_config(Path::new("unused"));
+        let now = unix_millis();
+        let marker = marker_for(&cfg, std::process::id(), 1, now - 60_001);
+        assert_eq!(marker_rejection(&cfg, &marker, now), Some("it is too old"));
+        let marker = marker_for(&cfg, std::process::id(), 1, now - 60_000);
+        assert_eq!(marker_rejection(&cfg, &marker, now), None);
+    }
+
+    #[test]
+    fn reuse_marker_rejects_another_command() {
+        let cfg = reuse_config(Path::new("unused"));
+        let now = unix_millis();
+        let mut marker = marker_for(&cfg, std::process::id(), 1, now);
+        marker.command = "./dist/other".into();
+        assert_eq!(
+            marker_rejection(&cfg, &marker, now),
+            Some("it is for a different command")
+        );
+    }
+
+    #[test]
+    fn reuse_marker_rejects_a_dead_pid() {
+        let cfg = reuse_config(Path::new("unused"));
+        let now = unix_millis();
+        let marker = marker_for(&cfg, dead_pid(), 1, now);
+        assert_eq!(
+            marker_rejection(&cfg, &marker, now),
+            Some("its process is gone")
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reuse_marker_rejects_a_recycled_pid() {
+        let cfg = reuse_config(Path::new("unused"));
+        let now = unix_millis();
+        let mut child = Command::new("sleep").arg("5").spawn().unwrap();
+        let marker = marker_for(&cfg, child.id(), 1, now);
+        let rejection = marker_rejection(&cfg, &marker, now);
+        let _ = child.kill();
+        let _ = child.wait();
+        assert_eq!(rejection, Some("its pid now runs a different binary"));
+    }
+
+    #[tokio::test]
+    async fn reuse_marker_adopts_a_healthy_backend() {
+        let _globals = GLOBALS.lock().await;
+        let path = temp_path("marker.json");
+        let cfg = install(reuse_config(&path));
+        let port = serve_ok().await;
+        let pid = std::process::id();
+        let written = unix_millis() - 1_000;
+        std::fs::write(
+            &path,
+            serde_json::to_vec(&marker_for(&cfg, pid, port, written)).unwrap(),
+        )
+        .unwrap();
+
+        assert!(adopt_marked_backend(&cfg, &path).await);
+        assert!(READY.load(Ordering::Acquire));
+        let adopted: Vec<_> = std::mem::take(&mut *GO.lock().unwrap())
+            .into_iter()
+            .map(|b| (b.port, b.adopted, b.ready))
+            .collect();
+        assert_eq!(adopted, [(port, Some(pid), true)]);
+        // Adopting counts as a sighting, so the marker is fresh again.
+        let rewritten: ReuseMarker =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert!(rewritten.written_at_unix_ms > written);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn reuse_marker_is_ignored_when_unhealthy() {
+        let _globals = GLOBALS.lock().await;
+        let path = temp_path("marker.json");
+        let cfg = install(reuse_config(&path));
+        // Nothing listens here any more.
+        let port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let marker = marker_for(&cfg, std::process::id(), port, unix_millis());
+        std::fs::write(&path, serde_json::to_vec(&marker).unwrap()).unwrap();
+
+        assert!(!adopt_marked_backend(&cfg, &path).await);
+        assert!(GO.lock().unwrap().is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn reaping_notices_a_dead_adopted_backend() {
+        let _globals = GLOBALS.lock().await;
+        install(test_config(json!({})));
+        GO.lock().unwrap().push(Backend {
+            index: 0,
+            port: 1,
+            child: None,
+            adopted: Some(dead_pid()),
+            ready: true,
+            announced: None,
+        });
+        READY.store(true, Ordering::Release);
+        let restarts = RESTARTS.load(Ordering::Acquire);
+
+        reap_child();
+        assert!(GO.lock().unwrap().is_empty());
+        assert!(!READY.load(Ordering::Acquire));
+        assert_eq!(RESTARTS.swap(restarts, Ordering::AcqRel), restarts + 1);
+    }
+
+    #[tokio::test]
+    async fn a_crash_seen_by_a_request_counts_toward_max_restarts() {
+        let _globals = GLOBALS.lock().await;
+        let (backend, _) = run_shell(r#": > "$OUT""#, json!({}));
+        install(test_config(json!({
+            "Process": { "NoSpawn": true, "MaxRestarts": 0 },
+        })));
+        GO.lock().unwrap().push(backend);
+        READY.store(true, Ordering::Release);
+        RESTARTS.store(0, Ordering::Release);
+
+        // What `upstream_failed` does when the connection is reset.
+        lose_instance(0, false);
+        assert!(GO.lock().unwrap().is_empty());
+        assert_eq!(RESTARTS.load(Ordering::Acquire), 1);
+        let e = initialize().await.unwrap_err();
+        assert!(e.contains("exceeding MaxRestarts (0)"), "{e}");
+
+        // A backend that is merely slow to answer is not a crash.
+        GO.lock().unwrap().push(external_backend(0, 1));
+        lose_instance(0, false);
+        assert_eq!(RESTARTS.swap(0, Ordering::AcqRel), 1);
+    }
+
+    #[tokio::test]
+    async fn a_lost_instance_is_not_replaced_once_max_restarts_is_exceeded() {
+        let _globals = GLOBALS.lock().await;
+        let (crashed, _) = run_shell(r#": > "$OUT""#, json!({}));
+        install(test_config(json!({
+            "Core": { "DistDir": "/bin" },
+            "Process": {
+                "Command": "sh",
+                "Args": ["-c", "sleep 5"],
+                "Instances": 2,
+                "MaxRestarts": 0,
+            },
+        })));
+        GO.lock().unwrap().extend([crashed, external_backend(1, 1)]);
+        READY.store(true, Ordering::Release);
+        RESTARTS.store(0, Ordering::Release);
+
+        // The crash is counted before the replacement is attempted, so the
+        // replacement is refused rather than spawned.
+        lose_instance(0, false);
+        assert_eq!(RESTARTS.load(Ordering::Acquire), 1);
+        sleep(Duration::from_millis(300)).await;
+        let indices: Vec<_> = GO.lock().unwrap().iter().map(|b| b.index).collect();
+        assert_eq!(indices, [1]);
+        assert!(READY.load(Ordering::Acquire));
+        RESTARTS.store(0, Ordering::Release);
+    }
+
+    #[test]
+    fn the_crash_count_starts_over_after_a_quiet_period() {
+        let _globals = GLOBALS.blocking_lock();
+        let cfg = test_config(json!({ "Process": { "RestartResetMs": 60_000 } }));
+        RESTARTS.store(3, Ordering::Release);
+        LAST_CRASH_MS.store(unix_millis() - 1_000, Ordering::Release);
+        assert_eq!(count_crash(&cfg), 4);
+
+        LAST_CRASH_MS.store(unix_millis() - 60_000, Ordering::Release);
+        assert_eq!(count_crash(&cfg), 1);
+
+        // 0 keeps counting however long ago the last crash was.
+        let cfg = test_config(json!({ "Process": { "RestartResetMs": 0 } }));
+        LAST_CRASH_MS.store(1, Ordering::Release);
+        assert_eq!(count_crash(&cfg), 2);
+        RESTARTS.store(0, Ordering::Release);
+    }
+
+    /// An h2c backend that answers after a short pause, so requests overlap;
+    /// returns its port and how many connections it accepted.
+    async fn serve_h2c() -> (u16, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::Relaxed);
+                let service = hyper::service::service_fn(|_req| async {
+                    sleep(Duration::from_millis(50)).await;
+                    Ok::<_, Error>(hyper::Response::new(Full::new(Bytes::from_static(b"ok"))))
+                });
+                tokio::spawn(
+                    hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+        (port, accepted)
+    }
+
+    #[tokio::test]
+    async fn h2c_multiplexes_concurrent_requests_over_one_connection() {
+        let (port, accepted) = serve_h2c().await;
+        let cfg = test_config(json!({ "Http": { "Http2": true } }));
+        let client = build_proxy_client(&cfg);
+        let mut requests = tokio::task::JoinSet::new();
+        for _ in 0..8 {
+            let uri = backend_uri(&cfg, port, "/".parse().unwrap()).unwrap();
+            let req = upstream_request(
+                &hyper::Method::GET,
+                uri,
+                hyper::HeaderMap::new(),
+                full(Bytes::new()),
+            );
+            requests.spawn(client.request(req));
+        }
+        while let Some(res) = requests.join_next().await {
+            assert_eq!(res.unwrap().unwrap().status(), hyper::StatusCode::OK);
+        }
+        assert_eq!(accepted.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn health_checks_stay_on_http1_with_h2c() {
+        let port = serve_ok().await;
+        let cfg = test_config(json!({ "Http": { "Http2": true } }));
+        let uri = backend_uri(&cfg, port, "/healthz".parse().unwrap()).unwrap();
+        let req = hyper::Request::builder()
+            .uri(uri)
+            .body(Empty::new())
+            .unwrap();
+        let res = build_health_client(&cfg).request(req).await.unwrap();
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> hyper::HeaderMap {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect()
+    }
+
+    async fn body_bytes(res: Response<ResponseBody>) -> Bytes {
+        res.into_body().0.collect().await.unwrap().to_bytes()
+    }
+
+    const BROWSER: (&str, &str) = ("accept", "text/html,application/xhtml+xml;q=0.9,*/*;q=0.8");
+
+    #[tokio::test]
+    async fn maintenance_page_is_served_with_503() {
+        let path = temp_path("maintenance.html");
+        std::fs::write(&path, "<h1>back soon</h1>").unwrap();
+        let page = FileOnce::new();
+        let cfg = test_config(json!({ "Http": { "MaintenancePage": path } }));
+
+        let page = page.get(cfg.http.maintenance_page.as_deref());
+        let res = starting_response(&cfg, page, &headers(&[BROWSER]), "down".into()).unwrap();
+        assert_eq!(res.status(), hyper::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+        assert_eq!(res.headers()["cache-control"], "no-store");
+        assert_eq!(res.headers()[WAVE_ERROR_HEADER], "starting");
+        assert_eq!(body_bytes(res).await, "<h1>back soon</h1>");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn maintenance_page_falls_back_to_the_error_when_missing() {
+        let cfg = test_config(json!({ "Http": { "MaintenancePage": temp_path("missing.html") } }));
+        let page = FileOnce::new();
+        let page = page.get(cfg.http.maintenance_page.as_deref());
+        assert!(page.is_none());
+
+        let res = starting_response(&cfg, page, &headers(&[BROWSER]), "down".into()).unwrap();
+        assert_eq!(res.status(), hyper::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()["content-type"], "application/json");
+        let text = res.extensions().get::<ErrorBody>().unwrap().to_text();
+        assert_eq!(text, "starting: down\n");
+    }
+
+    #[test]
+    fn maintenance_page_is_read_once() {
+        let path = temp_path("maintenance.html");
+        std::fs::write(&path, "v1").unwrap();
+        let page = FileOnce::new();
+        assert_eq!(page.get(path.to_str()).unwrap(), "v1");
+        std::fs::write(&path, "v2").unwrap();
+        assert_eq!(page.get(path.to_str()).unwrap(), "v1");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(page.get(path.to_str()).unwrap(), "v1");
+    }
+
+    #[tokio::test]
+    async fn maintenance_page_is_not_served_to_api_clients() {
+        let path = temp_path("maintenance.html");
+        std::fs::write(&path, "<h1>back soon</h1>").unwrap();
+        let page = FileOnce::new();
+        let cfg = test_config(json!({ "Http": { "MaintenancePage": path } }));
+        let page = page.get(cfg.http.maintenance_page.as_deref());
+
+        let api = headers(&[("accept", "application/json")]);
+        let res = starting_response(&cfg, page, &api, "down".into()).unwrap();
+        assert_eq!(res.status(), hyper::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()["content-type"], "application/json");
+        let body: serde_json::Value = serde_json::from_slice(&body_bytes(res).await).unwrap();
+        assert_eq!(
+            body,
+            json!({ "error": { "code": "starting", "message": "down" } })
+        );
+
+        // Unless the page is for everyone.
+        let cfg = test_config(json!({
+            "Http": { "MaintenancePage": path, "MaintenancePageHtmlOnly": false },
+        }));
+        let res = starting_response(&cfg, page, &api, "down".into()).unwrap();
+        assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    fn external_backend(index: usize, port: u16) -> Backend {
+        Backend {
+            index,
+            port,
+            child: None,
+            adopted: None,
+            ready: true,
+            announced: None,
+        }
+    }
+
+    /// The error a proxied request to `port` fails with.
+    async fn client_error(cfg: &Config, port: u16) -> UpstreamError {
+        let uri = backend_uri(cfg, port, "/".parse().unwrap()).unwrap();
+        let req = upstream_request(
+            &hyper::Method::GET,
+            uri,
+            hyper::HeaderMap::new(),
+            full(Bytes::new()),
+        );
+        UpstreamError::Client(build_proxy_client(cfg).request(req).await.unwrap_err())
+    }
+
+    #[tokio::test]
+    async fn refused_connections_take_the_instance_out() {
+        let _globals = GLOBALS.lock().await;
+        let cfg = install(test_config(json!({})));
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let e = client_error(&cfg, port).await;
+        assert!(e.is_connect());
+        GO.lock().unwrap().push(external_backend(0, port));
+
+        let res = upstream_failed(e, 0).await.unwrap();
+        assert_eq!(res.status(), hyper::StatusCode::BAD_GATEWAY);
+        assert!(GO.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_broken_response_keeps_the_instance() {
+        let _globals = GLOBALS.lock().await;
+        let cfg = install(test_config(json!({})));
+        // Accepts, reads the request and hangs up without answering.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+            }
+        });
+        let e = client_error(&cfg, port).await;
+        assert!(!e.is_connect());
+        GO.lock().unwrap().push(external_backend(0, port));
+
+        let res = upstream_failed(e, 0).await.unwrap();
+        assert_eq!(res.status(), hyper::StatusCode::BAD_GATEWAY);
+        assert_eq!(GO.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn startup_exit_is_by_instance() {
+        let _globals = GLOBALS.blocking_lock();
+        install(test_config(json!({})));
+        // Two instances sharing a port (a socket, or a NoSpawn backend) are
+        // still told apart.
+        GO.lock().unwrap().push(external_backend(1, 8000));
+        assert_eq!(startup_exit(0).as_deref(), Some("reaped"));
+        assert_eq!(startup_exit(1), None);
+        GO.lock().unwrap().clear();
+    }
+
+    #[test]
+    fn backend_host_is_used_for_health_checks_and_requests() {
+        let cfg = test_config(json!({ "Core": { "DistDir": "dist", "BackendHost": "::1" } }));
+        assert_eq!(build_authority(&cfg, 9013).unwrap(), "[::1]:9013");
+        let health = backend_uri(&cfg, 9013, cfg.watch.readiness_endpoint().parse().unwrap());
+        assert_eq!(health.unwrap(), "http://[::1]:9013/healthz");
+        let request = backend_uri(&cfg, 9013, "/api/items?page=2".parse().unwrap());
+        assert_eq!(request.unwrap(), "http://[::1]:9013/api/items?page=2");
+
+        let cfg = test_config(json!({
+            "Core": { "DistDir": "dist", "BackendHost": "backend.internal" },
+        }));
+        assert_eq!(
+            build_authority(&cfg, 9014).unwrap(),
+            "backend.internal:9014"
+        );
+        // Over a socket the host only ends up in `Host`.
+        let cfg = test_config(json!({
+            "Core": { "DistDir": "dist", "BackendSocket": "/tmp/wave.sock" },
+        }));
+        assert_eq!(build_authority(&cfg, 9015).unwrap(), "localhost");
+    }
+
+    const NO_CONTENT: &[u8] = b"HTTP/1.1 204 No Content\r\nconnection: close\r\n\r\n";
+
+    #[tokio::test]
+    async fn expected_health_statuses_are_matched_exactly() {
+        let _globals = GLOBALS.lock().await;
+        let port = serve_raw(NO_CONTENT).await;
+        let deadline = || Instant::now() + Duration::from_millis(300);
+
+        let cfg = install(test_config(json!({
+            "Watch": { "HealthcheckEndpoint": "/healthz", "HealthcheckExpectStatus": [204] },
+        })));
+        assert!(wait_healthy(&cfg, None, port, deadline()).await.is_ok());
+
+        let cfg = install(test_config(json!({
+            "Watch": { "HealthcheckEndpoint": "/healthz", "HealthcheckExpectStatus": [200] },
+        })));
+        assert_eq!(
+            wait_healthy(&cfg, None, port, deadline()).await,
+            Err("health check timed out".into())
+        );
+    }
+
+    #[test]
+    fn health_status_defaults_to_any_success() {
+        let cfg = test_config(json!({}));
+        for status in [200, 204, 299] {
+            assert!(is_healthy_status(
+                &cfg,
+                hyper::StatusCode::from_u16(status).unwrap()
+            ));
+        }
+        for status in [302, 404, 500] {
+            assert!(!is_healthy_status(
+                &cfg,
+                hyper::StatusCode::from_u16(status).unwrap()
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn a_missing_health_route_is_reported_after_the_window() {
+        let _globals = GLOBALS.lock().await;
+        let port =
+            serve_raw(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                .await;
+        let cfg = install(test_config(json!({})));
+        let start = Instant::now();
+        assert_eq!(
+            wait_healthy(&cfg, None, port, start + Duration::from_secs(30)).await,
+            Err("backend up but health endpoint /healthz returned 404 \u{2014} check HealthcheckEndpoint".into())
+        );
+        let waited = start.elapsed();
+        assert!(
+            (HEALTH_MISCONFIG_WINDOW..HEALTH_MISCONFIG_WINDOW + Duration::from_secs(2))
+                .contains(&waited),
+            "{waited:?}"
+        );
+
+        let cfg = install(test_config(json!({
+            "Watch": { "HealthcheckEndpoint": "/healthz", "ReadinessEndpoint": "/ready" },
+        })));
+        let result = wait_healthy(&cfg, None, port, Instant::now() + Duration::from_secs(30)).await;
+        assert_eq!(
+            result,
+            Err("backend up but health endpoint /ready returned 404 \u{2014} check ReadinessEndpoint".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn a_backend_that_never_answers_times_out() {
+        let _globals = GLOBALS.lock().await;
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let cfg = install(test_config(json!({})));
+        assert_eq!(
+            wait_healthy(
+                &cfg,
+                None,
+                port,
+                Instant::now() + Duration::from_millis(300)
+            )
+            .await,
+            Err("health check timed out".into())
+        );
+    }
+
+    /// Spawns `sh -c script` as instance 0 under `process` and waits for it,
+    /// returning the backend and what the script wrote to `$OUT`.
+    fn run_shell(script: &str, mut process: serde_json::Value) -> (Backend, String) {
+        let out = temp_path("out");
+        process["Command"] = json!("sh");
+        process["Args"] = json!(["-c", script]);
+        process["Env"]["OUT"] = json!(out);
+        let cfg = test_config(json!({
+            "Core": { "DistDir": "/bin", "Port": 0 },
+            "Process": process,
+        }));
+        let mut backend = spawn_instance(&cfg, 0).unwrap();
+        assert!(backend.child.as_mut().unwrap().wait().unwrap().success());
+        let written = std::fs::read_to_string(&out).unwrap();
+        let _ = std::fs::remove_file(&out);
+        (backend, written)
+    }
+
+    #[test]
+    fn process_env_reaches_the_child_and_port_wins() {
+        let (backend, env) = run_shell(
+            r#"printf '%s\n' "$FLAG" "$FORWARDED" "$PORT" > "$OUT""#,
+            json!({ "Env": { "FLAG": "on", "FORWARDED": "${PATH}", "PORT": "1" } }),
+        );
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(env, format!("on\n{path}\n{}\n", backend.port));
+        assert_ne!(backend.port, 1);
+    }
+
+    #[test]
+    fn the_child_runs_in_the_working_dir() {
+        let dir = temp_path("cwd");
+        std::fs::create_dir(&dir).unwrap();
+        let (_, cwd) = run_shell(r#"pwd -P > "$OUT""#, json!({ "WorkingDir": dir }));
+        assert_eq!(
+            cwd.trim_end(),
+            dir.canonicalize().unwrap().to_str().unwrap()
+        );
+        std::fs::remove_dir(&dir).unwrap();
+
+        let (_, cwd) = run_shell(r#"pwd -P > "$OUT""#, json!({}));
+        let own = std::env::current_dir().unwrap().canonicalize().unwrap();
+        assert_eq!(cwd.trim_end(), own.to_str().unwrap());
+    }
+
+    fn request_parts(uri: &str, headers: &[(&str, &str)]) -> hyper::http::request::Parts {
+        let mut req = hyper::Request::get(uri).body(()).unwrap();
+        *req.headers_mut() = self::headers(headers);
+        req.into_parts().0
+    }
+
+    #[test]
+    fn connection_tokens_are_hop_by_hop_for_the_request() {
+        let parts = request_parts(
+            "/",
+            &[
+                ("connection", "Upgrade, Foo"),
+                ("connection", "x-extra"),
+                ("upgrade", "something"),
+                ("foo", "1"),
+                ("x-extra", "2"),
+                ("keep-alive", "timeout=5"),
+                ("bar", "3"),
+            ],
+        );
+        assert_eq!(
+            connection_tokens(&parts.headers),
+            ["upgrade", "foo", "x-extra"]
+        );
+
+        let _globals = GLOBALS.blocking_lock();
+        let cfg = install(test_config(
+            json!({ "Http": { "ForwardedHeaders": false } }),
+        ));
+        let sent = upstream_headers(&parts, &cfg, false);
+        let names: Vec<_> = sent.keys().map(|k| k.as_str()).collect();
+        assert_eq!(names, ["bar"]);
+        // A WebSocket handshake keeps what it needs to upgrade.
+        let sent = upstream_headers(&parts, &cfg, true);
+        assert!(sent.contains_key("connection") && sent.contains_key("upgrade"));
+        assert!(!sent.contains_key("foo"));
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_first_request_does_not_abort_the_spawn() {
+        let _globals = GLOBALS.lock().await;
+        // Health checks are answered slowly, so the first caller is still
+        // waiting on one when it goes away.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    let _ = stream.read(&mut buf).await;
+                    sleep(Duration::from_millis(300)).await;
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                        .await;
+                });
+            }
+        });
+        let out = temp_path("starts");
+        install(test_config(json!({
+            "Core": { "DistDir": "/bin", "Port": port },
+            "Process": {
+                "Command": "sh",
+                "Args": ["-c", r#"echo started >> "$OUT"; exec sleep 30"#],
+                "Env": { "OUT": out },
+            },
+        })));
+
+        let first = tokio::spawn(ensure_ready());
+        sleep(Duration::from_millis(100)).await;
+        first.abort();
+        assert!(first.await.unwrap_err().is_cancelled());
+        ensure_ready().await.unwrap();
+
+        assert!(READY.load(Ordering::Acquire));
+        let children = GO
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|b| b.child.is_some())
+            .count();
+        assert_eq!(children, 1);
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "started\n");
+        kill_child().await;
+        let _ = std::fs::remove_file(&out);
+    }
+
+    #[test]
+    fn locations_naming_the_backend_point_at_the_client_host() {
+        let backend = hyper::http::uri::Authority::from_static("127.0.0.1:8080");
+        let req = request_parts(
+            "/login",
+            &[("host", "example.com"), ("x-forwarded-proto", "https")],
+        );
+        let rewrite = |location| rewrite_location(location, &backend, &req);
+        assert_eq!(
+            rewrite("http://127.0.0.1:8080/login?next=/a#top").unwrap(),
+            "https://example.com/login?next=/a#top"
+        );
+        assert_eq!(
+            rewrite("http://127.0.0.1:8080").unwrap(),
+            "https://example.com/"
+        );
+        assert_eq!(
+            rewrite("http://127.0.0.1:8080?x=1").unwrap(),
+            "https://example.com?x=1"
+        );
+        // Relative values already resolve against the client's host.
+        assert!(rewrite("/login").is_none());
+        assert!(rewrite("login?x=http://127.0.0.1:8080/").is_none());
+        assert!(rewrite("/login?next=http://127.0.0.1:8080/").is_none());
+        assert!(rewrite("//127.0.0.1:8080/login").is_none());
+        assert!(rewrite("https://other.example/login").is_none());
+        assert!(rewrite("http://127.0.0.1:8081/login").is_none());
+    }
+
+    #[test]
+    fn header_rewrites_replace_each_value() {
+        let cfg = test_config(json!({
+            "Http": {
+                "HeaderRewrites": [
+                    { "Header": "set-cookie", "Match": "Domain=internal", "Replace": "Domain=example.com" },
+                    { "Header": "link", "Match": "^<http://([^/]+)/", "Replace": "<https://$1/" },
+                ],
+            },
+        }));
+        let mut headers = hyper::HeaderMap::new();
+        headers.append("set-cookie", "a=1; Domain=internal".parse().unwrap());
+        headers.append("set-cookie", "b=2".parse().unwrap());
+        headers.append("link", "<http://cdn/app.js>; rel=preload".parse().unwrap());
+        apply_header_rewrites(&mut headers, &cfg.http.header_rewrites);
+        let cookies: Vec<_> = headers.get_all("set-cookie").iter().collect();
+        assert_eq!(cookies, ["a=1; Domain=example.com", "b=2"]);
+        assert_eq!(headers["link"], "<https://cdn/app.js>; rel=preload");
+    }
+
+    #[test]
+    fn plain_text_errors_need_a_clear_preference() {
+        let prefers = |accept: &str| prefers_plain_text(&headers(&[("accept", accept)]));
+        assert!(prefers("text/plain"));
+        assert!(prefers("text/*, application/json;q=0.5"));
+        assert!(!prefers("application/json, text/plain;q=0.9"));
+        assert!(!prefers("text/plain;q=0.5, application/json;q=0.5"));
+        assert!(!prefers("*/*"));
+        assert!(!prefers(BROWSER.1));
+        assert!(!prefers_plain_text(&hyper::HeaderMap::new()));
+    }
+
+    /// Spawns `sh -c script` as instance 0 of the installed config with
+    /// `PortPattern` set, registered in `GO`, and returns its port.
+    fn spawn_announcing(script: &str, timeout_ms: u64) -> (Arc<Config>, u16) {
+        let cfg = install(test_config(json!({
+            "Core": { "DistDir": "/bin", "Port": 0 },
+            "Process": {
+                "Command": "sh",
+                "Args": ["-c", script],
+                "PortPattern": r"listening on :(\d+)",
+                "PortPatternTimeoutMs": timeout_ms,
+            },
+        })));
+        let backend = spawn_instance(&cfg, 0).unwrap();
+        let port = backend.port;
+        GO.lock().unwrap().push(backend);
+        (cfg, port)
+    }
+
+    #[tokio::test]
+    async fn an_announced_port_replaces_the_configured_one() {
+        let _globals = GLOBALS.lock().await;
+        let (cfg, port) = spawn_announcing(
+            "echo starting; sleep 0.1; echo 'listening on :41234'; exec sleep 30",
+            5_000,
+        );
+        assert_eq!(announced_port(&cfg, 0, port).await, 41234);
+        assert_eq!(GO.lock().unwrap()[0].port, 41234);
+        kill_child().await;
+
+        let (cfg, port) = spawn_announcing("echo 'listening on port 1'; exec sleep 30", 200);
+        let start = Instant::now();
+        assert_eq!(announced_port(&cfg, 0, port).await, port);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(GO.lock().unwrap()[0].port, port);
+        kill_child().await;
+    }
+
+    /// Spawns `sh -c script` as a registered instance 0 with `ReadySignal`
+    /// at `signal`.
+    fn spawn_signalling(script: &str, signal: &Path) -> (Arc<Config>, u16) {
+        let cfg = install(test_config(json!({
+            "Core": { "DistDir": "/bin", "Port": 0 },
+            "Watch": { "HealthcheckEndpoint": "/healthz", "ReadySignal": signal },
+            "Process": { "Command": "sh", "Args": ["-c", script] },
+        })));
+        let backend = spawn_instance(&cfg, 0).unwrap();
+        let port = backend.port;
+        GO.lock().unwrap().push(backend);
+        (cfg, port)
+    }
+
+    #[tokio::test]
+    async fn a_touched_ready_file_ends_the_wait() {
+        let _globals = GLOBALS.lock().await;
+        let signal = temp_path("ready");
+        std::fs::write(&signal, "stale").unwrap();
+        let (cfg, port) = spawn_signalling(
+            r#"sleep 0.3; touch "$WAVE_READY_FILE"; exec sleep 30"#,
+            &signal,
+        );
+        // The stale signal from a previous run is gone before the child starts.
+        assert!(!signal.exists());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert!(wait_healthy(&cfg, Some(0), port, deadline).await.is_ok());
+        kill_child().await;
+
+        let (cfg, port) = spawn_signalling("exec sleep 30", &signal);
+        let deadline = Instant::now() + Duration::from_millis(300);
+        assert_eq!(
+            wait_healthy(&cfg, Some(0), port, deadline).await,
+            Err(format!(
+                "ready signal {} did not arrive in time",
+                signal.display()
+            ))
+        );
+        kill_child().await;
+        let _ = std::fs::remove_file(&signal);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn a_line_on_a_ready_pipe_ends_the_wait() {
+        let _globals = GLOBALS.lock().await;
+        let signal = temp_path("ready.fifo");
+        let path = std::ffi::CString::new(signal.to_str().unwrap()).unwrap();
+        // SAFETY: `path` is a valid NUL-terminated string.
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+        let (cfg, port) = spawn_signalling(
+            r#"sleep 0.2; echo ready > "$WAVE_READY_FILE"; exec sleep 30"#,
+            &signal,
+        );
+        // A pipe is kept, not removed as stale.
+        assert!(signal.exists());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert!(wait_healthy(&cfg, Some(0), port, deadline).await.is_ok());
+        kill_child().await;
+        let _ = std::fs::remove_file(&signal);
+    }
+
+    #[tokio::test]
+    async fn requests_wait_out_a_slow_restart_within_the_window() {
+        let _globals = GLOBALS.lock().await;
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let cfg = install(test_config(json!({
+            "Core": { "DistDir": "dist", "Port": port },
+            "Watch": {
+                "HealthcheckEndpoint": "/healthz",
+                "HealthcheckTimeoutMs": 200,
+                "HealthcheckPollMs": 50,
+            },
+            "Process": { "NoSpawn": true },
+        })));
+        RESTARTS.store(0, Ordering::Release);
+
+        // Without a window the first failed attempt is the answer.
+        assert_eq!(
+            ready_within(&cfg, Duration::ZERO).await,
+            Err("health check timed out".into())
+        );
+
+        // The backend comes back after several attempts have failed.
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(600)).await;
+            let listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+                .await
+                .unwrap();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                    .await;
+            }
+        });
+        let start = Instant::now();
+        ready_within(&cfg, Duration::from_secs(5)).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(600));
+        assert!(READY.load(Ordering::Acquire));
+        kill_child().await;
+    }
+
+    #[test]
+    fn the_inbound_version_goes_upstream_with_the_forwarded_headers() {
+        let _globals = GLOBALS.blocking_lock();
+        let mut parts = request_parts("/", &[]);
+        let version = |cfg, parts: &hyper::http::request::Parts| {
+            upstream_headers(parts, cfg, false)
+                .get("x-forwarded-proto-version")
+                .map(|v| v.to_str().unwrap().to_owned())
+        };
+        let cfg = install(test_config(
+            json!({ "Http": { "ForwardedProtoVersion": true } }),
+        ));
+        assert_eq!(version(&cfg, &parts).as_deref(), Some("HTTP/1.1"));
+        parts.version = hyper::Version::HTTP_2;
+        assert_eq!(version(&cfg, &parts).as_deref(), Some("HTTP/2"));
+        parts.version = hyper::Version::HTTP_10;
+        assert_eq!(version(&cfg, &parts).as_deref(), Some("HTTP/1.0"));
+
+        let off = install(test_config(json!({})));
+        assert_eq!(version(&off, &parts), None);
+        let no_forwarding = install(test_config(json!({
+            "Http": { "ForwardedProtoVersion": true, "ForwardedHeaders": false },
+        })));
+        assert_eq!(version(&no_forwarding, &parts), None);
+    }
+
+    fn forwarded(headers: &[(&str, &str)]) -> String {
+        let mut out = hyper::HeaderMap::new();
+        apply_rfc7239_forwarded(&request_parts("/", headers), &mut out);
+        out[hyper::header::FORWARDED].to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn forwarded_elements_follow_rfc_7239() {
+        assert_eq!(
+            forwarded(&[("x-real-ip", "203.0.113.7"), ("host", "example.com")]),
+            "for=203.0.113.7;proto=http;host=example.com"
+        );
+        assert_eq!(
+            forwarded(&[
+                ("x-vercel-forwarded-for", "2001:db8::1"),
+                ("host", "example.com:8443"),
+                ("x-forwarded-proto", "https"),
+            ]),
+            r#"for="[2001:db8::1]";proto=https;host="example.com:8443""#
+        );
+        // Without a usable client address the node is `unknown`, unless it
+        // is already an obfuscated identifier.
+        assert_eq!(forwarded(&[]), "for=unknown;proto=http");
+        assert_eq!(
+            forwarded(&[("x-real-ip", "not an ip")]),
+            "for=unknown;proto=http"
+        );
+        assert_eq!(
+            forwarded(&[("x-real-ip", "_edge1")]),
+            "for=_edge1;proto=http"
+        );
+    }
+
+    #[test]
+    fn forwarded_chains_append_this_hop() {
+        assert_eq!(
+            forwarded(&[
+                (
+                    "forwarded",
+                    r#"for=198.51.100.1;proto=https, for="[2001:db8::2]""#
+                ),
+                ("forwarded", "for=192.0.2.9"),
+                ("x-real-ip", "203.0.113.7"),
+            ]),
+            r#"for=198.51.100.1;proto=https, for="[2001:db8::2]", for=192.0.2.9, for=203.0.113.7;proto=http"#
+        );
+        assert_eq!(forwarded_value("a.b-c_1"), "a.b-c_1");
+        assert_eq!(forwarded_value(r#"say "hi"\"#), r#""say \"hi\"\\""#);
+        assert_eq!(forwarded_value(""), r#""""#);
+    }
+
+    #[test]
+    fn forwarded_style_picks_the_headers() {
+        let _globals = GLOBALS.blocking_lock();
+        let parts = request_parts("/", &[("x-real-ip", "203.0.113.7")]);
+        for (style, x_forwarded, rfc7239) in [
+            ("x-forwarded", true, false),
+            ("rfc7239", false, true),
+            ("both", true, true),
+        ] {
+            let cfg = install(test_config(json!({ "Http": { "ForwardedStyle": style } })));
+            let sent = upstream_headers(&parts, &cfg, false);
+            assert_eq!(sent.contains_key("x-forwarded-for"), x_forwarded, "{style}");
+            assert_eq!(sent.contains_key("forwarded"), rfc7239, "{style}");
+        }
+    }
+
+    #[test]
+    fn tracking_params_are_stripped_and_the_rest_kept_verbatim() {
+        let cfg = test_config(json!({ "Http": { "StripQueryParams": ["utm_*", "fbclid"] } }));
+        let strip = |query| strip_query_params(&cfg, query).map(|q| q.into_owned());
+        assert_eq!(
+            strip("q=a%20b+c&utm_source=x&page=2&fbclid=abc&x=%2F").as_deref(),
+            Some("q=a%20b+c&page=2&x=%2F")
+        );
+        // Names are matched decoded; flags without a value count too.
+        assert_eq!(
+            strip("utm%5Fmedium=x&fbclid&keep=1").as_deref(),
+            Some("keep=1")
+        );
+        assert_eq!(
+            strip("fbclid2=x&utm=1&%zz=2").as_deref(),
+            Some("fbclid2=x&utm=1&%zz=2")
+        );
+        assert_eq!(strip("a=1&&b=2").as_deref(), Some("a=1&&b=2"));
+        // Nothing left means no query at all.
+        assert_eq!(strip("utm_source=x&utm_campaign=y&fbclid=z"), None);
+        assert_eq!(strip("utm_source=x&&"), None);
+
+        let off = test_config(json!({}));
+        assert_eq!(
+            strip_query_params(&off, "utm_source=x").as_deref(),
+            Some("utm_source=x")
+        );
+    }
+
+    /// A port that accepts no more connections: its backlog is full and
+    /// nothing accepts, so new connects get no answer at all.
+    async fn blackholed_port() -> (tokio::net::TcpListener, Vec<tokio::net::TcpStream>) {
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(0).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut queued = Vec::new();
+        while let Ok(Ok(stream)) = tokio::time::timeout(
+            Duration::from_millis(100),
+            tokio::net::TcpStream::connect(addr),
+        )
+        .await
+        {
+            queued.push(stream);
+        }
+        (listener, queued)
+    }
+
+    #[tokio::test]
+    async fn connects_give_up_after_the_connect_timeout() {
+        let _globals = GLOBALS.lock().await;
+        let (listener, _queued) = blackholed_port().await;
+        let port = listener.local_addr().unwrap().port();
+        let cfg = install(test_config(json!({ "Http": { "ConnectTimeoutMs": 200 } })));
+        let uri = backend_uri(&cfg, port, "/".parse().unwrap()).unwrap();
+        let timely = |waited: Duration| {
+            (Duration::from_millis(200)..Duration::from_secs(1)).contains(&waited)
+        };
+
+        let start = Instant::now();
+        let e = build_proxy_client(&cfg)
+            .request(upstream_request(
+                &hyper::Method::GET,
+                uri.clone(),
+                hyper::HeaderMap::new(),
+                full(Bytes::new()),
+            ))
+            .await
+            .unwrap_err();
+        assert!(e.is_connect(), "{e:?}");
+        assert!(timely(start.elapsed()), "{:?}", start.elapsed());
+
+        // Health polls fail just as fast instead of waiting on the OS.
+        let start = Instant::now();
+        let req = hyper::Request::get(uri).body(Empty::new()).unwrap();
+        let e = build_health_client(&cfg).request(req).await.unwrap_err();
+        assert!(e.is_connect(), "{e:?}");
+        assert!(timely(start.elapsed()), "{:?}", start.elapsed());
+    }
+
+    /// A `200` with `headers` and a body of `body`, as the backend sent it.
+    fn backend_response(headers: &[(&str, &str)], body: &'static [u8]) -> Response<ResponseBody> {
+        let mut response = Response::new(ResponseBody(full(Bytes::from_static(body))));
+        *response.headers_mut() = self::headers(headers);
+        response
+    }
+
+    /// Passes `response` through `fill_cache` under `key` and streams its
+    /// body to the end, which is when it is stored.
+    async fn fill(cfg: &Config, key: &str, req: &[(&str, &str)], response: Response<ResponseBody>) {
+        let cache = cfg.cache.as_ref().unwrap();
+        let response = fill_cache(response, key.into(), &headers(req), cache);
+        body_bytes(response).await;
+    }
+
+    /// The body of the variant of `key` a request with `req` headers gets.
+    async fn cached_body(key: &str, req: &[(&str, &str)]) -> Option<Bytes> {
+        let hit = CACHE.lock().unwrap().get(key, &headers(req), false)?;
+        Some(body_bytes(hit).await)
+    }
+
+    fn cache_config(max_entries: usize) -> Config {
+        let cfg = test_config(json!({ "Cache": { "MaxEntries": max_entries } }));
+        *CACHE.lock().unwrap() = ResponseCache::new();
+        cfg
+    }
+
+    #[tokio::test]
+    async fn cached_responses_are_served_until_they_go_stale() {
+        let _globals = GLOBALS.lock().await;
+        let cfg = cache_config(8);
+        let fresh = [("cache-control", "max-age=60"), ("age", "10")];
+        fill(&cfg, "h/fresh", &[], backend_response(&fresh, b"fresh")).await;
+        let hit = CACHE
+            .lock()
+            .unwrap()
+            .get("h/fresh", &headers(&[]), false)
+            .unwrap();
+        assert_eq!(hit.headers()["age"], "10");
+        assert_eq!(body_bytes(hit).await, "fresh");
+
+        // `s-maxage` wins for a shared cache; an `Age` past it means the
+        // response arrived stale.
+        let shared = [("cache-control", "max-age=60, s-maxage=1")];
+        fill(&cfg, "h/shared", &[], backend_response(&shared, b"shared")).await;
+        let stale = [("cache-control", "max-age=60"), ("age", "60")];
+        fill(&cfg, "h/stale", &[], backend_response(&stale, b"stale")).await;
+        assert_eq!(CACHE.lock().unwrap().len, 2);
+        assert!(cached_body("h/stale", &[]).await.is_none());
+
+        sleep(Duration::from_millis(1100)).await;
+        assert!(cached_body("h/shared", &[]).await.is_none());
+        assert!(cached_body("h/fresh", &[]).await.is_some());
+        let cache = CACHE.lock().unwrap();
+        assert_eq!(cache.len, 1);
+        assert!(!cache.entries.contains_key("h/shared"));
+    }
+
+    #[tokio::test]
+    async fn variants_follow_the_request_headers_vary_names() {
+        let _globals = GLOBALS.lock().await;
+        let cfg = cache_config(8);
+        let vary = [("cache-control", "max-age=60"), ("vary", "Accept-Language")];
+        let en = [("accept-language", "en")];
+        let de = [("accept-language", "de")];
+        fill(&cfg, "h/v", &en, backend_response(&vary, b"hello")).await;
+        fill(&cfg, "h/v", &de, backend_response(&vary, b"hallo")).await;
+        assert_eq!(cached_body("h/v", &en).await.unwrap(), "hello");
+        assert_eq!(cached_body("h/v", &de).await.unwrap(), "hallo");
+        assert!(
+            cached_body("h/v", &[("accept-language", "fr")])
+                .await
+                .is_none()
+        );
+        // A request without the header is a variant of its own.
+        assert!(cached_body("h/v", &[]).await.is_none());
+
+        // Refetching a variant replaces it rather than adding another.
+        fill(&cfg, "h/v", &en, backend_response(&vary, b"hi")).await;
+        assert_eq!(cached_body("h/v", &en).await.unwrap(), "hi");
+        assert_eq!(CACHE.lock().unwrap().len, 2);
+
+        let star = [("cache-control", "max-age=60"), ("vary", "*")];
+        fill(&cfg, "h/star", &en, backend_response(&star, b"any")).await;
+        assert!(!CACHE.lock().unwrap().entries.contains_key("h/star"));
+    }
+
+    #[tokio::test]
+    async fn private_and_personal_responses_are_never_stored() {
+        let _globals = GLOBALS.lock().await;
+        let cfg = cache_config(8);
+        for (i, response) in [
+            &[("cache-control", "max-age=60, no-store")][..],
+            &[("cache-control", "max-age=60, no-cache")],
+            &[("cache-control", "private, max-age=60")],
+            &[("cache-control", "max-age=60"), ("set-cookie", "id=1")],
+            &[("cache-control", "max-age=0")],
+            &[],
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            fill(
+                &cfg,
+                &format!("h/{i}"),
+                &[],
+                backend_response(response, b"x"),
+            )
+            .await;
+        }
+        let mut redirect = backend_response(&[("cache-control", "max-age=60")], b"");
+        *redirect.status_mut() = hyper::StatusCode::FOUND;
+        fill(&cfg, "h/redirect", &[], redirect).await;
+        assert_eq!(CACHE.lock().unwrap().len, 0);
+
+        let get = hyper::Method::GET;
+        assert!(is_cacheable_request(&get, &headers(&[])));
+        assert!(is_cacheable_request(&hyper::Method::HEAD, &headers(&[])));
+        assert!(!is_cacheable_request(&hyper::Method::POST, &headers(&[])));
+        for refused in [
+            ("authorization", "Bearer t"),
+            ("cache-control", "no-store"),
+            ("cache-control", "max-age=0, No-Cache"),
+        ] {
+            assert!(
+                !is_cacheable_request(&get, &headers(&[refused])),
+                "{refused:?}"
+            );
+        }
+        let upgrade = headers(&[("connection", "upgrade"), ("upgrade", "websocket")]);
+        assert!(!is_cacheable_request(&get, &upgrade));
+    }
+
+    #[tokio::test]
+    async fn the_least_recently_used_entry_is_evicted_past_max_entries() {
+        let _globals = GLOBALS.lock().await;
+        let cfg = cache_config(2);
+        let cacheable = [("cache-control", "max-age=60")];
+        fill(&cfg, "h/a", &[], backend_response(&cacheable, b"a")).await;
+        fill(&cfg, "h/b", &[], backend_response(&cacheable, b"b")).await;
+        // Using `a` leaves `b` the oldest.
+        assert!(cached_body("h/a", &[]).await.is_some());
+        fill(&cfg, "h/c", &[], backend_response(&cacheable, b"c")).await;
+        assert!(cached_body("h/b", &[]).await.is_none());
+        assert!(cached_body("h/a", &[]).await.is_some());
+        assert!(cached_body("h/c", &[]).await.is_some());
+        assert_eq!(CACHE.lock().unwrap().len, 2);
+
+        // Variants of one key count as entries of their own.
+        let vary = [("cache-control", "max-age=60"), ("vary", "accept")];
+        fill(
+            &cfg,
+            "h/v",
+            &[("accept", "1")],
+            backend_response(&vary, b"1"),
+        )
+        .await;
+        fill(
+            &cfg,
+            "h/v",
+            &[("accept", "2")],
+            backend_response(&vary, b"2"),
+        )
+        .await;
+        let cache = CACHE.lock().unwrap();
+        assert_eq!(cache.len, 2);
+        assert_eq!(cache.entries.keys().collect::<Vec<_>>(), ["h/v"]);
+    }
+
+    #[tokio::test]
+    async fn invalidation_drops_every_query_of_a_resource() {
+        let _globals = GLOBALS.lock().await;
+        let cfg = cache_config(8);
+        let cacheable = [("cache-control", "max-age=60")];
+        for key in ["a/items", "a/items?page=2", "a/items/1", "b/items"] {
+            fill(&cfg, key, &[], backend_response(&cacheable, b"x")).await;
+        }
+        CACHE.lock().unwrap().invalidate("a/items");
+        let cache = CACHE.lock().unwrap();
+        assert_eq!(cache.len, 2);
+        assert_eq!(
+            cache.entries.keys().collect::<Vec<_>>(),
+            ["a/items/1", "b/items"]
+        );
+    }
+
+    #[test]
+    fn static_paths_never_step_outside_the_root() {
+        assert_eq!(
+            static_relative_path("/css/a%20b.css"),
+            Some(PathBuf::from("css/a b.css"))
+        );
+        assert_eq!(static_relative_path("/./a//b/"), Some(PathBuf::from("a/b")));
+        assert_eq!(static_relative_path("/"), None);
+        for refused in [
+            "/../etc/passwd",
+            "/a/../../etc/passwd",
+            "/%2e%2e/etc/passwd",
+            "/%2E%2E%2Fetc%2Fpasswd",
+            "/a%2F..%2F..%2Fetc",
+            "/..\\etc\\passwd",
+            "/a%5C..%5Cb",
+            "/a%00.html",
+            "/%ff%fe",
+            "/a%",
+            "/a%2",
+            "/a%zz",
+            "/a%+1",
+            "/a%-1",
+        ] {
+            assert_eq!(static_relative_path(refused), None, "{refused}");
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_static_only_serves_files_under_its_dir() {
+        let base = temp_path("static");
+        let root = base.join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub/inside.txt"), "inside").unwrap();
+        std::fs::write(base.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(base.join("secret.txt"), root.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink(&base, root.join("up")).unwrap();
+        let dir = root.to_str().unwrap();
+
+        let serve = |path: &str| {
+            let req = hyper::Request::get(path).body(()).unwrap();
+            async move { serve_static(&req, dir).await.map(Result::unwrap) }
+        };
+        let response = serve("/sub/inside.txt").await.unwrap();
+        assert_eq!(body_bytes(response).await, "inside");
+        assert!(serve("/sub/%69nside.txt").await.is_some());
+        for path in [
+            "/../secret.txt",
+            "/sub/../../secret.txt",
+            "/%2e%2e/secret.txt",
+            "/sub%2F..%2F..%2Fsecret.txt",
+            "/..%5Csecret.txt",
+            "/link.txt",
+            "/up/secret.txt",
+            "/sub",
+            "/sub/inside.txt%00",
+            "/sub/missing.txt",
+        ] {
+            assert!(serve(path).await.is_none(), "{path}");
+        }
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    fn breaker_config(threshold: u32, window_ms: u64, cooldown_ms: u64) -> Config {
+        test_config(json!({
+            "Http": {
+                "CircuitBreakerThreshold": threshold,
+                "CircuitBreakerWindowMs": window_ms,
+                "CircuitBreakerCooldownMs": cooldown_ms,
+            },
+        }))
+    }
+
+    #[tokio::test]
+    async fn the_breaker_opens_on_failures_within_the_window() {
+        let _globals = GLOBALS.lock().await;
+        let cfg = breaker_config(3, 200, 60_000);
+        let breaker = Breaker::new();
+        breaker.record_failure(&cfg);
+        breaker.record_failure(&cfg);
+        // The window has passed, so counting starts over.
+        sleep(Duration::from_millis(250)).await;
+        breaker.record_failure(&cfg);
+        breaker.record_failure(&cfg);
+        assert!(!breaker.is_open());
+        assert!(breaker.allow(&cfg).is_ok());
+
+        READY.store(true, Ordering::Release);
+        breaker.record_failure(&cfg);
+        assert!(breaker.is_open());
+        assert!(!READY.load(Ordering::Acquire));
+        let wait = breaker.allow(&cfg).unwrap_err();
+        assert!(wait > Duration::from_secs(59), "{wait:?}");
+
+        // A success in between means the failures were not consecutive.
+        let cfg = breaker_config(2, 60_000, 60_000);
+        let breaker = Breaker::new();
+        breaker.record_failure(&cfg);
+        breaker.record_success();
+        breaker.record_failure(&cfg);
+        assert!(!breaker.is_open());
+
+        let off = breaker_config(0, 60_000, 60_000);
+        for _ in 0..10 {
+            breaker.record_failure(&off);
+        }
+        assert!(!breaker.is_open());
+        assert!(breaker.allow(&off).is_ok());
+    }
+
+    #[tokio::test]
+    async fn an_open_breaker_probes_after_its_cooldown() {
+        let _globals = GLOBALS.lock().await;
+        let cfg = breaker_config(1, 60_000, 100);
+        let breaker = Breaker::new();
+        breaker.record_failure(&cfg);
+        assert!(breaker.allow(&cfg).is_err());
+
+        sleep(Duration::from_millis(120)).await;
+        // Half-open: one probe goes through while the rest keep waiting.
+        assert!(breaker.allow(&cfg).is_ok());
+        assert!(breaker.allow(&cfg).is_err());
+        // A failed probe re-opens the circuit for another cooldown.
+        breaker.record_failure(&cfg);
+        assert!(breaker.is_open());
+        assert!(breaker.allow(&cfg).is_err());
+
+        sleep(Duration::from_millis(120)).await;
+        assert!(breaker.allow(&cfg).is_ok());
+        breaker.record_success();
+        assert!(!breaker.is_open());
+        assert!(breaker.allow(&cfg).is_ok());
+        assert!(breaker.allow(&cfg).is_ok());
+        assert_eq!(breaker.lock().failures, 0);
+    }
+
+    #[tokio::test]
+    async fn a_probe_that_never_reports_back_expires() {
+        let _globals = GLOBALS.lock().await;
+        let cfg = breaker_config(1, 60_000, 100);
+        let breaker = Breaker::new();
+        breaker.record_failure(&cfg);
+        sleep(Duration::from_millis(120)).await;
+        assert!(breaker.allow(&cfg).is_ok());
+        assert!(breaker.allow(&cfg).is_err());
+        sleep(Duration::from_millis(120)).await;
+        assert!(breaker.allow(&cfg).is_ok());
+    }
+
+    #[tokio::test]
+    async fn config_edits_are_applied_refused_or_restart_the_backend() {
+        let _globals = GLOBALS.lock().await;
+        let path = temp_path("reload.json");
+        let write = |http: serde_json::Value, process: serde_json::Value| {
+            let raw = json!({
+                "Core": { "DistDir": "dist", "Port": 3000 },
+                "Watch": { "HealthcheckEndpoint": "/healthz" },
+                "Http": http,
+                "Process": process,
+            });
+            std::fs::write(&path, raw.to_string()).unwrap();
+        };
+        let no_spawn = json!({ "NoSpawn": true });
+        write(json!({ "UpstreamRetries": 0 }), no_spawn.clone());
+        install(Config::load(&path).unwrap());
+        GO.lock().unwrap().push(external_backend(0, 1));
+        READY.store(true, Ordering::Release);
+
+        // An ordinary setting is swapped in, and the backend keeps running.
+        write(json!({ "UpstreamRetries": 2 }), no_spawn.clone());
+        let before = config();
+        assert!(reload_config(&before).await);
+        assert_eq!(config().http.upstream_retries, 2);
+        assert!(READY.load(Ordering::Acquire));
+        assert_eq!(GO.lock().unwrap().len(), 1);
+        // Whoever still holds the superseded config keeps a working copy.
+        assert_eq!(before.http.upstream_retries, 0);
+        drop(before);
+
+        // Clients built from the old settings stay, so the edit is refused.
+        write(
+            json!({ "UpstreamRetries": 2, "ConnectTimeoutMs": 5 }),
+            no_spawn.clone(),
+        );
+        let current = config();
+        assert!(!reload_config(&current).await);
+        assert!(Arc::ptr_eq(&current, &config()));
+
+        // So is a config that does not load.
+        std::fs::write(&path, "{ \"Core\": ").unwrap();
+        assert!(!reload_config(&current).await);
+        assert!(Arc::ptr_eq(&current, &config()));
+
+        // A setting only the backend reads restarts it.
+        write(
+            json!({ "UpstreamRetries": 2 }),
+            json!({ "NoSpawn": true, "MaxRestarts": 9 }),
+        );
+        RESTARTS.store(3, Ordering::Release);
+        assert!(reload_config(&current).await);
+        assert_eq!(config().process.max_restarts, 9);
+        assert!(!READY.load(Ordering::Acquire));
+        assert!(GO.lock().unwrap().is_empty());
+        assert_eq!(RESTARTS.load(Ordering::Acquire), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
 }
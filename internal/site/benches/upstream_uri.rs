@@ -0,0 +1,58 @@
+//! Building the upstream URI per request: formatting and reparsing the whole
+//! string, as the proxy used to, against assembling it from the cached
+//! backend authority and the request's own path-and-query, as
+//! `backend_uri` does now. The proxy is a binary, so the new path is
+//! mirrored here rather than imported.
+
+use criterion::{Criterion, black_box, criterion_group, criterion_main};
+use hyper::Uri;
+use hyper::http::uri::{Authority, Parts, PathAndQuery, Scheme};
+use std::sync::RwLock;
+
+const PORT: u16 = 8080;
+
+/// `backend_authority`: one authority per port, built on first use.
+fn backend_authority(port: u16) -> Authority {
+    static CACHE: RwLock<Vec<(u16, Authority)>> = RwLock::new(Vec::new());
+
+    if let Some((_, authority)) = CACHE
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(cached, _)| *cached == port)
+    {
+        return authority.clone();
+    }
+    let authority: Authority = format!("127.0.0.1:{port}").parse().unwrap();
+    CACHE.write().unwrap().push((port, authority.clone()));
+    authority
+}
+
+fn from_parts(path_and_query: PathAndQuery) -> Uri {
+    let mut parts = Parts::default();
+    parts.scheme = Some(Scheme::HTTP);
+    parts.authority = Some(backend_authority(PORT));
+    parts.path_and_query = Some(path_and_query);
+    Uri::from_parts(parts).unwrap()
+}
+
+fn format_and_parse(path_and_query: &str) -> Uri {
+    format!("http://127.0.0.1:{PORT}{path_and_query}")
+        .parse()
+        .unwrap()
+}
+
+fn upstream_uri(c: &mut Criterion) {
+    let inbound: Uri = "/api/users/42/orders?page=3&sort=desc".parse().unwrap();
+    let mut group = c.benchmark_group("upstream_uri");
+    group.bench_function("format_and_parse", |b| {
+        b.iter(|| format_and_parse(black_box(&inbound).path_and_query().unwrap().as_str()))
+    });
+    group.bench_function("from_parts", |b| {
+        b.iter(|| from_parts(black_box(&inbound).path_and_query().unwrap().clone()))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, upstream_uri);
+criterion_main!(benches);
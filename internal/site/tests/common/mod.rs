@@ -0,0 +1,188 @@
+//! Runs the proxy binary against in-process backends.
+
+#![allow(dead_code)]
+
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::body::{Bytes, Frame, Incoming};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::future::Future;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub type Body = BoxBody<Bytes, Error>;
+
+/// The proxy process, killed when dropped.
+pub struct Proxy {
+    child: Child,
+    dir: PathBuf,
+    pub port: u16,
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+impl Proxy {
+    pub fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{path}", self.port)
+    }
+
+    /// Peak resident set size so far, from `/proc/<pid>/status`.
+    pub fn peak_rss_kb(&self) -> Option<u64> {
+        let status = std::fs::read_to_string(format!("/proc/{}/status", self.child.id())).ok()?;
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmHWM:"))?
+            .trim()
+            .strip_suffix("kB")?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    /// Sends SIGTERM, as the platform does when it stops the container.
+    #[cfg(unix)]
+    pub fn terminate(&self) {
+        // SAFETY: `kill` has no memory-safety preconditions.
+        unsafe { libc::kill(self.child.id() as libc::pid_t, libc::SIGTERM) };
+    }
+}
+
+pub fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+pub fn text(body: impl Into<Bytes>) -> hyper::Response<Body> {
+    hyper::Response::new(Full::new(body.into()).map_err(Error::from).boxed())
+}
+
+/// A backend that answers `/healthz` with 200 and everything else with
+/// `handle`. Returns its port.
+pub async fn backend<F, Fut>(handle: F) -> u16
+where
+    F: Fn(hyper::Request<Incoming>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = hyper::Response<Body>> + Send + 'static,
+{
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let handle = handle.clone();
+            let service = hyper::service::service_fn(move |req: hyper::Request<Incoming>| {
+                let handle = handle.clone();
+                async move {
+                    if req.uri().path() == "/healthz" {
+                        return Ok::<_, Error>(text("ok"));
+                    }
+                    Ok(handle(req).await)
+                }
+            });
+            tokio::spawn(
+                hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service),
+            );
+        }
+    });
+    port
+}
+
+/// Starts the proxy in front of an already running backend on
+/// `backend_port` and waits for it to listen. `overrides` holds config
+/// sections whose fields replace the test defaults.
+pub async fn start_proxy(backend_port: u16, overrides: serde_json::Value) -> Proxy {
+    let mut config = serde_json::json!({
+        "Core": { "DistDir": "dist", "Port": backend_port },
+        "Watch": {
+            "HealthcheckEndpoint": "/healthz",
+            "HealthcheckTimeoutMs": 2000,
+            "ReloadConfig": false,
+        },
+        "Process": { "NoSpawn": true },
+    });
+    for (section, fields) in overrides.as_object().expect("overrides is an object") {
+        let target = config
+            .as_object_mut()
+            .unwrap()
+            .entry(section.clone())
+            .or_insert_with(|| serde_json::json!({}));
+        for (field, value) in fields.as_object().expect("sections are objects") {
+            target[field] = value.clone();
+        }
+    }
+
+    let port = free_port();
+    let dir = std::env::temp_dir().join(format!("wave-it-{}-{port}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("wave.config.json");
+    std::fs::write(&path, config.to_string()).unwrap();
+    let child = Command::new(env!("CARGO_BIN_EXE_proxy"))
+        .current_dir(&dir)
+        .env("WAVE_CONFIG_PATH", &path)
+        .env("VERCEL_DEV_PORT", port.to_string())
+        .env("RUST_LOG", "error")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let proxy = Proxy { child, dir, port };
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .is_err()
+    {
+        assert!(Instant::now() < deadline, "proxy did not start listening");
+        sleep(Duration::from_millis(20)).await;
+    }
+    proxy
+}
+
+pub fn client() -> Client<hyper_util::client::legacy::connect::HttpConnector, Body> {
+    Client::builder(TokioExecutor::new()).build_http()
+}
+
+/// Sends `req` and collects the whole response.
+pub async fn send(req: hyper::Request<Body>) -> (hyper::http::response::Parts, Bytes) {
+    let res = client().request(req).await.unwrap();
+    let (parts, body) = res.into_parts();
+    (parts, body.collect().await.unwrap().to_bytes())
+}
+
+pub fn get(url: String) -> hyper::Request<Body> {
+    hyper::Request::get(url).body(empty()).unwrap()
+}
+
+pub fn empty() -> Body {
+    http_body_util::Empty::new().map_err(Error::from).boxed()
+}
+
+pub fn full(body: impl Into<Bytes>) -> Body {
+    Full::new(body.into()).map_err(Error::from).boxed()
+}
+
+/// A chunked body sending `frames` one `gap` apart, starting after the
+/// first `gap`.
+pub fn drip(frames: Vec<Frame<Bytes>>, gap: Duration) -> Body {
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    tokio::spawn(async move {
+        for frame in frames {
+            sleep(gap).await;
+            if tx.send(Ok::<_, Error>(frame)).await.is_err() {
+                return;
+            }
+        }
+    });
+    StreamBody::new(tokio_stream::wrappers::ReceiverStream::new(rx)).boxed()
+}
@@ -0,0 +1,146 @@
+//! Bursts of concurrent requests through the proxy binary under different
+//! `Http.PoolMaxIdlePerHost` settings, and under hyper's own defaults. A
+//! benchmark more than a test, so it is ignored by default; run it with
+//!
+//! ```text
+//! cargo test --release --test pool_burst -- --ignored --nocapture
+//! ```
+//!
+//! and compare the rows it prints.
+
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::Bytes;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+mod common;
+
+use common::start_proxy;
+
+const BURSTS: usize = 20;
+const BURST_SIZE: usize = 256;
+
+/// A backend that answers everything with a short body after a millisecond,
+/// counting the connections it accepts.
+async fn backend(accepted: Arc<AtomicUsize>) -> u16 {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            accepted.fetch_add(1, Ordering::Relaxed);
+            let service = hyper::service::service_fn(|_req| async {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                Ok::<_, hyper::Error>(hyper::Response::new(Full::new(Bytes::from_static(b"ok"))))
+            });
+            tokio::spawn(
+                hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service),
+            );
+        }
+    });
+    port
+}
+
+struct Run {
+    label: &'static str,
+    burst_ms: Vec<f64>,
+    latency_ms: Vec<f64>,
+    backend_connections: usize,
+}
+
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    sorted[((sorted.len() - 1) as f64 * p).round() as usize]
+}
+
+async fn run(label: &'static str, http: serde_json::Value) -> Run {
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let proxy = start_proxy(backend(accepted.clone()).await, http).await;
+    let client = Client::builder(TokioExecutor::new())
+        .pool_max_idle_per_host(BURST_SIZE)
+        .build_http::<Empty<Bytes>>();
+    let uri: hyper::Uri = format!("http://127.0.0.1:{}/", proxy.port).parse().unwrap();
+
+    // Starts the backend connection and fills the client's own pool, so
+    // only the proxy's pool differs between runs.
+    let warm_up = (0..BURST_SIZE).map(|_| client.get(uri.clone()));
+    for res in tokio::task::JoinSet::from_iter(warm_up).join_all().await {
+        res.unwrap().into_body().collect().await.unwrap();
+    }
+    accepted.store(0, Ordering::Relaxed);
+
+    let mut burst_ms = Vec::with_capacity(BURSTS);
+    let mut latency_ms = Vec::with_capacity(BURSTS * BURST_SIZE);
+    for _ in 0..BURSTS {
+        let start = Instant::now();
+        let requests = (0..BURST_SIZE).map(|_| {
+            let (client, uri) = (client.clone(), uri.clone());
+            async move {
+                let sent = Instant::now();
+                let res = client.get(uri).await.unwrap();
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                res.into_body().collect().await.unwrap();
+                sent.elapsed().as_secs_f64() * 1000.0
+            }
+        });
+        latency_ms.extend(tokio::task::JoinSet::from_iter(requests).join_all().await);
+        burst_ms.push(start.elapsed().as_secs_f64() * 1000.0);
+        // Long enough for idle connections to matter, well under the
+        // default `PoolIdleTimeoutMs`.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    burst_ms.sort_by(f64::total_cmp);
+    latency_ms.sort_by(f64::total_cmp);
+    Run {
+        label,
+        burst_ms,
+        latency_ms,
+        backend_connections: accepted.load(Ordering::Relaxed),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "benchmark; run with --ignored --nocapture"]
+async fn pool_burst() {
+    let runs = [
+        // What the client was built with before the pool was configurable:
+        // hyper's defaults, with no connect timeout and Nagle left on.
+        run(
+            "baseline (hyper defaults)",
+            serde_json::json!({ "Http": {
+                "PoolMaxIdlePerHost": usize::MAX,
+                "PoolIdleTimeoutMs": 90_000,
+                "ConnectTimeoutMs": 0,
+                "TcpNodelay": false,
+            } }),
+        )
+        .await,
+        run(
+            "no pooling (PoolMaxIdlePerHost 0)",
+            serde_json::json!({ "Http": { "PoolMaxIdlePerHost": 0 } }),
+        )
+        .await,
+        run(
+            "PoolMaxIdlePerHost 8",
+            serde_json::json!({ "Http": { "PoolMaxIdlePerHost": 8 } }),
+        )
+        .await,
+        run("defaults (PoolMaxIdlePerHost 64)", serde_json::json!({})).await,
+    ];
+    println!(
+        "{BURSTS} bursts of {BURST_SIZE} concurrent GETs\n{:<36} {:>10} {:>10} {:>10} {:>14}",
+        "", "burst p50", "req p50", "req p99", "backend conns"
+    );
+    for run in &runs {
+        println!(
+            "{:<36} {:>8.1}ms {:>8.2}ms {:>8.2}ms {:>14}",
+            run.label,
+            percentile(&run.burst_ms, 0.5),
+            percentile(&run.latency_ms, 0.5),
+            percentile(&run.latency_ms, 0.99),
+            run.backend_connections,
+        );
+    }
+}
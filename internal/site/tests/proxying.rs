@@ -0,0 +1,765 @@
+//! Requests through the proxy binary from unusual clients to misbehaving or
+//! unusual backends.
+
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Bytes, Frame};
+use serde_json::json;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::time::sleep;
+use tokio_stream::wrappers::ReceiverStream;
+
+mod common;
+
+use common::{backend, drip, get, send, start_proxy, text};
+
+#[tokio::test]
+async fn a_stalled_backend_times_out_with_504() {
+    let port = backend(|req| async move {
+        if req.uri().path() == "/slow" {
+            sleep(Duration::from_secs(2)).await;
+            return text("late");
+        }
+        // Answers at once, then streams for longer than the timeout.
+        let frames = ["a", "b", "c"].map(|s| Frame::data(s.into())).into();
+        hyper::Response::new(drip(frames, Duration::from_millis(200)))
+    })
+    .await;
+    let proxy = start_proxy(port, json!({ "Http": { "UpstreamRequestTimeoutMs": 300 } })).await;
+
+    let start = Instant::now();
+    let (res, body) = send(get(proxy.url("/slow"))).await;
+    assert_eq!(res.status, 504);
+    assert!(
+        start.elapsed() < Duration::from_secs(1),
+        "{:?}",
+        start.elapsed()
+    );
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"]["code"], "timeout");
+    assert_eq!(
+        body["error"]["message"],
+        "backend did not respond within 300ms"
+    );
+
+    // Only the time to the first byte counts.
+    let (res, body) = send(get(proxy.url("/stream"))).await;
+    assert_eq!(res.status, 200);
+    assert_eq!(body, "abc");
+}
+
+#[tokio::test]
+async fn headers_named_by_connection_stay_on_their_hop() {
+    let port = backend(|req| async move {
+        let mut names: Vec<_> = req
+            .headers()
+            .keys()
+            .map(|k| k.as_str().to_owned())
+            .collect();
+        names.sort();
+        let mut res = text(names.join(","));
+        let headers = res.headers_mut();
+        headers.insert("connection", "X-Resp".parse().unwrap());
+        headers.insert("x-resp", "1".parse().unwrap());
+        headers.insert("x-keep", "2".parse().unwrap());
+        res
+    })
+    .await;
+    let proxy = start_proxy(port, json!({ "Http": { "ForwardedHeaders": false } })).await;
+
+    let req = hyper::Request::get(proxy.url("/"))
+        .header("connection", "Upgrade, Foo")
+        .header("foo", "1")
+        .header("bar", "2")
+        .body(common::empty())
+        .unwrap();
+    let (res, body) = send(req).await;
+    assert_eq!(res.status, 200);
+    assert!(
+        !body.split(|&b| b == b',').any(|name| name == b"foo"),
+        "{body:?}"
+    );
+    assert!(
+        body.split(|&b| b == b',').any(|name| name == b"bar"),
+        "{body:?}"
+    );
+    assert!(!res.headers.contains_key("x-resp"));
+    assert_eq!(res.headers["x-keep"], "2");
+}
+
+/// Sends `head` over a fresh connection and returns everything the proxy
+/// writes back before closing it.
+async fn exchange(proxy: &common::Proxy, head: &str) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", proxy.port))
+        .await
+        .unwrap();
+    stream.write_all(head.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+#[tokio::test]
+async fn unusual_request_targets_get_400_or_a_path() {
+    let port = backend(|req| async move { text(format!("{} {}", req.method(), req.uri())) }).await;
+    let proxy = start_proxy(port, json!({})).await;
+
+    for head in [
+        "OPTIONS * HTTP/1.1\r\nhost: x\r\nconnection: close\r\n\r\n",
+        "CONNECT example.com:443 HTTP/1.1\r\nhost: example.com:443\r\nconnection: close\r\n\r\n",
+    ] {
+        let response = exchange(&proxy, head).await;
+        assert!(
+            response.starts_with("HTTP/1.1 400 "),
+            "{head:?}: {response}"
+        );
+        assert!(response.contains(r#""code":"bad_request""#), "{response}");
+    }
+    // An absolute-form target is forwarded by its path and query.
+    let response = exchange(
+        &proxy,
+        "GET http://elsewhere/a%2Fb?q=%00 HTTP/1.1\r\nhost: elsewhere\r\nconnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200 "), "{response}");
+    assert!(response.ends_with("GET /a%2Fb?q=%00"), "{response}");
+
+    // The proxy is still serving.
+    let (res, _) = send(get(proxy.url("/"))).await;
+    assert_eq!(res.status, 200);
+}
+
+#[tokio::test]
+async fn event_streams_flush_each_event_and_outlive_the_timeout() {
+    let port = backend(|_req| async move {
+        // Headers wait for the first event, well past the timeout.
+        sleep(Duration::from_millis(800)).await;
+        let events = (0..4).map(|i| Frame::data(format!("data: {i}\n\n").into()));
+        let mut res = hyper::Response::new(drip(events.collect(), Duration::from_millis(500)));
+        res.headers_mut()
+            .insert("content-type", "text/event-stream".parse().unwrap());
+        res
+    })
+    .await;
+    let proxy = start_proxy(
+        port,
+        json!({ "Http": { "UpstreamRequestTimeoutMs": 300, "Compression": true } }),
+    )
+    .await;
+
+    let req = hyper::Request::get(proxy.url("/events"))
+        .header("accept", "text/event-stream")
+        .header("accept-encoding", "gzip, br")
+        .body(common::empty())
+        .unwrap();
+    let start = Instant::now();
+    let res = common::client().request(req).await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert!(!res.headers().contains_key("content-encoding"));
+    let mut body = res.into_body();
+    let mut arrivals = Vec::new();
+    while let Some(frame) = body.frame().await {
+        if let Ok(data) = frame.unwrap().into_data() {
+            arrivals.push((data, start.elapsed()));
+        }
+    }
+    let events: Vec<_> = arrivals.iter().map(|(data, _)| data.clone()).collect();
+    assert_eq!(
+        events,
+        ["data: 0\n\n", "data: 1\n\n", "data: 2\n\n", "data: 3\n\n"]
+    );
+    // Each event is passed on as it comes rather than held for the end.
+    for pair in arrivals.windows(2) {
+        let gap = pair[1].1 - pair[0].1;
+        assert!(gap > Duration::from_millis(300), "{arrivals:?}");
+    }
+}
+
+#[tokio::test]
+async fn a_client_leaving_mid_response_cancels_the_backend_stream() {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let flag = cancelled.clone();
+    let port = backend(move |req| {
+        let flag = flag.clone();
+        async move {
+            if req.uri().path() != "/stream" {
+                return text("ok");
+            }
+            let (tx, rx) = tokio::sync::mpsc::channel(1);
+            tokio::spawn(async move {
+                loop {
+                    let frame = Frame::data(Bytes::from_static(b"tick\n"));
+                    if tx.send(Ok::<_, common::Error>(frame)).await.is_err() {
+                        flag.store(true, Ordering::Relaxed);
+                        return;
+                    }
+                    sleep(Duration::from_millis(20)).await;
+                }
+            });
+            hyper::Response::new(StreamBody::new(ReceiverStream::new(rx)).boxed())
+        }
+    })
+    .await;
+    let proxy = start_proxy(port, json!({})).await;
+
+    let res = common::client()
+        .request(get(proxy.url("/stream")))
+        .await
+        .unwrap();
+    let mut body = res.into_body();
+    body.frame().await.unwrap().unwrap();
+    drop(body);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !cancelled.load(Ordering::Relaxed) {
+        assert!(Instant::now() < deadline, "the backend kept streaming");
+        sleep(Duration::from_millis(20)).await;
+    }
+    // The backend is not blamed for it.
+    let (res, body) = send(get(proxy.url("/"))).await;
+    assert_eq!(res.status, 200);
+    assert_eq!(body, "ok");
+}
+
+#[tokio::test]
+async fn the_upstream_host_follows_its_mode() {
+    let port =
+        backend(|req| async move { text(req.headers()["host"].to_str().unwrap().to_owned()) })
+            .await;
+    let host_for = |mode: &str| {
+        let mode = mode.to_owned();
+        async move {
+            let proxy = start_proxy(port, json!({ "Http": { "UpstreamHost": mode } })).await;
+            let req = hyper::Request::get(proxy.url("/"))
+                .header("host", "shop.example.com")
+                .body(common::empty())
+                .unwrap();
+            let (res, body) = send(req).await;
+            assert_eq!(res.status, 200);
+            String::from_utf8(body.to_vec()).unwrap()
+        }
+    };
+    assert_eq!(host_for("rewrite").await, format!("127.0.0.1:{port}"));
+    assert_eq!(host_for("preserve").await, "shop.example.com");
+    assert_eq!(host_for("api.internal:9000").await, "api.internal:9000");
+}
+
+#[tokio::test]
+async fn backend_trailers_reach_the_client() {
+    let port = backend(|_req| async move {
+        let mut trailers = hyper::HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        trailers.insert("grpc-message", "ok".parse().unwrap());
+        let frames = vec![
+            Frame::data(Bytes::from_static(b"a")),
+            Frame::data(Bytes::from_static(b"b")),
+            Frame::trailers(trailers),
+        ];
+        let mut res = hyper::Response::new(drip(frames, Duration::from_millis(10)));
+        res.headers_mut()
+            .insert("trailer", "grpc-status, grpc-message".parse().unwrap());
+        res
+    })
+    .await;
+    let proxy = start_proxy(port, json!({})).await;
+
+    let req = hyper::Request::get(proxy.url("/"))
+        .header("te", "trailers")
+        .body(common::empty())
+        .unwrap();
+    let res = common::client().request(req).await.unwrap();
+    assert_eq!(res.status(), 200);
+    let collected = res.into_body().collect().await.unwrap();
+    let trailers = collected
+        .trailers()
+        .cloned()
+        .expect("trailers were dropped");
+    assert_eq!(collected.to_bytes(), "ab");
+    assert_eq!(trailers["grpc-status"], "0");
+    assert_eq!(trailers["grpc-message"], "ok");
+}
+
+fn preflight(url: String, origin: &str, method: &str) -> hyper::Request<common::Body> {
+    hyper::Request::options(url)
+        .header("origin", origin)
+        .header("access-control-request-method", method)
+        .header("access-control-request-headers", "Content-Type")
+        .body(common::empty())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn cors_preflights_are_answered_and_responses_decorated() {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counter = hits.clone();
+    let port = backend(move |req| {
+        counter.fetch_add(1, Ordering::Relaxed);
+        async move { text(req.method().to_string()) }
+    })
+    .await;
+    let proxy = start_proxy(
+        port,
+        json!({
+            "Cors": {
+                "AllowedOrigins": ["https://app.example"],
+                "AllowedMethods": ["GET", "POST"],
+                "AllowedHeaders": ["content-type"],
+                "ExposedHeaders": ["x-total"],
+                "AllowCredentials": true,
+                "MaxAgeSecs": 600,
+            },
+        }),
+    )
+    .await;
+
+    let (res, body) = send(preflight(proxy.url("/api"), "https://app.example", "POST")).await;
+    assert_eq!(res.status, 204);
+    assert!(body.is_empty());
+    let h = &res.headers;
+    assert_eq!(h["access-control-allow-origin"], "https://app.example");
+    assert_eq!(h["access-control-allow-credentials"], "true");
+    assert_eq!(h["access-control-allow-methods"], "GET, POST");
+    assert_eq!(h["access-control-allow-headers"], "Content-Type");
+    assert_eq!(h["access-control-max-age"], "600");
+    assert_eq!(h["vary"], "origin");
+
+    // Refusals are a bare 204 too; the browser reads the missing headers.
+    for (origin, method) in [
+        ("https://evil.example", "POST"),
+        ("https://app.example", "DELETE"),
+    ] {
+        let (res, _) = send(preflight(proxy.url("/api"), origin, method)).await;
+        assert_eq!(res.status, 204);
+        assert!(!res.headers.contains_key("access-control-allow-origin"));
+    }
+    assert_eq!(hits.load(Ordering::Relaxed), 0);
+
+    let req = hyper::Request::get(proxy.url("/api"))
+        .header("origin", "https://app.example")
+        .body(common::empty())
+        .unwrap();
+    let (res, body) = send(req).await;
+    assert_eq!(body, "GET");
+    assert_eq!(
+        res.headers["access-control-allow-origin"],
+        "https://app.example"
+    );
+    assert_eq!(res.headers["access-control-allow-credentials"], "true");
+    assert_eq!(res.headers["access-control-expose-headers"], "x-total");
+    let req = hyper::Request::get(proxy.url("/api"))
+        .header("origin", "https://evil.example")
+        .body(common::empty())
+        .unwrap();
+    let (res, _) = send(req).await;
+    assert!(!res.headers.contains_key("access-control-allow-origin"));
+    assert_eq!(hits.load(Ordering::Relaxed), 2);
+}
+
+#[tokio::test]
+async fn without_cors_preflights_reach_the_backend() {
+    let port = backend(|req| async move { text(req.method().to_string()) }).await;
+    let proxy = start_proxy(port, json!({})).await;
+    let (res, body) = send(preflight(proxy.url("/api"), "https://app.example", "POST")).await;
+    assert_eq!(res.status, 200);
+    assert_eq!(body, "OPTIONS");
+    assert!(!res.headers.contains_key("access-control-allow-origin"));
+}
+
+#[tokio::test]
+async fn errors_are_json_unless_plain_text_is_preferred() {
+    let proxy = start_proxy(
+        common::free_port(),
+        json!({ "Watch": { "HealthcheckTimeoutMs": 300, "HealthcheckPollMs": 50 } }),
+    )
+    .await;
+
+    let (res, body) = send(get(proxy.url("/"))).await;
+    assert_eq!(res.status, 503);
+    assert_eq!(res.headers["content-type"], "application/json");
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"]["code"], "starting");
+    assert_eq!(body["error"]["message"], "health check timed out");
+
+    let req = hyper::Request::get(proxy.url("/"))
+        .header("accept", "text/plain, application/json;q=0.5")
+        .body(common::empty())
+        .unwrap();
+    let (res, body) = send(req).await;
+    assert_eq!(res.status, 503);
+    assert_eq!(res.headers["content-type"], "text/plain; charset=utf-8");
+    assert_eq!(body, "starting: health check timed out\n");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn responses_finished_while_draining_close_the_connection() {
+    let port = backend(|req| async move {
+        if req.uri().path() == "/slow" {
+            sleep(Duration::from_millis(500)).await;
+        }
+        text("ok")
+    })
+    .await;
+    for close_on_drain in [true, false] {
+        let proxy = start_proxy(port, json!({ "Http": { "CloseOnDrain": close_on_drain } })).await;
+
+        let (res, _) = send(get(proxy.url("/"))).await;
+        assert_eq!(res.status, 200);
+        assert!(!res.headers.contains_key("connection"));
+
+        let slow = tokio::spawn(send(get(proxy.url("/slow"))));
+        sleep(Duration::from_millis(200)).await;
+        proxy.terminate();
+        let (res, body) = slow.await.unwrap();
+        assert_eq!(res.status, 200);
+        assert_eq!(body, "ok");
+        assert_eq!(
+            res.headers.get("connection").is_some_and(|v| v == "close"),
+            close_on_drain
+        );
+    }
+}
+
+async fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    async_compression::tokio::bufread::GzipEncoder::new(data)
+        .read_to_end(&mut out)
+        .await
+        .unwrap();
+    out
+}
+
+async fn gunzip(data: &[u8]) -> String {
+    let mut out = String::new();
+    async_compression::tokio::bufread::GzipDecoder::new(data)
+        .read_to_string(&mut out)
+        .await
+        .unwrap();
+    out
+}
+
+#[tokio::test]
+async fn gzipped_html_is_rewritten_and_compressed_again() {
+    let port = backend(|req| async move {
+        let html = format!(
+            r#"<a href="http://{}/next">next</a>"#,
+            req.headers()["x-backend"].to_str().unwrap()
+        );
+        let mut res = text(gzip(html.as_bytes()).await);
+        let headers = res.headers_mut();
+        headers.insert("content-encoding", "gzip".parse().unwrap());
+        let content_type = req.headers()["x-type"].clone();
+        headers.insert("content-type", content_type);
+        let location = format!(
+            "http://{}/next",
+            req.headers()["x-backend"].to_str().unwrap()
+        );
+        headers.insert("location", location.parse().unwrap());
+        res
+    })
+    .await;
+    let backend = format!("127.0.0.1:{port}");
+    let request = |proxy: &common::Proxy, content_type: &str| {
+        hyper::Request::get(proxy.url("/"))
+            .header("host", "example.com")
+            .header("accept-encoding", "gzip")
+            .header("x-backend", &backend)
+            .header("x-type", content_type)
+            .body(common::empty())
+            .unwrap()
+    };
+    let rewrites =
+        json!([{ "Match": r"http://127\.0\.0\.1:\d+", "Replace": "https://example.com" }]);
+
+    let proxy = start_proxy(port, json!({ "Http": { "BodyRewrites": rewrites } })).await;
+    let (res, body) = send(request(&proxy, "text/html; charset=utf-8")).await;
+    assert_eq!(res.status, 200);
+    assert_eq!(res.headers["location"], "http://example.com/next");
+    assert_eq!(res.headers["content-encoding"], "gzip");
+    assert_eq!(
+        gunzip(&body).await,
+        r#"<a href="https://example.com/next">next</a>"#
+    );
+    // Content types no rule names pass through still compressed, untouched.
+    let (res, body) = send(request(&proxy, "application/json")).await;
+    assert_eq!(res.headers["content-encoding"], "gzip");
+    assert_eq!(
+        gunzip(&body).await,
+        format!(r#"<a href="http://{backend}/next">next</a>"#)
+    );
+
+    let proxy = start_proxy(
+        port,
+        json!({ "Http": { "BodyRewrites": rewrites, "BodyRewriteRecompress": false } }),
+    )
+    .await;
+    let (res, body) = send(request(&proxy, "text/html")).await;
+    assert!(!res.headers.contains_key("content-encoding"));
+    assert_eq!(body, r#"<a href="https://example.com/next">next</a>"#);
+}
+
+#[tokio::test]
+async fn oversized_request_headers_get_431() {
+    let port = backend(|_req| async move { text("ok") }).await;
+    let proxy = start_proxy(
+        port,
+        json!({ "Http": { "MaxRequestHeaders": 8, "MaxRequestHeaderBytes": 512 } }),
+    )
+    .await;
+
+    let mut many = hyper::Request::get(proxy.url("/"));
+    for i in 0..10 {
+        many = many.header(format!("x-{i}"), "1");
+    }
+    let large = hyper::Request::get(proxy.url("/")).header("cookie", "x".repeat(600));
+    for (req, message) in [
+        (many, "more than 8 request headers"),
+        (large, "request headers exceed 512 bytes"),
+    ] {
+        let (res, body) = send(req.body(common::empty()).unwrap()).await;
+        assert_eq!(res.status, 431);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], "headers_too_large");
+        assert_eq!(body["error"]["message"], message);
+    }
+    let (res, _) = send(get(proxy.url("/"))).await;
+    assert_eq!(res.status, 200);
+}
+
+#[tokio::test]
+async fn disallowed_methods_get_405_with_allow() {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counter = hits.clone();
+    let port = backend(move |req| {
+        counter.fetch_add(1, Ordering::Relaxed);
+        async move { text(req.method().to_string()) }
+    })
+    .await;
+    let proxy = start_proxy(
+        port,
+        json!({
+            "Http": {
+                "AllowedMethods": ["GET", "post"],
+                "Routes": [
+                    { "Prefix": "/reports", "AllowedMethods": ["GET"] },
+                    { "Prefix": "/open", "AllowedMethods": [] },
+                ],
+            },
+        }),
+    )
+    .await;
+    let request = |method: &str, path: &str| {
+        hyper::Request::builder()
+            .method(method)
+            .uri(proxy.url(path))
+            .body(common::empty())
+            .unwrap()
+    };
+
+    for (method, path, allow) in [
+        ("DELETE", "/items/1", "GET, POST, HEAD"),
+        ("POST", "/reports/1", "GET, HEAD"),
+    ] {
+        let (res, body) = send(request(method, path)).await;
+        assert_eq!(res.status, 405, "{method} {path}");
+        assert_eq!(res.headers["allow"], allow);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], "method_not_allowed");
+    }
+    assert_eq!(hits.load(Ordering::Relaxed), 0);
+
+    for (method, path) in [
+        ("POST", "/items"),
+        ("HEAD", "/reports/1"),
+        ("DELETE", "/open/1"),
+    ] {
+        let (res, _) = send(request(method, path)).await;
+        assert_eq!(res.status, 200, "{method} {path}");
+    }
+    assert_eq!(hits.load(Ordering::Relaxed), 3);
+}
+
+#[tokio::test]
+async fn the_inbound_http_version_is_forwarded() {
+    let port = backend(|req| async move {
+        let version = req.headers().get("x-forwarded-proto-version").cloned();
+        text(
+            version
+                .map(|v| v.to_str().unwrap().to_owned())
+                .unwrap_or_default(),
+        )
+    })
+    .await;
+    let proxy = start_proxy(port, json!({ "Http": { "ForwardedProtoVersion": true } })).await;
+
+    let (_, body) = send(get(proxy.url("/"))).await;
+    assert_eq!(body, "HTTP/1.1");
+}
+
+#[tokio::test]
+async fn stripped_query_params_never_reach_the_backend() {
+    let port = backend(|req| async move { text(req.uri().to_string()) }).await;
+    let proxy = start_proxy(
+        port,
+        json!({ "Http": { "StripQueryParams": ["utm_*", "fbclid"] } }),
+    )
+    .await;
+    for (sent, seen) in [
+        (
+            "/search?q=a%26b&utm_source=x&page=2",
+            "/search?q=a%26b&page=2",
+        ),
+        ("/p?utm_source=x&fbclid=y", "/p"),
+        ("/plain", "/plain"),
+    ] {
+        let (_, body) = send(get(proxy.url(sent))).await;
+        assert_eq!(body, seen);
+    }
+}
+
+#[tokio::test]
+async fn responses_past_max_response_bytes_are_refused_or_cut_off() {
+    let port = backend(|req| async move {
+        match req.uri().path() {
+            "/fixed" => text(vec![b'x'; 4096]),
+            "/small" => text(vec![b'x'; 1024]),
+            _ => {
+                let frames = (0..8).map(|_| Frame::data(Bytes::from(vec![b'x'; 512])));
+                hyper::Response::new(drip(frames.collect(), Duration::from_millis(10)))
+            }
+        }
+    })
+    .await;
+    let proxy = start_proxy(port, json!({ "Http": { "MaxResponseBytes": 1024 } })).await;
+
+    let (res, body) = send(get(proxy.url("/fixed"))).await;
+    assert_eq!(res.status, 502);
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        body["error"]["message"],
+        "backend response of 4096 bytes exceeds the 1024 byte limit"
+    );
+    let (res, body) = send(get(proxy.url("/small"))).await;
+    assert_eq!(res.status, 200);
+    assert_eq!(body.len(), 1024);
+
+    // Once the headers are out, the body is cut off short of the limit.
+    let res = common::client()
+        .request(get(proxy.url("/stream")))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    let mut body = res.into_body();
+    let mut received = 0;
+    let ended = loop {
+        match body.frame().await {
+            Some(Ok(frame)) => received += frame.data_ref().map_or(0, |data| data.len()),
+            Some(Err(e)) => break Err(e),
+            None => break Ok(()),
+        }
+    };
+    assert!(
+        ended.is_err(),
+        "the body ended cleanly after {received} bytes"
+    );
+    assert!(received <= 1024, "{received}");
+
+    let (res, _) = send(get(proxy.url("/small"))).await;
+    assert_eq!(res.status, 200);
+}
+
+#[tokio::test]
+async fn cached_responses_are_kept_per_host_and_rewritten_target() {
+    let port = backend(|req| async move {
+        let mut res = text(format!(
+            "{} {}",
+            req.headers()["host"].to_str().unwrap(),
+            req.uri()
+        ));
+        res.headers_mut()
+            .insert("cache-control", "max-age=60".parse().unwrap());
+        res
+    })
+    .await;
+    let proxy = start_proxy(
+        port,
+        json!({
+            "Cache": {},
+            "Http": { "UpstreamHost": "preserve", "StripQueryParams": ["utm_*"] },
+        }),
+    )
+    .await;
+    let fetch = |host: &'static str, path: &'static str| {
+        let req = hyper::Request::get(proxy.url(path))
+            .header("host", host)
+            .body(common::empty())
+            .unwrap();
+        async move {
+            let (res, body) = send(req).await;
+            assert_eq!(res.status, 200);
+            (
+                String::from_utf8(body.to_vec()).unwrap(),
+                res.headers.contains_key("age"),
+            )
+        }
+    };
+
+    assert_eq!(
+        fetch("a.example", "/p").await,
+        ("a.example /p".into(), false)
+    );
+    assert_eq!(
+        fetch("a.example", "/p").await,
+        ("a.example /p".into(), true)
+    );
+    // Another host on the same backend is its own resource.
+    assert_eq!(
+        fetch("B.example", "/p").await,
+        ("B.example /p".into(), false)
+    );
+    assert_eq!(
+        fetch("b.example", "/p").await,
+        ("B.example /p".into(), true)
+    );
+    // Parameters the backend never sees do not split the entry.
+    assert_eq!(
+        fetch("a.example", "/p?utm_source=x").await,
+        ("a.example /p".into(), true)
+    );
+}
+
+#[tokio::test]
+async fn a_post_makes_the_cached_resource_stale() {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counter = hits.clone();
+    let port = backend(move |_| {
+        let n = counter.fetch_add(1, Ordering::SeqCst);
+        async move {
+            let mut res = text(n.to_string());
+            res.headers_mut()
+                .insert("cache-control", "max-age=60".parse().unwrap());
+            res
+        }
+    })
+    .await;
+    let proxy = start_proxy(port, json!({ "Cache": {} })).await;
+    let body = |method: &str, path: &str| {
+        let req = hyper::Request::builder()
+            .method(method)
+            .uri(proxy.url(path))
+            .body(common::empty())
+            .unwrap();
+        async move { send(req).await.1 }
+    };
+
+    assert_eq!(body("GET", "/items?page=2").await, "0");
+    assert_eq!(body("GET", "/items?page=2").await, "0");
+    assert_eq!(body("GET", "/other").await, "1");
+    assert_eq!(body("POST", "/items").await, "2");
+    assert_eq!(body("GET", "/items?page=2").await, "3");
+    assert_eq!(body("GET", "/other").await, "1");
+    assert_eq!(hits.load(Ordering::SeqCst), 4);
+}
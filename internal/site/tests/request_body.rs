@@ -0,0 +1,78 @@
+//! Request bodies: buffered up to `Http.MaxBodyBytes` while retries are on,
+//! streamed without a cap once they are off.
+
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Bytes, Frame};
+use serde_json::json;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+mod common;
+
+use common::{Body, Error, backend, full, send, start_proxy, text};
+
+/// A backend replying with the length of the body it read; `hits` counts
+/// the requests that reached it.
+async fn length_echo(hits: Arc<AtomicUsize>) -> u16 {
+    backend(move |req| {
+        let hits = hits.clone();
+        async move {
+            hits.fetch_add(1, Ordering::Relaxed);
+            let body = req.into_body().collect().await.unwrap().to_bytes();
+            text(body.len().to_string())
+        }
+    })
+    .await
+}
+
+fn post(url: String, body: Body) -> hyper::Request<Body> {
+    hyper::Request::post(url).body(body).unwrap()
+}
+
+/// A body without a `Content-Length`, sent chunked.
+fn chunked(len: usize) -> Body {
+    let frames = (0..len / 1024).map(|_| Ok::<_, Error>(Frame::data(Bytes::from(vec![0; 1024]))));
+    StreamBody::new(tokio_stream::iter(frames)).boxed()
+}
+
+#[tokio::test]
+async fn buffered_bodies_over_the_cap_get_413() {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let proxy = start_proxy(
+        length_echo(hits.clone()).await,
+        json!({ "Http": { "MaxBodyBytes": 4096 } }),
+    )
+    .await;
+
+    let (res, body) = send(post(proxy.url("/upload"), full(vec![0; 4096]))).await;
+    assert_eq!(res.status, 200);
+    assert_eq!(body, "4096");
+
+    for body in [full(vec![0; 4097]), chunked(8192)] {
+        let (res, body) = send(post(proxy.url("/upload"), body)).await;
+        assert_eq!(res.status, 413);
+        assert_eq!(res.headers["content-type"], "application/json");
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], "payload_too_large");
+        assert_eq!(body["error"]["message"], "request body exceeds 4096 bytes");
+    }
+    assert_eq!(hits.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn streamed_bodies_are_not_capped() {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let proxy = start_proxy(
+        length_echo(hits.clone()).await,
+        json!({ "Http": { "MaxBodyBytes": 4096, "UpstreamRetries": 0 } }),
+    )
+    .await;
+
+    let len = 8 * 1024 * 1024;
+    for body in [full(vec![0; len]), chunked(len)] {
+        let (res, body) = send(post(proxy.url("/upload"), body)).await;
+        assert_eq!(res.status, 200);
+        assert_eq!(body, len.to_string());
+    }
+    assert_eq!(hits.load(Ordering::Relaxed), 2);
+}
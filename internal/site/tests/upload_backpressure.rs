@@ -0,0 +1,116 @@
+//! Uploads a large body through the proxy binary to a backend that reads it
+//! slowly, checking that the proxy only pulls from the client as fast as the
+//! backend drains it instead of buffering the upload.
+
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::body::{Bytes, Frame};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+mod common;
+
+use common::start_proxy;
+
+const UPLOAD_BYTES: u64 = 256 * 1024 * 1024;
+const CHUNK_BYTES: usize = 64 * 1024;
+/// How long the backend reads slowly before draining the rest at full speed.
+const SLOW_PHASE: Duration = Duration::from_secs(2);
+/// Bytes sent but not yet read by the backend, allowing for the kernel
+/// socket buffers on both loopback hops plus a few frames in the proxy.
+const MAX_IN_FLIGHT: u64 = 48 * 1024 * 1024;
+/// The upload is over five times this, so buffering it would show.
+const MAX_PROXY_RSS_KB: u64 = 48 * 1024;
+
+/// A backend that answers `/healthz` and, for anything else, reads the body
+/// one frame at a time, sleeping between frames for `SLOW_PHASE`. Replies
+/// with the number of bytes it read; `consumed` tracks it as it goes.
+async fn slow_backend(consumed: Arc<AtomicU64>) -> u16 {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let consumed = consumed.clone();
+            let service =
+                hyper::service::service_fn(move |req: hyper::Request<hyper::body::Incoming>| {
+                    let consumed = consumed.clone();
+                    async move {
+                        if req.uri().path() == "/healthz" {
+                            return Ok::<_, hyper::Error>(hyper::Response::new(Full::new(
+                                Bytes::from_static(b"ok"),
+                            )));
+                        }
+                        let slow_until = Instant::now() + SLOW_PHASE;
+                        let mut body = req.into_body();
+                        while let Some(frame) = body.frame().await {
+                            if let Ok(data) = frame?.into_data() {
+                                consumed.fetch_add(data.len() as u64, Ordering::Relaxed);
+                            }
+                            if Instant::now() < slow_until {
+                                sleep(Duration::from_millis(5)).await;
+                            }
+                        }
+                        let read = consumed.load(Ordering::Relaxed).to_string();
+                        Ok(hyper::Response::new(Full::new(Bytes::from(read))))
+                    }
+                });
+            tokio::spawn(
+                hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service),
+            );
+        }
+    });
+    port
+}
+
+#[tokio::test]
+async fn slow_backend_holds_back_a_large_upload() {
+    let consumed = Arc::new(AtomicU64::new(0));
+    let backend_port = slow_backend(consumed.clone()).await;
+    // Retries would buffer the body to replay it.
+    let proxy = start_proxy(
+        backend_port,
+        serde_json::json!({ "Http": { "UpstreamRetries": 0 } }),
+    )
+    .await;
+
+    let produced = Arc::new(AtomicU64::new(0));
+    let counter = produced.clone();
+    let chunk = Bytes::from(vec![b'x'; CHUNK_BYTES]);
+    let chunks = tokio_stream::iter((0..UPLOAD_BYTES / CHUNK_BYTES as u64).map(move |_| {
+        counter.fetch_add(CHUNK_BYTES as u64, Ordering::Relaxed);
+        Ok::<_, std::convert::Infallible>(Frame::data(chunk.clone()))
+    }));
+    let req = hyper::Request::post(format!("http://127.0.0.1:{}/upload", proxy.port))
+        .header("content-length", UPLOAD_BYTES)
+        .body(StreamBody::new(chunks))
+        .unwrap();
+    let client = Client::builder(TokioExecutor::new()).build_http();
+    let upload = tokio::spawn(async move {
+        let res = client.request(req).await.unwrap();
+        let status = res.status();
+        (status, res.into_body().collect().await.unwrap().to_bytes())
+    });
+
+    let mut max_in_flight = 0;
+    while !upload.is_finished() {
+        let in_flight = produced
+            .load(Ordering::Relaxed)
+            .saturating_sub(consumed.load(Ordering::Relaxed));
+        max_in_flight = max_in_flight.max(in_flight);
+        sleep(Duration::from_millis(10)).await;
+    }
+    let (status, body) = upload.await.unwrap();
+    assert_eq!(status, hyper::StatusCode::OK);
+    assert_eq!(body, UPLOAD_BYTES.to_string());
+    assert!(
+        max_in_flight < MAX_IN_FLIGHT,
+        "{max_in_flight} bytes were in flight at once"
+    );
+    if let Some(rss) = proxy.peak_rss_kb() {
+        assert!(rss < MAX_PROXY_RSS_KB, "proxy peaked at {rss} kB");
+    }
+}